@@ -3,30 +3,42 @@
 #include <poll.h>
 #include <stdlib.h>
 #include <string.h>
+#include <sys/io.h>
 #include <sys/mount.h>
 #include <unistd.h>
 
+// The VMM exits with (status << 1) | 1 when started with --debug-exit 0xf4
+#define DEBUG_EXIT_PORT 0xf4
+
+static int report(int status) {
+  if (ioperm(DEBUG_EXIT_PORT, 1, 1) == 0) {
+    outb(status, DEBUG_EXIT_PORT);
+  }
+
+  return status;
+}
+
 int main(void) {
   char msg[4096] = "Hello from userspace!";
   size_t idx = 0;
 
   if (mount("dev", "/dev", "devtmpfs", 0, NULL) == -1) {
-    return EXIT_FAILURE;
+    return report(EXIT_FAILURE);
   }
 
   int kmsg = open("/dev/kmsg", O_WRONLY | O_APPEND);
   if (kmsg == -1) {
-    return EXIT_FAILURE;
+    return report(EXIT_FAILURE);
   }
 
   DIR *dir = opendir("/dev");
   if (!dir) {
-    return EXIT_FAILURE;
+    return report(EXIT_FAILURE);
   }
 
   // Write the original message once before overwriting it
   if (write(kmsg, msg, strlen(msg)) == -1) {
-    return EXIT_FAILURE;
+    return report(EXIT_FAILURE);
   }
 
   for (struct dirent *dp = NULL; (dp = readdir(dir)) != NULL;) {
@@ -40,9 +52,11 @@ int main(void) {
   msg[idx++] = '\0';
 
   if (write(kmsg, msg, idx) == -1) {
-    return EXIT_FAILURE;
+    return report(EXIT_FAILURE);
   }
 
   closedir(dir);
   close(kmsg);
+
+  return report(EXIT_SUCCESS);
 }
\ No newline at end of file
@@ -0,0 +1,444 @@
+//! ACPI tables describing a hardware-reduced platform, ref:
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html
+
+use crate::{
+    aml,
+    constants::TIMER_GSI,
+    devices::{acpi_pm, i8042},
+    memory::{GuestMemory, MemoryError},
+    mptable,
+    util::{as_bytes, checksum, ByteValued},
+};
+use std::{fmt, mem};
+
+/// The RSDP is placed where a BIOS would put it, at the start of the BIOS
+/// area, with the rest of the tables following it
+pub const RSDP_ADDR: u64 = 0xe0000;
+
+/// The tables have to stay below 1MiB, where the kernel is loaded
+const ACPI_END: u64 = 0x100000;
+
+const OEM_ID: [u8; 6] = *b"VMM   ";
+const OEM_TABLE_ID: [u8; 8] = *b"VMMTABLE";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: [u8; 4] = *b"VMM ";
+const CREATOR_REVISION: u32 = 1;
+
+const FADT_REVISION: u8 = 6;
+const FADT_MINOR_REVISION: u8 = 5;
+/// There is no PM timer, SCI, GPEs or PM1 blocks, sleeping and resetting
+/// goes through the sleep and reset registers
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+const IAPC_VGA_NOT_PRESENT: u16 = 1 << 2;
+const IAPC_MSI_NOT_SUPPORTED: u16 = 1 << 3;
+const IAPC_CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+const MADT_REVISION: u8 = 5;
+const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee00000;
+const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec00000;
+/// The system also has dual 8259s, which KVM emulates
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+const ADDRESS_SPACE_SYSTEM_IO: u8 = 1;
+const ACCESS_SIZE_BYTE: u8 = 1;
+
+const DSDT_REVISION: u8 = 2;
+const XSDT_REVISION: u8 = 1;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    /// The tables don't fit below 1MiB
+    TooLarge,
+    Memory(MemoryError),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge => write!(f, "ACPI tables don't fit in guest memory"),
+            Self::Memory(err) => write!(f, "failed to write ACPI tables: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AcpiError {}
+
+impl From<MemoryError> for AcpiError {
+    fn from(err: MemoryError) -> Self {
+        Self::Memory(err)
+    }
+}
+
+/// Root System Description Pointer, revision 2
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by all the system description tables
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: [u8; 4],
+    creator_revision: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct GenericAddress {
+    space_id: u8,
+    bit_width: u8,
+    bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+/// Fixed ACPI Description Table, following the header
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct Fadt {
+    firmware_ctrl: u32,
+    dsdt: u32,
+    reserved0: u8,
+    preferred_pm_profile: u8,
+    sci_int: u16,
+    smi_cmd: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    pstate_cnt: u8,
+    pm1a_evt_blk: u32,
+    pm1b_evt_blk: u32,
+    pm1a_cnt_blk: u32,
+    pm1b_cnt_blk: u32,
+    pm2_cnt_blk: u32,
+    pm_tmr_blk: u32,
+    gpe0_blk: u32,
+    gpe1_blk: u32,
+    pm1_evt_len: u8,
+    pm1_cnt_len: u8,
+    pm2_cnt_len: u8,
+    pm_tmr_len: u8,
+    gpe0_blk_len: u8,
+    gpe1_blk_len: u8,
+    gpe1_base: u8,
+    cst_cnt: u8,
+    p_lvl2_lat: u16,
+    p_lvl3_lat: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alrm: u8,
+    mon_alrm: u8,
+    century: u8,
+    iapc_boot_arch: u16,
+    reserved1: u8,
+    flags: u32,
+    reset_reg: GenericAddress,
+    reset_value: u8,
+    arm_boot_arch: u16,
+    fadt_minor_version: u8,
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+    x_pm1a_evt_blk: GenericAddress,
+    x_pm1b_evt_blk: GenericAddress,
+    x_pm1a_cnt_blk: GenericAddress,
+    x_pm1b_cnt_blk: GenericAddress,
+    x_pm2_cnt_blk: GenericAddress,
+    x_pm_tmr_blk: GenericAddress,
+    x_gpe0_blk: GenericAddress,
+    x_gpe1_blk: GenericAddress,
+    sleep_control_reg: GenericAddress,
+    sleep_status_reg: GenericAddress,
+    hypervisor_vendor_id: u64,
+}
+
+/// Multiple APIC Description Table, following the header
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct Madt {
+    local_apic_address: u32,
+    flags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MadtLocalApic {
+    type_: u8,
+    length: u8,
+    processor_uid: u8,
+    apic_id: u8,
+    flags: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MadtIoApic {
+    type_: u8,
+    length: u8,
+    io_apic_id: u8,
+    reserved: u8,
+    address: u32,
+    gsi_base: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MadtInterruptOverride {
+    type_: u8,
+    length: u8,
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    flags: u16,
+}
+
+// SAFETY: the tables are packed and only made of integers
+unsafe impl ByteValued for Rsdp {}
+unsafe impl ByteValued for SdtHeader {}
+unsafe impl ByteValued for Fadt {}
+unsafe impl ByteValued for Madt {}
+unsafe impl ByteValued for MadtLocalApic {}
+unsafe impl ByteValued for MadtIoApic {}
+unsafe impl ByteValued for MadtInterruptOverride {}
+
+/// Prefix the body of a table with its header, filling in the checksum
+fn sdt(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+    let mut header = SdtHeader {
+        signature: *signature,
+        length: (mem::size_of::<SdtHeader>() + body.len()) as u32,
+        revision,
+        oem_id: OEM_ID,
+        oem_table_id: OEM_TABLE_ID,
+        oem_revision: OEM_REVISION,
+        creator_id: CREATOR_ID,
+        creator_revision: CREATOR_REVISION,
+        ..Default::default()
+    };
+
+    header.checksum = checksum(as_bytes(&header)).wrapping_add(checksum(body));
+
+    [as_bytes(&header), body].concat()
+}
+
+/// An 8-bit register at I/O `port`
+fn io_register(port: u16) -> GenericAddress {
+    GenericAddress {
+        space_id: ADDRESS_SPACE_SYSTEM_IO,
+        bit_width: 8,
+        bit_offset: 0,
+        access_size: ACCESS_SIZE_BYTE,
+        address: port.into(),
+    }
+}
+
+fn fadt(dsdt_addr: u64) -> Vec<u8> {
+    let fadt = Fadt {
+        flags: FADT_HW_REDUCED_ACPI | FADT_RESET_REG_SUP,
+        // Resetting goes through the keyboard controller, like on a real PC
+        reset_reg: io_register(i8042::DATA_PORT + i8042::COMMAND_OFFSET),
+        reset_value: i8042::CMD_RESET_CPU,
+        iapc_boot_arch: IAPC_VGA_NOT_PRESENT | IAPC_MSI_NOT_SUPPORTED | IAPC_CMOS_RTC_NOT_PRESENT,
+        fadt_minor_version: FADT_MINOR_REVISION,
+        x_dsdt: dsdt_addr,
+        sleep_control_reg: io_register(acpi_pm::SLEEP_CONTROL_PORT),
+        sleep_status_reg: io_register(acpi_pm::SLEEP_STATUS_PORT),
+        ..Default::default()
+    };
+
+    sdt(b"FACP", FADT_REVISION, as_bytes(&fadt))
+}
+
+fn madt(num_cpus: u8) -> Vec<u8> {
+    let mut body = as_bytes(&Madt {
+        local_apic_address: APIC_DEFAULT_PHYS_BASE,
+        flags: MADT_PCAT_COMPAT,
+    })
+    .to_vec();
+
+    for id in 0..num_cpus {
+        body.extend_from_slice(as_bytes(&MadtLocalApic {
+            type_: MADT_LOCAL_APIC,
+            length: mem::size_of::<MadtLocalApic>() as u8,
+            processor_uid: id,
+            apic_id: id,
+            flags: LOCAL_APIC_ENABLED,
+        }));
+    }
+
+    body.extend_from_slice(as_bytes(&MadtIoApic {
+        type_: MADT_IO_APIC,
+        length: mem::size_of::<MadtIoApic>() as u8,
+        io_apic_id: mptable::ioapic_id(num_cpus),
+        address: IO_APIC_DEFAULT_PHYS_BASE,
+        gsi_base: 0,
+        ..Default::default()
+    }));
+
+    // Only the timer is rerouted, the other ISA IRQs are identity mapped
+    body.extend_from_slice(as_bytes(&MadtInterruptOverride {
+        type_: MADT_INTERRUPT_OVERRIDE,
+        length: mem::size_of::<MadtInterruptOverride>() as u8,
+        bus: 0,
+        source: 0,
+        gsi: TIMER_GSI,
+        flags: 0,
+    }));
+
+    sdt(b"APIC", MADT_REVISION, &body)
+}
+
+/// Write the ACPI tables, with a DSDT containing the AML of the given
+/// devices under `\_SB` and the S5 sleep state, returning the address of the
+/// RSDP
+pub fn setup_acpi(
+    memory: &GuestMemory,
+    num_cpus: u8,
+    devices: &[Vec<u8>],
+) -> Result<u64, AcpiError> {
+    // Each table is 8-byte aligned, following the RSDP
+    let align = |addr: u64| (addr + 7) & !7;
+
+    let dsdt_addr = align(RSDP_ADDR + mem::size_of::<Rsdp>() as u64);
+    let dsdt = sdt(
+        b"DSDT",
+        DSDT_REVISION,
+        &[
+            // SLP_TYPa and SLP_TYPb, the latter is unused on hardware-reduced platforms
+            aml::name(
+                "\\_S5",
+                &aml::package_of(&[aml::integer(acpi_pm::S5_SLEEP_TYPE.into()), aml::integer(0)]),
+            ),
+            aml::scope("\\_SB", devices),
+        ]
+        .concat(),
+    );
+
+    let fadt_addr = align(dsdt_addr + dsdt.len() as u64);
+    let fadt = fadt(dsdt_addr);
+
+    let madt_addr = align(fadt_addr + fadt.len() as u64);
+    let madt = madt(num_cpus);
+
+    let xsdt_addr = align(madt_addr + madt.len() as u64);
+    let xsdt = sdt(
+        b"XSDT",
+        XSDT_REVISION,
+        &[fadt_addr.to_le_bytes(), madt_addr.to_le_bytes()].concat(),
+    );
+
+    if xsdt_addr + xsdt.len() as u64 > ACPI_END {
+        return Err(AcpiError::TooLarge);
+    }
+
+    let mut rsdp = Rsdp {
+        signature: *b"RSD PTR ",
+        oem_id: OEM_ID,
+        revision: 2,
+        length: mem::size_of::<Rsdp>() as u32,
+        xsdt_address: xsdt_addr,
+        ..Default::default()
+    };
+
+    // The first checksum only covers the ACPI 1.0 part of the structure
+    rsdp.checksum = checksum(&as_bytes(&rsdp)[..20]);
+    rsdp.extended_checksum = checksum(as_bytes(&rsdp));
+
+    memory.write_obj(RSDP_ADDR, &rsdp)?;
+    memory.write_slice(dsdt_addr, &dsdt)?;
+    memory.write_slice(fadt_addr, &fadt)?;
+    memory.write_slice(madt_addr, &madt)?;
+    memory.write_slice(xsdt_addr, &xsdt)?;
+
+    Ok(RSDP_ADDR)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        acpi::{fadt, madt, setup_acpi, Fadt, Rsdp, SdtHeader, RSDP_ADDR},
+        memory::GuestMemory,
+        util::checksum,
+    };
+    use std::mem;
+
+    #[test]
+    fn table_layout() {
+        assert_eq!(mem::size_of::<Rsdp>(), 36);
+        assert_eq!(mem::size_of::<SdtHeader>(), 36);
+        assert_eq!(mem::size_of::<SdtHeader>() + mem::size_of::<Fadt>(), 276);
+
+        let fadt = fadt(0xe0028);
+
+        assert_eq!(&fadt[..4], b"FACP");
+        assert_eq!(fadt.len(), 276);
+        // x_dsdt
+        assert_eq!(fadt[140..148], 0xe0028u64.to_le_bytes());
+        assert_eq!(checksum(&fadt), 0);
+
+        // Header, LAPIC address and flags, 4 LAPICs, the IOAPIC and an override
+        let madt = madt(4);
+
+        assert_eq!(madt.len(), 44 + 4 * 8 + 12 + 10);
+        assert_eq!(
+            u32::from_le_bytes(madt[4..8].try_into().unwrap()),
+            madt.len() as u32
+        );
+        // IOAPIC ID
+        assert_eq!(madt[44 + 4 * 8 + 2], 4);
+        assert_eq!(checksum(&madt), 0);
+    }
+
+    #[test]
+    fn rsdp_points_to_tables() {
+        let memory = GuestMemory::with_ram(0x200000).unwrap();
+        let rsdp_addr = setup_acpi(&memory, 2, &[]).unwrap();
+
+        assert_eq!(rsdp_addr, RSDP_ADDR);
+
+        let mut rsdp = [0; 36];
+        memory.read_slice(rsdp_addr, &mut rsdp).unwrap();
+
+        assert_eq!(&rsdp[..8], b"RSD PTR ");
+        assert_eq!(checksum(&rsdp[..20]), 0);
+        assert_eq!(checksum(&rsdp), 0);
+
+        let xsdt_addr = u64::from_le_bytes(rsdp[24..32].try_into().unwrap());
+        let mut xsdt = [0; 52];
+        memory.read_slice(xsdt_addr, &mut xsdt).unwrap();
+
+        assert_eq!(&xsdt[..4], b"XSDT");
+        assert_eq!(checksum(&xsdt), 0);
+
+        for (entry, signature) in xsdt[36..].chunks(8).zip([b"FACP", b"APIC"]) {
+            let mut table = [0; 4];
+            memory
+                .read_slice(u64::from_le_bytes(entry.try_into().unwrap()), &mut table)
+                .unwrap();
+
+            assert_eq!(&table, signature);
+        }
+    }
+}
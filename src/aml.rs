@@ -0,0 +1,264 @@
+//! Just enough of an ACPI Machine Language encoder to describe devices in
+//! the DSDT, ref: https://uefi.org/specs/ACPI/6.5/20_AML_Specification.html
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const DWORD_PREFIX: u8 = 0x0C;
+const QWORD_PREFIX: u8 = 0x0E;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const EXT_OP_PREFIX: u8 = 0x5B;
+const DEVICE_OP: u8 = 0x82;
+
+const ROOT_PREFIX: u8 = b'\\';
+const DUAL_NAME_PREFIX: u8 = 0x2E;
+const MULTI_NAME_PREFIX: u8 = 0x2F;
+
+/// Small resource descriptors, the low bits hold the length
+const IRQ_NO_FLAGS: u8 = 0x22;
+const IO_PORT: u8 = 0x47;
+const END_TAG: u8 = 0x79;
+/// Large resource descriptors
+const MEMORY32_FIXED: u8 = 0x86;
+const EXTENDED_INTERRUPT: u8 = 0x89;
+
+/// The package length encoding, which counts the bytes of the encoding itself
+fn pkg_length(len: usize) -> Vec<u8> {
+    // Up to 63 bytes fit in the lead byte alone, after that the lead byte
+    // holds the lowest nibble and the number of bytes that follow
+    for extra in 0..4 {
+        let total = len + 1 + extra;
+
+        if extra == 0 && total < 1 << 6 {
+            return vec![total as u8];
+        }
+
+        if extra > 0 && total < 1 << (4 + 8 * extra) {
+            let mut bytes = vec![((extra as u8) << 6) | (total as u8 & 0xF)];
+
+            bytes.extend((0..extra).map(|n| (total >> (4 + 8 * n)) as u8));
+
+            return bytes;
+        }
+    }
+
+    panic!("AML package too large: {len} bytes");
+}
+
+/// Prefix `contents` with the opcode and its package length
+fn package(opcode: &[u8], contents: &[u8]) -> Vec<u8> {
+    [opcode, &pkg_length(contents.len()), contents].concat()
+}
+
+/// Encode a path such as `\_SB.COM1`, names shorter than 4 characters are
+/// padded with underscores
+pub fn name_string(path: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    let path = match path.strip_prefix('\\') {
+        Some(path) => {
+            bytes.push(ROOT_PREFIX);
+            path
+        }
+        None => path,
+    };
+
+    let segments = path.split('.').collect::<Vec<_>>();
+
+    match segments.len() {
+        1 => {}
+        2 => bytes.push(DUAL_NAME_PREFIX),
+        count => bytes.extend([MULTI_NAME_PREFIX, count as u8]),
+    }
+
+    for segment in segments {
+        assert!(segment.len() <= 4, "invalid AML name segment {segment}");
+
+        bytes.extend(segment.bytes());
+        bytes.extend(std::iter::repeat_n(b'_', 4 - segment.len()));
+    }
+
+    bytes
+}
+
+/// Encode an integer using the smallest possible representation
+pub fn integer(value: u64) -> Vec<u8> {
+    match value {
+        0 => vec![ZERO_OP],
+        1 => vec![ONE_OP],
+        value if value <= u8::MAX.into() => vec![BYTE_PREFIX, value as u8],
+        value if value <= u16::MAX.into() => {
+            [&[WORD_PREFIX], &(value as u16).to_le_bytes()[..]].concat()
+        }
+        value if value <= u32::MAX.into() => {
+            [&[DWORD_PREFIX], &(value as u32).to_le_bytes()[..]].concat()
+        }
+        value => [&[QWORD_PREFIX], &value.to_le_bytes()[..]].concat(),
+    }
+}
+
+/// Compressed EISA ID such as `PNP0501`, used for `_HID`
+pub fn eisa_id(id: &str) -> Vec<u8> {
+    let id = id.as_bytes();
+    assert_eq!(
+        id.len(),
+        7,
+        "EISA IDs are 3 letters followed by 4 hex digits"
+    );
+
+    let letter = |n: usize| u32::from(id[n] - b'@');
+    let digit = |n: usize| (id[n] as char).to_digit(16).expect("invalid EISA ID");
+
+    let vendor = (letter(0) << 10) | (letter(1) << 5) | letter(2);
+    let product = (digit(3) << 12) | (digit(4) << 8) | (digit(5) << 4) | digit(6);
+
+    // Both halves are stored big endian
+    let id = ((vendor as u16).swap_bytes() as u32) | (((product as u16).swap_bytes() as u32) << 16);
+
+    integer(id.into())
+}
+
+/// `Name(path, value)`
+pub fn name(path: &str, value: &[u8]) -> Vec<u8> {
+    [&[NAME_OP], &name_string(path)[..], value].concat()
+}
+
+/// `Package() { elements }`, the number of elements is encoded in a byte
+pub fn package_of(elements: &[Vec<u8>]) -> Vec<u8> {
+    let count = u8::try_from(elements.len()).expect("AML package has too many elements");
+
+    package(&[PACKAGE_OP], &[vec![count], elements.concat()].concat())
+}
+
+/// `Scope(path) { children }`
+pub fn scope(path: &str, children: &[Vec<u8>]) -> Vec<u8> {
+    package(
+        &[SCOPE_OP],
+        &[name_string(path), children.concat()].concat(),
+    )
+}
+
+/// `Device(path) { children }`
+pub fn device(path: &str, children: &[Vec<u8>]) -> Vec<u8> {
+    package(
+        &[EXT_OP_PREFIX, DEVICE_OP],
+        &[name_string(path), children.concat()].concat(),
+    )
+}
+
+/// `ResourceTemplate() { descriptors }`, a buffer of resource descriptors
+/// terminated by an end tag
+pub fn resource_template(descriptors: &[Vec<u8>]) -> Vec<u8> {
+    // A checksum of zero means that the checksum should be ignored
+    let contents = [&descriptors.concat()[..], &[END_TAG, 0]].concat();
+
+    package(
+        &[BUFFER_OP],
+        &[integer(contents.len() as u64), contents].concat(),
+    )
+}
+
+/// `IO(Decode16, base, base, 1, len)`
+pub fn io_port(base: u16, len: u8) -> Vec<u8> {
+    let base = base.to_le_bytes();
+
+    vec![IO_PORT | 7, 1, base[0], base[1], base[0], base[1], 1, len]
+}
+
+/// `IRQNoFlags() { irq }`, an edge triggered, active high ISA interrupt
+pub fn irq_no_flags(irq: u32) -> Vec<u8> {
+    assert!(irq < 16, "ISA IRQs only go up to 15");
+
+    [&[IRQ_NO_FLAGS | 2][..], &(1u16 << irq).to_le_bytes()].concat()
+}
+
+/// `Interrupt(ResourceConsumer, Edge, ActiveHigh, Exclusive) { gsi }`
+pub fn interrupt(gsi: u32) -> Vec<u8> {
+    // Consumer | Edge triggered
+    let flags = (1 << 0) | (1 << 1);
+
+    [
+        &[EXTENDED_INTERRUPT, 6, 0, flags, 1][..],
+        &gsi.to_le_bytes(),
+    ]
+    .concat()
+}
+
+/// `Memory32Fixed(ReadWrite, base, len)`
+pub fn memory32_fixed(base: u32, len: u32) -> Vec<u8> {
+    [
+        &[MEMORY32_FIXED, 9, 0, 1][..],
+        &base.to_le_bytes(),
+        &len.to_le_bytes(),
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aml::{
+        device, eisa_id, integer, io_port, irq_no_flags, name, name_string, package_of, pkg_length,
+        resource_template,
+    };
+
+    #[test]
+    fn encode_primitives() {
+        assert_eq!(pkg_length(0x3e), [0x3f]);
+        assert_eq!(pkg_length(0x3f), [0x41, 0x04]);
+        assert_eq!(pkg_length(0xffd), [0x4f, 0xff]);
+        assert_eq!(pkg_length(0xffe), [0x81, 0x00, 0x01]);
+
+        assert_eq!(integer(0), [0x00]);
+        assert_eq!(integer(0x3f8), [0x0b, 0xf8, 0x03]);
+        assert_eq!(integer(1 << 32), [0x0e, 0, 0, 0, 0, 1, 0, 0, 0]);
+
+        assert_eq!(name_string("\\_SB"), b"\\_SB_");
+        assert_eq!(name_string("_SB.COM1"), b"\x2e_SB_COM1");
+        assert_eq!(name_string("A.B.C"), b"\x2f\x03A___B___C___");
+
+        // PNP0501 is 0x0105D041
+        assert_eq!(eisa_id("PNP0501"), [0x0c, 0x41, 0xd0, 0x05, 0x01]);
+
+        assert_eq!(
+            package_of(&[integer(5), integer(0)]),
+            [0x12, 0x05, 0x02, 0x0a, 0x05, 0x00]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn package_element_count_is_bounded() {
+        package_of(&vec![integer(0); 256]);
+    }
+
+    #[test]
+    fn encode_device() {
+        let com1 = device(
+            "COM1",
+            &[
+                name("_HID", &eisa_id("PNP0501")),
+                name(
+                    "_CRS",
+                    &resource_template(&[io_port(0x3f8, 8), irq_no_flags(4)]),
+                ),
+            ],
+        );
+
+        #[rustfmt::skip]
+        let expected = [
+            0x5b, 0x82, 0x25, b'C', b'O', b'M', b'1',
+            0x08, b'_', b'H', b'I', b'D', 0x0c, 0x41, 0xd0, 0x05, 0x01,
+            0x08, b'_', b'C', b'R', b'S',
+            0x11, 0x10, 0x0a, 0x0d,
+            0x47, 0x01, 0xf8, 0x03, 0xf8, 0x03, 0x01, 0x08,
+            0x22, 0x10, 0x00,
+            0x79, 0x00,
+        ];
+
+        assert_eq!(com1, expected);
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// A device that's accessed through port I/O or MMIO
+pub trait BusDevice: Send {
+    /// Fill `data` with what's read at `offset` from the start of the device
+    fn read(&mut self, offset: u64, data: &mut [u8]);
+    /// Handle `data` being written at `offset` from the start of the device
+    fn write(&mut self, offset: u64, data: &[u8]);
+}
+
+#[derive(Debug)]
+pub enum BusError {
+    /// Devices must occupy at least one address
+    ZeroLength,
+    /// The range is already occupied by another device, entirely or partially
+    Overlap { base: u64, len: u64 },
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroLength => write!(f, "device range must not be empty"),
+            Self::Overlap { base, len } => write!(
+                f,
+                "device range {base:#x}..{:#x} overlaps with an existing device",
+                base + len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+struct BusEntry {
+    len: u64,
+    device: Arc<Mutex<dyn BusDevice>>,
+}
+
+/// Maps address ranges (I/O ports or guest physical addresses) to devices
+#[derive(Default)]
+pub struct Bus {
+    /// Keyed by the base address of the device
+    devices: BTreeMap<u64, BusEntry>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `device` handle accesses to `base..base + len`
+    pub fn insert(
+        &mut self,
+        device: Arc<Mutex<dyn BusDevice>>,
+        base: u64,
+        len: u64,
+    ) -> Result<(), BusError> {
+        if len == 0 {
+            return Err(BusError::ZeroLength);
+        }
+
+        let end = base
+            .checked_add(len)
+            .ok_or(BusError::Overlap { base, len })?;
+
+        // Only the closest device starting below the end of the new range can
+        // overlap with it, as existing ranges never overlap each other
+        if let Some((&other_base, other)) = self.devices.range(..end).next_back() {
+            if other_base + other.len > base {
+                return Err(BusError::Overlap { base, len });
+            }
+        }
+
+        self.devices.insert(base, BusEntry { len, device });
+
+        Ok(())
+    }
+
+    /// Find the device handling `len` bytes at `addr`, along with the offset
+    /// into it. Accesses straddling the end of a device aren't handled by it
+    pub fn resolve(&self, addr: u64, len: u64) -> Option<(u64, &Arc<Mutex<dyn BusDevice>>)> {
+        let (&base, entry) = self.devices.range(..=addr).next_back()?;
+        let offset = addr - base;
+
+        (offset.checked_add(len)? <= entry.len).then_some((offset, &entry.device))
+    }
+
+    /// Dispatch a read, returns `false` if no device handles `addr`
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        match self.resolve(addr, data.len() as u64) {
+            Some((offset, device)) => {
+                device.lock().unwrap().read(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Dispatch a write, returns `false` if no device handles `addr`
+    pub fn write(&self, addr: u64, data: &[u8]) -> bool {
+        match self.resolve(addr, data.len() as u64) {
+            Some((offset, device)) => {
+                device.lock().unwrap().write(offset, data);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::{Bus, BusDevice, BusError};
+    use std::sync::{Arc, Mutex};
+
+    /// Reads back the offset that was accessed
+    struct OffsetDevice;
+
+    impl BusDevice for OffsetDevice {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn write(&mut self, _offset: u64, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn overlapping_ranges_are_rejected() {
+        let device = Arc::new(Mutex::new(OffsetDevice));
+        let mut bus = Bus::new();
+
+        bus.insert(device.clone(), 0x10, 0x10).unwrap();
+
+        for (base, len) in [(0x10, 1), (0x8, 0x9), (0x1f, 0x10), (0x0, 0x100)] {
+            assert!(matches!(
+                bus.insert(device.clone(), base, len),
+                Err(BusError::Overlap { .. })
+            ));
+        }
+
+        assert!(matches!(
+            bus.insert(device.clone(), 0x0, 0),
+            Err(BusError::ZeroLength)
+        ));
+
+        bus.insert(device.clone(), 0x0, 0x10).unwrap();
+        bus.insert(device.clone(), 0x20, 0x10).unwrap();
+    }
+
+    #[test]
+    fn accesses_are_dispatched_with_offsets() {
+        let mut bus = Bus::new();
+        bus.insert(Arc::new(Mutex::new(OffsetDevice)), 0x3f8, 8)
+            .unwrap();
+
+        let mut data = [0; 1];
+
+        assert!(bus.read(0x3fd, &mut data));
+        assert_eq!(data, [5]);
+        assert!(!bus.read(0x3f7, &mut data));
+        assert!(!bus.read(0x400, &mut data));
+        assert!(!bus.write(0x0, &data));
+    }
+
+    #[test]
+    fn accesses_crossing_the_end_are_rejected() {
+        let mut bus = Bus::new();
+        bus.insert(Arc::new(Mutex::new(OffsetDevice)), 0x1000, 0x10)
+            .unwrap();
+
+        let mut data = [0; 4];
+
+        assert!(bus.read(0x100c, &mut data));
+        assert_eq!(data, [0xc; 4]);
+        assert!(!bus.read(0x100e, &mut data));
+        assert!(!bus.write(0x100f, &data));
+    }
+}
@@ -0,0 +1,435 @@
+use crate::util;
+use std::{fmt, path::PathBuf};
+
+pub const DEFAULT_MEMORY_SIZE: u64 = 1 << 30;
+pub const DEFAULT_CPUS: u8 = 1;
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0 earlyprintk=ttyS0 rdinit=/init";
+
+pub const USAGE: &str = "\
+Usage: vmm --kernel <PATH> [OPTIONS]
+
+Options:
+  -k, --kernel <PATH>     kernel to boot, either a bzImage or an ELF vmlinux
+                          with a PVH entry point
+  -i, --initrd <PATH>     initramfs to pass to the kernel
+  -c, --cmdline <ARGS>    kernel command line, replacing the default of
+                          \"console=ttyS0 earlyprintk=ttyS0 rdinit=/init\"
+  -a, --append <ARGS>     extra arguments appended to the kernel command line
+  -m, --memory <SIZE>     guest RAM, such as 256M or 4G [default: 1G]
+  -p, --cpus <COUNT>      number of vCPUs [default: 1]
+  -s, --serial <BACKEND>  where to connect ttyS0 [default: stdio]
+                          stdio, file:<PATH>, pty, socket:<PATH> or none
+  -d, --debug-exit <PORT> let the guest exit with (value << 1) | 1 by writing
+                          to the I/O port, such as 0xf4
+  -r, --reboot <POLICY>   what to do when the guest reboots [default: exit]
+                          exit or restart
+  -g, --gdb <ADDRESS>     wait for GDB to connect before booting the guest
+                          tcp:<HOST:PORT> or socket:<PATH>
+  -D, --disk <PATH>[,ro]  virtio-blk disk backed by a raw or qcow2 image, may
+                          be given multiple times, further options are
+                          block-size=<SIZE> and packed for packed virtqueues
+  -v, --verbose           log accesses to unhandled I/O ports and MMIO
+  -h, --help              print this message
+
+Exit status:
+  0  the guest powered off
+  1  the VMM failed
+  2  invalid arguments
+  4  the guest rebooted, with --reboot=exit
+  6  the guest crashed
+  8  the VM was killed with Ctrl-A x or from GDB
+  odd codes come from the guest, with --debug-exit, so 1 is also the guest
+  writing 0
+";
+
+/// What the guest's serial port is connected to
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SerialBackend {
+    /// The terminal, Ctrl-A x exits the VMM
+    #[default]
+    Stdio,
+    /// Output is written to the file, there is no input
+    File(PathBuf),
+    /// A newly allocated pseudoterminal
+    Pty,
+    /// A unix socket at the given path that clients can connect to
+    Socket(PathBuf),
+    /// Output is discarded, there is no input
+    None,
+}
+
+/// Where to listen for GDB
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GdbAddress {
+    /// Such as `localhost:1234`
+    Tcp(String),
+    /// A unix socket at the given path
+    Socket(PathBuf),
+}
+
+impl fmt::Display for GdbAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Socket(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A disk image passed with `--disk`, which the guest sees as /dev/vda,
+/// /dev/vdb and so on in order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiskConfig {
+    pub path: PathBuf,
+    pub read_only: bool,
+    /// Logical block size, a power of two from 512 bytes to 64KiB
+    pub block_size: u32,
+    /// Offer packed virtqueues instead of split ones only
+    pub packed: bool,
+}
+
+impl DiskConfig {
+    /// Parse `path[,ro][,block-size=<SIZE>][,packed]`
+    fn parse(value: &str) -> Option<Self> {
+        let mut options = value.split(',');
+        let path = options.next().filter(|path| !path.is_empty())?;
+
+        let mut disk = Self {
+            path: path.into(),
+            read_only: false,
+            block_size: 512,
+            packed: false,
+        };
+
+        for option in options {
+            match option.split_once('=') {
+                None if option == "ro" => disk.read_only = true,
+                None if option == "packed" => disk.packed = true,
+                Some(("block-size", size)) => {
+                    disk.block_size = util::parse_size(size)
+                        .and_then(|size| u32::try_from(size).ok())
+                        .filter(|size| size.is_power_of_two() && (512..=65536).contains(size))?;
+                }
+                _ => return None,
+            }
+        }
+
+        Some(disk)
+    }
+}
+
+/// What to do when the guest reboots
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RebootPolicy {
+    /// Stop the VMM, with a distinct exit code
+    #[default]
+    Exit,
+    /// Boot the kernel again in a fresh VM
+    Restart,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    /// `--help` was passed, not an actual error
+    Help,
+    UnknownOption(String),
+    MissingValue(&'static str),
+    InvalidValue {
+        option: &'static str,
+        value: String,
+    },
+    MissingKernel,
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Help => write!(f, "help requested"),
+            Self::UnknownOption(option) => write!(f, "unknown option '{option}'"),
+            Self::MissingValue(option) => write!(f, "{option} requires a value"),
+            Self::InvalidValue { option, value } => {
+                write!(f, "invalid value '{value}' for {option}")
+            }
+            Self::MissingKernel => write!(f, "no kernel passed, use --kernel <PATH>"),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Args {
+    pub kernel: PathBuf,
+    pub initrd: Option<PathBuf>,
+    pub cmdline: String,
+    pub memory: u64,
+    pub cpus: u8,
+    pub serial: SerialBackend,
+    pub reboot: RebootPolicy,
+    pub debug_exit: Option<u16>,
+    pub gdb: Option<GdbAddress>,
+    pub disks: Vec<DiskConfig>,
+    pub verbose: bool,
+}
+
+impl Args {
+    /// Parse the arguments, excluding the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, CliError> {
+        let mut kernel = None;
+        let mut initrd = None;
+        let mut cmdline = DEFAULT_CMDLINE.to_string();
+        let mut append = Vec::new();
+        let mut memory = DEFAULT_MEMORY_SIZE;
+        let mut cpus = DEFAULT_CPUS;
+        let mut serial = SerialBackend::default();
+        let mut reboot = RebootPolicy::default();
+        let mut debug_exit = None;
+        let mut gdb = None;
+        let mut disks = Vec::new();
+        let mut verbose = false;
+
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            // Both `--option value` and `--option=value` are accepted
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => {
+                    (name.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+
+            let option = match name.as_str() {
+                "-h" | "--help" => return Err(CliError::Help),
+                "-v" | "--verbose" => {
+                    verbose = true;
+                    continue;
+                }
+                "-k" | "--kernel" => "--kernel",
+                "-i" | "--initrd" => "--initrd",
+                "-c" | "--cmdline" => "--cmdline",
+                "-a" | "--append" => "--append",
+                "-m" | "--memory" => "--memory",
+                "-p" | "--cpus" => "--cpus",
+                "-s" | "--serial" => "--serial",
+                "-r" | "--reboot" => "--reboot",
+                "-d" | "--debug-exit" => "--debug-exit",
+                "-g" | "--gdb" => "--gdb",
+                "-D" | "--disk" => "--disk",
+                _ => return Err(CliError::UnknownOption(name)),
+            };
+
+            let value = inline_value
+                .or_else(|| args.next())
+                .ok_or(CliError::MissingValue(option))?;
+
+            let invalid = |value: String| CliError::InvalidValue { option, value };
+
+            match option {
+                "--kernel" => kernel = Some(PathBuf::from(value)),
+                "--initrd" => initrd = Some(PathBuf::from(value)),
+                "--cmdline" => cmdline = value,
+                "--append" => append.push(value),
+                "--memory" => {
+                    memory = util::parse_size(&value)
+                        .filter(|&size| size != 0 && size & 0xFFF == 0)
+                        .ok_or_else(|| invalid(value))?;
+                }
+                "--cpus" => {
+                    cpus = value
+                        .parse()
+                        .ok()
+                        .filter(|&cpus| cpus != 0)
+                        .ok_or_else(|| invalid(value))?;
+                }
+                "--serial" => {
+                    serial = match value.split_once(':') {
+                        Some(("file", path)) if !path.is_empty() => {
+                            SerialBackend::File(path.into())
+                        }
+                        Some(("socket", path)) if !path.is_empty() => {
+                            SerialBackend::Socket(path.into())
+                        }
+                        None if value == "stdio" => SerialBackend::Stdio,
+                        None if value == "pty" => SerialBackend::Pty,
+                        None if value == "none" => SerialBackend::None,
+                        _ => return Err(invalid(value)),
+                    };
+                }
+                "--reboot" => {
+                    reboot = match value.as_str() {
+                        "exit" => RebootPolicy::Exit,
+                        "restart" => RebootPolicy::Restart,
+                        _ => return Err(invalid(value)),
+                    };
+                }
+                "--debug-exit" => {
+                    let port = match value.strip_prefix("0x") {
+                        Some(hex) => u16::from_str_radix(hex, 16),
+                        None => value.parse(),
+                    };
+
+                    debug_exit = Some(port.map_err(|_| invalid(value))?);
+                }
+                "--gdb" => {
+                    gdb = match value.split_once(':') {
+                        Some(("tcp", addr)) if addr.contains(':') => {
+                            Some(GdbAddress::Tcp(addr.to_string()))
+                        }
+                        Some(("socket", path)) if !path.is_empty() => {
+                            Some(GdbAddress::Socket(path.into()))
+                        }
+                        _ => return Err(invalid(value)),
+                    };
+                }
+                "--disk" => disks.push(DiskConfig::parse(&value).ok_or_else(|| invalid(value))?),
+                _ => unreachable!(),
+            }
+        }
+
+        // Appended arguments always follow the base command line, wherever
+        // `--cmdline` is given
+        for value in append {
+            if !cmdline.is_empty() {
+                cmdline.push(' ');
+            }
+
+            cmdline.push_str(&value);
+        }
+
+        Ok(Self {
+            kernel: kernel.ok_or(CliError::MissingKernel)?,
+            initrd,
+            cmdline,
+            memory,
+            cpus,
+            serial,
+            reboot,
+            debug_exit,
+            gdb,
+            disks,
+            verbose,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{
+        Args, CliError, DiskConfig, GdbAddress, RebootPolicy, SerialBackend, DEFAULT_CMDLINE,
+    };
+
+    fn parse(args: &[&str]) -> Result<Args, CliError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_all_options() {
+        let args = parse(&[
+            "--kernel",
+            "bzImage",
+            "-i",
+            "initramfs",
+            "--append=quiet",
+            "--memory=4G",
+            "--cpus",
+            "4",
+            "--serial",
+            "socket:/tmp/vmm.sock",
+            "--reboot=restart",
+            "--debug-exit",
+            "0xf4",
+            "--gdb=tcp:localhost:1234",
+            "--disk",
+            "root.img",
+            "-D",
+            "data.img,ro,block-size=4K,packed",
+            "-v",
+        ])
+        .unwrap();
+
+        assert_eq!(args.kernel.to_str(), Some("bzImage"));
+        assert_eq!(args.initrd.unwrap().to_str(), Some("initramfs"));
+        assert_eq!(args.cmdline, format!("{DEFAULT_CMDLINE} quiet"));
+        assert_eq!(args.memory, 4 << 30);
+        assert_eq!(args.cpus, 4);
+        assert_eq!(args.serial, SerialBackend::Socket("/tmp/vmm.sock".into()));
+        assert_eq!(args.reboot, RebootPolicy::Restart);
+        assert_eq!(args.debug_exit, Some(0xf4));
+        assert_eq!(args.gdb, Some(GdbAddress::Tcp("localhost:1234".into())));
+        assert_eq!(
+            args.disks,
+            [
+                DiskConfig {
+                    path: "root.img".into(),
+                    read_only: false,
+                    block_size: 512,
+                    packed: false,
+                },
+                DiskConfig {
+                    path: "data.img".into(),
+                    read_only: true,
+                    block_size: 4096,
+                    packed: true,
+                },
+            ]
+        );
+        assert!(args.verbose);
+
+        let args = parse(&["-k", "bzImage", "--cmdline", "console=ttyS0"]).unwrap();
+
+        assert_eq!(args.initrd, None);
+        assert_eq!(args.cmdline, "console=ttyS0");
+        assert_eq!(args.serial, SerialBackend::Stdio);
+        assert_eq!(args.reboot, RebootPolicy::Exit);
+        assert_eq!(args.debug_exit, None);
+        assert_eq!(args.gdb, None);
+        assert!(args.disks.is_empty());
+
+        for args in [
+            ["-k", "bzImage", "-a", "foo", "-c", "bar", "-a", "baz"],
+            ["-k", "bzImage", "-c", "bar", "-a", "foo", "-a", "baz"],
+        ] {
+            assert_eq!(parse(&args).unwrap().cmdline, "bar foo baz");
+        }
+
+        let args = parse(&["-k", "bzImage", "-g", "socket:/tmp/gdb.sock"]).unwrap();
+        assert_eq!(args.gdb, Some(GdbAddress::Socket("/tmp/gdb.sock".into())));
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert!(matches!(parse(&["--help"]), Err(CliError::Help)));
+        assert!(matches!(parse(&[]), Err(CliError::MissingKernel)));
+        assert!(matches!(
+            parse(&["-k", "bzImage", "--frobnicate"]),
+            Err(CliError::UnknownOption(_))
+        ));
+        assert!(matches!(
+            parse(&["--kernel"]),
+            Err(CliError::MissingValue("--kernel"))
+        ));
+
+        for (option, value) in [
+            ("--memory", "1000"),
+            ("--memory", "0"),
+            ("--cpus", "0"),
+            ("--cpus", "256"),
+            ("--serial", "file:"),
+            ("--serial", "tcp:1234"),
+            ("--reboot", "halt"),
+            ("--debug-exit", "0x10000"),
+            ("--gdb", "1234"),
+            ("--gdb", "tcp:1234"),
+            ("--disk", ",ro"),
+            ("--disk", "root.img,rw"),
+            ("--disk", "root.img,block-size=1000"),
+            ("--disk", "root.img,block-size=128K"),
+        ] {
+            assert!(matches!(
+                parse(&["-k", "bzImage", option, value]),
+                Err(CliError::InvalidValue { .. })
+            ));
+        }
+    }
+}
@@ -5,8 +5,10 @@ pub mod PageTables {
     pub const PML4: usize = 0x1000;
     /// Page Directory Pointer Table
     pub const PDPT: usize = 0x2000;
-    /// Page Directory
-    pub const PD: usize = 0x3000;
+    /// Page Directories, one for every GiB of identity mapped memory
+    pub const PD: usize = 0x30000;
+    /// End of the region reserved for Page Directories, right below the EBDA
+    pub const PD_END: usize = 0x9f000;
 }
 
 /// Paging
@@ -16,8 +18,12 @@ pub mod PageFlags {
     pub const PRESENT: u64 = 1 << 0;
     /// The page is read/write
     pub const READ_WRITE: u64 = 1 << 1;
+    /// The page is accessible from user mode
+    pub const USER: u64 = 1 << 2;
     /// Make PDE map to a 4MiB page, Page Size Extension must be enabled
     pub const PAGE_SIZE: u64 = 1 << 7;
+    /// Instructions can't be fetched from the page, if enabled in EFER
+    pub const NO_EXECUTE: u64 = 1 << 63;
 }
 
 /// Control Register 0
@@ -37,6 +43,8 @@ pub mod Cr4Flags {
     /// Physical Address Extension, size of large pages is reduced from
     /// 4MiB to 2MiB and PSE is enabled regardless of the PSE bit
     pub const PAE: u64 = 1 << 5;
+    /// 5-level paging, 57-bit linear addresses
+    pub const LA57: u64 = 1 << 12;
 }
 
 /// Extended Feature Enable Register
@@ -46,6 +54,8 @@ pub mod EferFlags {
     pub const LME: u64 = 1 << 8;
     /// Long Mode Active
     pub const LMA: u64 = 1 << 10;
+    /// No-Execute Enable, otherwise bit 63 of page table entries is reserved
+    pub const NXE: u64 = 1 << 11;
 }
 
 /// Code/Data Segment flags
@@ -60,3 +70,10 @@ pub mod SegmentFlags {
     /// Write permissions for Data Segment
     pub const DATA_WRITE: u8 = 1 << 1;
 }
+
+/// The PIT is wired to pin 2 of the IOAPIC, as KVM routes it, rather than
+/// to pin 0 like ISA IRQ 0 would be
+pub const TIMER_GSI: u32 = 2;
+
+/// Number of pins of the IOAPIC, GSIs past them can't be delivered
+pub const IOAPIC_PINS: u32 = 24;
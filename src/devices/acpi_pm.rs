@@ -0,0 +1,77 @@
+//! Sleep control and status registers of a hardware-reduced ACPI platform,
+//! taking the place of the PM1 control and status blocks
+
+use crate::{
+    bus::BusDevice,
+    power::{PowerControl, VmExitStatus},
+};
+
+pub const SLEEP_CONTROL_PORT: u16 = 0x600;
+pub const SLEEP_STATUS_PORT: u16 = 0x601;
+pub const PORT_COUNT: u16 = 2;
+
+/// SLP_TYP value of the S5 (soft off) state, reported through `\_S5`
+pub const S5_SLEEP_TYPE: u8 = 5;
+
+const SLEEP_CONTROL: u64 = 0;
+
+const SLP_TYP_SHIFT: u8 = 2;
+const SLP_TYP_MASK: u8 = 0x7;
+const SLP_EN: u8 = 1 << 5;
+
+pub struct AcpiPm {
+    power: PowerControl,
+}
+
+impl AcpiPm {
+    pub fn new(power: PowerControl) -> Self {
+        Self { power }
+    }
+}
+
+impl BusDevice for AcpiPm {
+    fn read(&mut self, _offset: u64, data: &mut [u8]) {
+        // Nothing ever wakes up from sleep, since only S5 is supported
+        data.fill(0);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let Some(&value) = data.first() else {
+            return;
+        };
+
+        if offset == SLEEP_CONTROL
+            && value & SLP_EN != 0
+            && (value >> SLP_TYP_SHIFT) & SLP_TYP_MASK == S5_SLEEP_TYPE
+        {
+            self.power.request(VmExitStatus::PowerOff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bus::BusDevice,
+        devices::acpi_pm::AcpiPm,
+        power::{PowerControl, VmExitStatus},
+    };
+
+    #[test]
+    fn only_s5_powers_off() {
+        let power = PowerControl::new();
+        let mut pm = AcpiPm::new(power.clone());
+
+        // S3 and S5 without SLP_EN are ignored
+        pm.write(0, &[(3 << 2) | (1 << 5)]);
+        pm.write(0, &[5 << 2]);
+        assert_eq!(power.status(), None);
+
+        pm.write(0, &[(5 << 2) | (1 << 5)]);
+        assert_eq!(power.status(), Some(VmExitStatus::PowerOff));
+
+        // Only the first request counts
+        power.request(VmExitStatus::Reboot);
+        assert_eq!(power.wait(), VmExitStatus::PowerOff);
+    }
+}
@@ -0,0 +1,54 @@
+//! Lets the guest stop the VM with an exit code of its choosing, like QEMU's
+//! isa-debug-exit device, e.g. for reporting the result of a test run
+
+use crate::{
+    bus::BusDevice,
+    power::{PowerControl, VmExitStatus},
+};
+
+pub const PORT_COUNT: u16 = 1;
+
+pub struct DebugExit {
+    power: PowerControl,
+}
+
+impl DebugExit {
+    pub fn new(power: PowerControl) -> Self {
+        Self { power }
+    }
+}
+
+impl BusDevice for DebugExit {
+    fn read(&mut self, _offset: u64, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn write(&mut self, _offset: u64, data: &[u8]) {
+        let Some(&value) = data.first() else {
+            return;
+        };
+
+        // Same as QEMU, so that an exit code of 0 can't be mistaken for the
+        // guest powering off
+        self.power.request(VmExitStatus::Exit((value << 1) | 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bus::BusDevice,
+        devices::debug_exit::DebugExit,
+        power::{PowerControl, VmExitStatus},
+    };
+
+    #[test]
+    fn exit_code_is_derived_from_value() {
+        let power = PowerControl::new();
+
+        DebugExit::new(power.clone()).write(0, &[0x21, 0, 0, 0]);
+
+        assert_eq!(power.status(), Some(VmExitStatus::Exit(0x43)));
+        assert_eq!(VmExitStatus::Exit(0x43).exit_code(), 0x43);
+    }
+}
@@ -0,0 +1,68 @@
+//! Just enough of the i8042 keyboard controller for the guest to reset the
+//! system through it, there is no keyboard behind it
+
+use crate::{
+    bus::BusDevice,
+    power::{PowerControl, VmExitStatus},
+};
+
+/// Data port, followed by the status/command port 4 ports later
+pub const DATA_PORT: u16 = 0x60;
+pub const PORT_COUNT: u16 = 5;
+
+/// Offset of the command (write) and status (read) register
+pub const COMMAND_OFFSET: u16 = 4;
+
+/// Pulses the CPU reset line
+pub const CMD_RESET_CPU: u8 = 0xFE;
+
+pub struct I8042 {
+    power: PowerControl,
+}
+
+impl I8042 {
+    pub fn new(power: PowerControl) -> Self {
+        Self { power }
+    }
+}
+
+impl BusDevice for I8042 {
+    fn read(&mut self, _offset: u64, data: &mut [u8]) {
+        // Empty input and output buffers, so the guest never waits on us
+        data.fill(0);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if offset == COMMAND_OFFSET.into() && data.first() == Some(&CMD_RESET_CPU) {
+            self.power.request(VmExitStatus::Reboot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bus::Bus,
+        devices::i8042::{CMD_RESET_CPU, DATA_PORT, I8042, PORT_COUNT},
+        power::{PowerControl, VmExitStatus},
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn reset_command_reboots() {
+        let power = PowerControl::new();
+        let mut bus = Bus::new();
+        bus.insert(
+            Arc::new(Mutex::new(I8042::new(power.clone()))),
+            DATA_PORT.into(),
+            PORT_COUNT.into(),
+        )
+        .unwrap();
+
+        assert!(bus.write(0x60, &[CMD_RESET_CPU]));
+        assert_eq!(power.status(), None);
+
+        assert!(bus.write(0x64, &[CMD_RESET_CPU]));
+        assert_eq!(power.status(), Some(VmExitStatus::Reboot));
+    }
+}
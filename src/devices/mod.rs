@@ -0,0 +1,45 @@
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use std::{
+    fs::File,
+    io::Write,
+    os::fd::{AsFd, BorrowedFd},
+};
+
+pub mod acpi_pm;
+pub mod debug_exit;
+pub mod i8042;
+pub mod serial;
+pub mod virtio_blk;
+pub mod virtio_mmio;
+
+/// Edge triggered interrupt, backed by an eventfd that is hooked up to a GSI
+/// through `Kvm::register_irqfd`
+pub struct Interrupt {
+    eventfd: File,
+}
+
+impl Interrupt {
+    pub fn new() -> Result<Self, std::io::Error> {
+        Ok(Self {
+            eventfd: eventfd(0, EfdFlags::EFD_CLOEXEC)?.into(),
+        })
+    }
+
+    /// Another handle to the same eventfd, e.g. to hook it up to a new VM
+    pub fn try_clone(&self) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            eventfd: self.eventfd.try_clone()?,
+        })
+    }
+
+    /// Inject the interrupt into the guest
+    pub fn trigger(&self) -> Result<(), std::io::Error> {
+        (&self.eventfd).write_all(&1u64.to_ne_bytes())
+    }
+}
+
+impl AsFd for Interrupt {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.eventfd.as_fd()
+    }
+}
@@ -0,0 +1,449 @@
+//! 16550A UART, ref: https://www.ti.com/lit/ds/symlink/pc16550d.pdf
+
+use crate::{aml, bus::BusDevice, devices::Interrupt};
+use std::{collections::VecDeque, io::Write, mem};
+
+/// I/O port of the first serial port (COM1, ttyS0)
+pub const COM1_PORT: u16 = 0x3f8;
+/// IRQ line of COM1
+pub const COM1_IRQ: u32 = 4;
+/// Number of I/O ports occupied by the UART
+pub const PORT_COUNT: u16 = 8;
+
+/// Receiver Buffer (read) / Transmitter Holding (write), or the Divisor
+/// Latch LSB when DLAB is set
+const DATA: u8 = 0;
+/// Interrupt Enable, or the Divisor Latch MSB when DLAB is set
+const IER: u8 = 1;
+/// Interrupt Identification (read) / FIFO Control (write)
+const IIR_FCR: u8 = 2;
+/// Line Control
+const LCR: u8 = 3;
+/// Modem Control
+const MCR: u8 = 4;
+/// Line Status
+const LSR: u8 = 5;
+/// Modem Status
+const MSR: u8 = 6;
+/// Scratch
+const SCR: u8 = 7;
+
+/// Received Data Available
+const IER_RDA: u8 = 1 << 0;
+/// Transmitter Holding Register Empty
+const IER_THRE: u8 = 1 << 1;
+const IER_MASK: u8 = 0x0F;
+
+/// No interrupt is pending
+const IIR_NONE: u8 = 1 << 0;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+/// Set when the FIFOs are enabled
+const IIR_FIFO: u8 = 0xC0;
+
+const FCR_ENABLE_FIFO: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+/// Divisor Latch Access Bit
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOP: u8 = 1 << 4;
+const MCR_MASK: u8 = 0x1F;
+
+/// Data Ready
+const LSR_DR: u8 = 1 << 0;
+/// Overrun Error, input was dropped as the FIFO was full
+const LSR_OE: u8 = 1 << 1;
+/// Transmitter Holding Register Empty
+const LSR_THRE: u8 = 1 << 5;
+/// Transmitter Empty
+const LSR_TEMT: u8 = 1 << 6;
+
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+
+/// 115200 baud
+const DEFAULT_DIVISOR: u16 = 1;
+/// Size of the receive FIFO, further input from the host waits for room in it
+const FIFO_SIZE: usize = 16;
+
+/// Describe a UART to the guest's ACPI, `index` 0 being COM1
+pub fn aml(index: u8, port: u16, irq: u32) -> Vec<u8> {
+    aml::device(
+        &format!("COM{}", index + 1),
+        &[
+            aml::name("_HID", &aml::eisa_id("PNP0501")),
+            aml::name("_UID", &aml::integer(index.into())),
+            aml::name(
+                "_CRS",
+                &aml::resource_template(&[
+                    aml::io_port(port, PORT_COUNT as u8),
+                    aml::irq_no_flags(irq),
+                ]),
+            ),
+        ],
+    )
+}
+
+pub struct Serial {
+    interrupt: Interrupt,
+    out: Box<dyn Write + Send>,
+    /// The receive FIFO, as seen by the guest
+    rx: VecDeque<u8>,
+    /// Input from the host that doesn't fit in the FIFO yet
+    input: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo_enabled: bool,
+    /// Set when input is dropped, till LSR is read
+    overrun: bool,
+    /// The THR empty interrupt stays pending till IIR is read or THR is written
+    thre_pending: bool,
+}
+
+impl Serial {
+    pub fn new(interrupt: Interrupt, out: Box<dyn Write + Send>) -> Self {
+        let mut serial = Self {
+            interrupt,
+            out,
+            rx: VecDeque::new(),
+            input: VecDeque::new(),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo_enabled: false,
+            overrun: false,
+            thre_pending: false,
+        };
+
+        serial.reset();
+        serial
+    }
+
+    /// Put the registers back in their power-on state and drop pending input,
+    /// the host side stays connected
+    pub fn reset(&mut self) {
+        self.rx.clear();
+        self.input.clear();
+        self.ier = 0;
+        self.lcr = 0;
+        self.mcr = MCR_OUT2;
+        self.scr = 0;
+        self.divisor = DEFAULT_DIVISOR;
+        self.fifo_enabled = false;
+        self.overrun = false;
+        self.thre_pending = false;
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn loopback(&self) -> bool {
+        self.mcr & MCR_LOOP != 0
+    }
+
+    fn trigger_interrupt(&self) {
+        if let Err(err) = self.interrupt.trigger() {
+            eprintln!("failed to trigger serial interrupt: {err}");
+        }
+    }
+
+    /// Interrupt with the highest priority that's currently pending
+    fn pending_interrupt(&self) -> Option<u8> {
+        if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            Some(IIR_RDA)
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            Some(IIR_THRE)
+        } else {
+            None
+        }
+    }
+
+    fn update_interrupt(&self) {
+        if self.pending_interrupt().is_some() {
+            self.trigger_interrupt();
+        }
+    }
+
+    /// Queue up input for the guest to read, it's moved into the FIFO as the
+    /// guest drains it
+    pub fn enqueue_input(&mut self, input: &[u8]) {
+        if input.is_empty() || self.loopback() {
+            return;
+        }
+
+        self.input.extend(input);
+        self.fill_fifo();
+        self.update_interrupt();
+    }
+
+    fn fill_fifo(&mut self) {
+        while self.rx.len() < FIFO_SIZE {
+            match self.input.pop_front() {
+                Some(byte) => self.rx.push_back(byte),
+                None => break,
+            }
+        }
+    }
+
+    /// Loopback data goes straight into the FIFO, and is dropped once it's full
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() < FIFO_SIZE {
+            self.rx.push_back(byte);
+        } else {
+            self.overrun = true;
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.loopback() {
+            self.receive(byte);
+        } else if let Err(err) = self.out.write_all(&[byte]).and_then(|_| self.out.flush()) {
+            eprintln!("failed to write serial output: {err}");
+        }
+
+        // Transmission is instantaneous, so THR is immediately empty again
+        self.thre_pending = true;
+        self.update_interrupt();
+    }
+
+    fn read_register(&mut self, offset: u8) -> u8 {
+        match offset {
+            DATA if self.dlab() => self.divisor as u8,
+            DATA => {
+                let byte = self.rx.pop_front().unwrap_or(0);
+                self.fill_fifo();
+
+                byte
+            }
+            IER if self.dlab() => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let fifo = if self.fifo_enabled { IIR_FIFO } else { 0 };
+
+                match self.pending_interrupt() {
+                    Some(IIR_THRE) => {
+                        // Reading IIR acknowledges the THRE interrupt
+                        self.thre_pending = false;
+                        IIR_THRE | fifo
+                    }
+                    Some(iir) => iir | fifo,
+                    None => IIR_NONE | fifo,
+                }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let dr = if self.rx.is_empty() { 0 } else { LSR_DR };
+                // Reading LSR clears the error bits
+                let oe = if mem::take(&mut self.overrun) {
+                    LSR_OE
+                } else {
+                    0
+                };
+
+                LSR_THRE | LSR_TEMT | dr | oe
+            }
+            MSR if self.loopback() => {
+                // The modem control outputs are wired to the status inputs
+                let mut msr = 0;
+
+                if self.mcr & MCR_RTS != 0 {
+                    msr |= MSR_CTS;
+                }
+                if self.mcr & MCR_DTR != 0 {
+                    msr |= MSR_DSR;
+                }
+                if self.mcr & MCR_OUT1 != 0 {
+                    msr |= MSR_RI;
+                }
+                if self.mcr & MCR_OUT2 != 0 {
+                    msr |= MSR_DCD;
+                }
+
+                msr
+            }
+            MSR => MSR_DCD | MSR_DSR | MSR_CTS,
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u8, value: u8) {
+        match offset {
+            DATA if self.dlab() => self.divisor = (self.divisor & 0xFF00) | u16::from(value),
+            DATA => self.transmit(value),
+            IER if self.dlab() => {
+                self.divisor = (self.divisor & 0x00FF) | (u16::from(value) << 8);
+            }
+            IER => {
+                let enabled_thre = (value & !self.ier) & IER_THRE != 0;
+                self.ier = value & IER_MASK;
+
+                // THR is always empty, so enabling the interrupt fires it
+                if enabled_thre {
+                    self.thre_pending = true;
+                }
+
+                self.update_interrupt();
+            }
+            IIR_FCR => {
+                self.fifo_enabled = value & FCR_ENABLE_FIFO != 0;
+
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                    self.fill_fifo();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & MCR_MASK,
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for Serial {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        for (n, byte) in data.iter_mut().enumerate() {
+            *byte = self.read_register(offset as u8 + n as u8);
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        for (n, byte) in data.iter().enumerate() {
+            self.write_register(offset as u8 + n as u8, *byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::devices::{
+        serial::{
+            Serial, DATA, FIFO_SIZE, IER, IER_RDA, IER_THRE, IIR_FCR, IIR_NONE, IIR_RDA, IIR_THRE,
+            LCR, LCR_DLAB, LSR, LSR_DR, LSR_OE, LSR_TEMT, LSR_THRE, MCR, MCR_LOOP,
+        },
+        Interrupt,
+    };
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serial() -> (Serial, SharedBuffer) {
+        let out = SharedBuffer::default();
+        let serial = Serial::new(Interrupt::new().unwrap(), Box::new(out.clone()));
+
+        (serial, out)
+    }
+
+    #[test]
+    fn transmit_and_receive() {
+        let (mut serial, out) = serial();
+
+        serial.write_register(DATA, b'A');
+        assert_eq!(*out.0.lock().unwrap(), b"A");
+
+        serial.write_register(IER, IER_RDA);
+        assert_eq!(serial.read_register(LSR) & LSR_DR, 0);
+        assert_eq!(serial.read_register(IIR_FCR), IIR_NONE);
+
+        serial.enqueue_input(b"hi");
+        assert_eq!(serial.read_register(LSR) & LSR_DR, LSR_DR);
+        assert_eq!(serial.read_register(IIR_FCR), IIR_RDA);
+        assert_eq!(serial.read_register(DATA), b'h');
+        assert_eq!(serial.read_register(DATA), b'i');
+        assert_eq!(serial.read_register(IIR_FCR), IIR_NONE);
+    }
+
+    #[test]
+    fn input_larger_than_the_fifo_is_kept() {
+        let (mut serial, _) = serial();
+        let input: Vec<u8> = (0..FIFO_SIZE as u8 * 4).collect();
+
+        serial.enqueue_input(&input[..FIFO_SIZE + 1]);
+        serial.enqueue_input(&input[FIFO_SIZE + 1..]);
+        assert_eq!(serial.rx.len(), FIFO_SIZE);
+
+        for &byte in &input {
+            assert_eq!(serial.read_register(LSR), LSR_DR | LSR_THRE | LSR_TEMT);
+            assert_eq!(serial.read_register(DATA), byte);
+        }
+
+        assert_eq!(serial.read_register(LSR) & LSR_DR, 0);
+    }
+
+    #[test]
+    fn loopback_overruns_the_fifo() {
+        let (mut serial, _) = serial();
+
+        serial.write_register(MCR, MCR_LOOP);
+
+        for _ in 0..FIFO_SIZE {
+            serial.write_register(DATA, b'a');
+        }
+
+        assert_eq!(serial.read_register(LSR) & LSR_OE, 0);
+        serial.write_register(DATA, b'b');
+        assert_eq!(serial.read_register(LSR) & LSR_OE, LSR_OE);
+        assert_eq!(serial.read_register(LSR) & LSR_OE, 0);
+
+        for _ in 0..FIFO_SIZE {
+            assert_eq!(serial.read_register(DATA), b'a');
+        }
+
+        assert_eq!(serial.read_register(LSR) & LSR_DR, 0);
+    }
+
+    #[test]
+    fn thre_interrupt_is_cleared_by_iir_read() {
+        let (mut serial, _) = serial();
+
+        serial.write_register(IER, IER_THRE);
+        assert_eq!(serial.read_register(IIR_FCR), IIR_THRE);
+        assert_eq!(serial.read_register(IIR_FCR), IIR_NONE);
+    }
+
+    #[test]
+    fn divisor_latch_and_loopback() {
+        let (mut serial, out) = serial();
+
+        serial.write_register(LCR, LCR_DLAB);
+        serial.write_register(DATA, 0x0C);
+        serial.write_register(IER, 0x00);
+        assert_eq!(serial.read_register(DATA), 0x0C);
+        serial.write_register(LCR, 0x03);
+        assert_eq!(serial.read_register(IER), 0);
+
+        serial.write_register(MCR, MCR_LOOP);
+        serial.write_register(DATA, b'x');
+        assert_eq!(serial.read_register(DATA), b'x');
+        assert!(out.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,579 @@
+//! virtio-blk, a disk backed by a raw or qcow2 image, ref:
+//! https://docs.oasis-open.org/virtio/virtio/v1.2/cs01/virtio-v1.2-cs01.html#x1-2800007
+
+use crate::{
+    disk::DiskImage,
+    memory::GuestMemory,
+    util::{as_bytes, ByteValued},
+    virtio::{Features, QueueConfig, VirtioDevice, VirtioError, VirtioInterrupt},
+    virtqueue::{ChainReader, ChainWriter, DescriptorChain, Queue},
+};
+use std::{
+    io::{self, Read, Write},
+    mem,
+    sync::Arc,
+};
+
+pub const DEVICE_TYPE: u32 = 2;
+/// Requests always address the disk in 512 byte sectors, regardless of the
+/// logical block size
+pub const SECTOR_SIZE: u64 = 512;
+pub const DEFAULT_BLOCK_SIZE: u32 = 512;
+
+const QUEUE_SIZE: u16 = 256;
+/// Length of the ID returned by `GET_ID`, which isn't NUL terminated if it
+/// takes up the whole buffer
+const ID_LEN: usize = 20;
+/// Largest number of ranges in a single discard or write zeroes request
+const MAX_DISCARD_SEG: u32 = 32;
+/// Largest chunk of data buffered at once while copying between the image
+/// and guest memory
+const MAX_CHUNK: usize = 1 << 20;
+
+/// Feature bits specific to block devices
+#[allow(non_snake_case)]
+pub mod BlkFeatures {
+    /// `seg_max` holds the largest number of data buffers in a request
+    pub const SEG_MAX: u64 = 1 << 2;
+    /// The disk is read-only
+    pub const RO: u64 = 1 << 5;
+    /// `blk_size` holds the logical block size
+    pub const BLK_SIZE: u64 = 1 << 6;
+    /// Writes are cached and need to be flushed
+    pub const FLUSH: u64 = 1 << 9;
+    pub const DISCARD: u64 = 1 << 13;
+    pub const WRITE_ZEROES: u64 = 1 << 14;
+}
+
+#[allow(non_snake_case)]
+mod RequestType {
+    pub const IN: u32 = 0;
+    pub const OUT: u32 = 1;
+    pub const FLUSH: u32 = 4;
+    pub const GET_ID: u32 = 8;
+    pub const DISCARD: u32 = 11;
+    pub const WRITE_ZEROES: u32 = 13;
+}
+
+#[allow(non_snake_case)]
+mod RequestStatus {
+    pub const OK: u8 = 0;
+    pub const IOERR: u8 = 1;
+    pub const UNSUPP: u8 = 2;
+}
+
+/// Ranges of a write zeroes request may be deallocated instead
+const WRITE_ZEROES_UNMAP: u32 = 1 << 0;
+
+/// Start of every request, followed by its data and the status byte
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct RequestHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// A range of a discard or write zeroes request
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// SAFETY: both are only made of integers, with no padding between them
+unsafe impl ByteValued for RequestHeader {}
+unsafe impl ByteValued for DiscardSegment {}
+
+/// The configuration space, up to the fields that are offered
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct BlkConfig {
+    /// Size of the disk in 512 byte sectors
+    capacity: u64,
+    size_max: u32,
+    seg_max: u32,
+    cylinders: u16,
+    heads: u8,
+    sectors: u8,
+    blk_size: u32,
+    physical_block_exp: u8,
+    alignment_offset: u8,
+    min_io_size: u16,
+    opt_io_size: u32,
+    writeback: u8,
+    unused0: u8,
+    num_queues: u16,
+    max_discard_sectors: u32,
+    max_discard_seg: u32,
+    discard_sector_alignment: u32,
+    max_write_zeroes_sectors: u32,
+    max_write_zeroes_seg: u32,
+    write_zeroes_may_unmap: u8,
+    unused1: [u8; 3],
+    /// Tail padding up to the alignment of `capacity`, kept zeroed so that
+    /// every byte read by the driver is initialized
+    padding: [u8; 4],
+}
+
+const _: () = assert!(mem::size_of::<BlkConfig>() == 64);
+
+// SAFETY: only made of integers, with the tail padding made explicit
+unsafe impl ByteValued for BlkConfig {}
+
+/// The queue, once the driver activated the device
+struct ActiveQueue {
+    memory: Arc<GuestMemory>,
+    queue: Queue,
+    interrupt: VirtioInterrupt,
+}
+
+pub struct VirtioBlk {
+    disk: Box<dyn DiskImage>,
+    /// Size of the image in bytes, rounded down to the block size
+    size: u64,
+    read_only: bool,
+    block_size: u32,
+    packed_ring: bool,
+    id: [u8; ID_LEN],
+    active: Option<ActiveQueue>,
+}
+
+impl VirtioBlk {
+    /// A writable disk with 512 byte blocks, backed by `disk`
+    pub fn new(disk: Box<dyn DiskImage>) -> Self {
+        let mut blk = Self {
+            disk,
+            size: 0,
+            read_only: false,
+            block_size: DEFAULT_BLOCK_SIZE,
+            packed_ring: false,
+            id: [0; ID_LEN],
+            active: None,
+        };
+
+        blk.update_size();
+        blk
+    }
+
+    fn update_size(&mut self) {
+        let len = self.disk.size();
+        self.size = len - len % u64::from(self.block_size);
+    }
+
+    /// Reject writes from the guest, the file may then be opened read-only
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Logical block size, a power of two between 512 bytes and 64KiB. The
+    /// tail of the image that doesn't fill a whole block is left out
+    pub fn block_size(mut self, block_size: u32) -> io::Result<Self> {
+        if !block_size.is_power_of_two() || !(512..=65536).contains(&block_size) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        self.block_size = block_size;
+        self.update_size();
+
+        Ok(self)
+    }
+
+    /// Offer packed virtqueues to the driver, instead of only split ones
+    pub fn packed_ring(mut self, packed_ring: bool) -> Self {
+        self.packed_ring = packed_ring;
+        self
+    }
+
+    /// Serial number reported to the guest, truncated to 20 bytes
+    pub fn id(mut self, id: &str) -> Self {
+        let len = id.len().min(ID_LEN);
+
+        self.id = [0; ID_LEN];
+        self.id[..len].copy_from_slice(&id.as_bytes()[..len]);
+        self
+    }
+
+    fn config(&self) -> BlkConfig {
+        let sectors = u32::MAX >> 9;
+
+        BlkConfig {
+            capacity: self.size / SECTOR_SIZE,
+            // The header and status take up a descriptor each
+            seg_max: u32::from(QUEUE_SIZE) - 2,
+            blk_size: self.block_size,
+            max_discard_sectors: sectors,
+            max_discard_seg: MAX_DISCARD_SEG,
+            discard_sector_alignment: self.block_size / SECTOR_SIZE as u32,
+            max_write_zeroes_sectors: sectors,
+            max_write_zeroes_seg: MAX_DISCARD_SEG,
+            write_zeroes_may_unmap: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Check that `len` bytes at `sector` are within the disk, returning the
+    /// offset into the image
+    fn check_access(&self, sector: u64, len: u64) -> Option<u64> {
+        let offset = sector.checked_mul(SECTOR_SIZE)?;
+
+        (offset.checked_add(len)? <= self.size).then_some(offset)
+    }
+
+    fn read(&self, sector: u64, writer: &mut ChainWriter) -> io::Result<u8> {
+        // Everything but the status byte is data
+        let len = writer.remaining().saturating_sub(1);
+
+        let Some(mut offset) = self.check_access(sector, len as u64) else {
+            return Ok(RequestStatus::IOERR);
+        };
+
+        let mut buf = vec![0; len.min(MAX_CHUNK)];
+        let mut left = len;
+
+        while left > 0 {
+            let chunk = &mut buf[..left.min(MAX_CHUNK)];
+
+            self.disk.read_at(chunk, offset)?;
+            writer.write_all(chunk)?;
+
+            offset += chunk.len() as u64;
+            left -= chunk.len();
+        }
+
+        Ok(RequestStatus::OK)
+    }
+
+    fn write(&mut self, sector: u64, reader: &mut ChainReader) -> io::Result<u8> {
+        let len = reader.remaining();
+
+        let Some(mut offset) = self.check_access(sector, len as u64) else {
+            return Ok(RequestStatus::IOERR);
+        };
+
+        let mut buf = vec![0; len.min(MAX_CHUNK)];
+        let mut left = len;
+
+        while left > 0 {
+            let chunk = &mut buf[..left.min(MAX_CHUNK)];
+
+            reader.read_exact(chunk)?;
+            self.disk.write_at(chunk, offset)?;
+
+            offset += chunk.len() as u64;
+            left -= chunk.len();
+        }
+
+        Ok(RequestStatus::OK)
+    }
+
+    fn discard(&mut self, request_type: u32, reader: &mut ChainReader) -> io::Result<u8> {
+        let count = reader.remaining() / mem::size_of::<DiscardSegment>();
+
+        if count == 0 || count > MAX_DISCARD_SEG as usize {
+            return Ok(RequestStatus::UNSUPP);
+        }
+
+        for _ in 0..count {
+            let segment: DiscardSegment = reader.read_obj()?;
+            let len = u64::from(segment.num_sectors) * SECTOR_SIZE;
+
+            let Some(offset) = self.check_access(segment.sector, len) else {
+                return Ok(RequestStatus::IOERR);
+            };
+
+            match request_type {
+                RequestType::DISCARD if segment.flags != 0 => return Ok(RequestStatus::UNSUPP),
+                RequestType::WRITE_ZEROES if segment.flags & !WRITE_ZEROES_UNMAP != 0 => {
+                    return Ok(RequestStatus::UNSUPP)
+                }
+                _ => {}
+            }
+
+            // Discarded ranges are always deallocated, zeroed ones only if
+            // the driver allows it
+            let unmap =
+                request_type == RequestType::DISCARD || segment.flags & WRITE_ZEROES_UNMAP != 0;
+            self.disk.zero_range(offset, len, unmap)?;
+        }
+
+        Ok(RequestStatus::OK)
+    }
+
+    /// Handle a single request, returning the number of bytes written to the
+    /// chain
+    fn process(
+        &mut self,
+        memory: &GuestMemory,
+        chain: &DescriptorChain,
+    ) -> Result<u32, VirtioError> {
+        let mut reader = chain.reader(memory);
+        let mut writer = chain.writer(memory);
+
+        // Without room for the header and status the driver can't even be
+        // told that the request is malformed
+        let header: RequestHeader = match reader.read_obj() {
+            Ok(header) if writer.remaining() > 0 => header,
+            _ => return Err(VirtioError::InvalidChain(0)),
+        };
+
+        let result = match header.request_type {
+            RequestType::IN => self.read(header.sector, &mut writer),
+            RequestType::OUT if self.read_only => Ok(RequestStatus::IOERR),
+            RequestType::OUT => self.write(header.sector, &mut reader),
+            RequestType::FLUSH => self.disk.flush().map(|_| RequestStatus::OK),
+            RequestType::GET_ID => {
+                let len = ID_LEN.min(writer.remaining() - 1);
+                writer.write_all(&self.id[..len]).map(|_| RequestStatus::OK)
+            }
+            RequestType::DISCARD | RequestType::WRITE_ZEROES if self.read_only => {
+                Ok(RequestStatus::IOERR)
+            }
+            RequestType::DISCARD | RequestType::WRITE_ZEROES => {
+                self.discard(header.request_type, &mut reader)
+            }
+            _ => Ok(RequestStatus::UNSUPP),
+        };
+
+        let status = result.unwrap_or_else(|err| {
+            eprintln!("virtio-blk: request {} failed: {err}", header.request_type);
+            RequestStatus::IOERR
+        });
+
+        // The status is always the very last byte of the chain
+        let padding = writer.remaining() - 1;
+        io::copy(&mut io::repeat(0).take(padding as u64), &mut writer)?;
+        writer.write_all(&[status])?;
+
+        Ok(writer.written() as u32)
+    }
+
+    /// Handle every request that is available, then notify the driver
+    fn process_queue(&mut self, active: &mut ActiveQueue) -> Result<(), VirtioError> {
+        while let Some(chain) = active.queue.pop()? {
+            let len = self.process(&active.memory, &chain)?;
+            active.queue.add_used(&chain, len)?;
+        }
+
+        if active.queue.needs_notification()? {
+            active.interrupt.signal_used_buffer()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_type(&self) -> u32 {
+        DEVICE_TYPE
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = Features::RING_INDIRECT_DESC
+            | Features::RING_EVENT_IDX
+            | Features::IN_ORDER
+            | BlkFeatures::SEG_MAX
+            | BlkFeatures::BLK_SIZE
+            | BlkFeatures::FLUSH
+            | BlkFeatures::DISCARD
+            | BlkFeatures::WRITE_ZEROES;
+
+        if self.read_only {
+            features |= BlkFeatures::RO;
+        }
+
+        if self.packed_ring {
+            features |= Features::RING_PACKED;
+        }
+
+        features
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.config();
+        let config = as_bytes(&config);
+
+        data.fill(0);
+
+        if let Some(config) = config.get(offset as usize..) {
+            let len = data.len().min(config.len());
+            data[..len].copy_from_slice(&config[..len]);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        memory: Arc<GuestMemory>,
+        features: u64,
+        queues: &[QueueConfig],
+        interrupt: VirtioInterrupt,
+    ) -> Result<(), VirtioError> {
+        let queue = Queue::new(memory.clone(), 0, &queues[0], features)?;
+
+        self.active = Some(ActiveQueue {
+            memory,
+            queue,
+            interrupt,
+        });
+
+        Ok(())
+    }
+
+    fn queue_notify(&mut self, index: u16) -> Result<(), VirtioError> {
+        let Some(mut active) = self.active.take().filter(|_| index == 0) else {
+            return Ok(());
+        };
+
+        let result = self.process_queue(&mut active);
+        self.active = Some(active);
+
+        result
+    }
+
+    fn reset(&mut self) {
+        self.active = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        devices::{virtio_blk::VirtioBlk, Interrupt},
+        disk::RawImage,
+        memory::GuestMemory,
+        virtio::{Features, QueueConfig, VirtioDevice, VirtioInterrupt},
+        virtqueue::DescriptorFlags,
+    };
+    use std::{fs::File, io::Write, os::unix::fs::FileExt, sync::Arc};
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const HEADER: u64 = 0x4000;
+    const DATA: u64 = 0x5000;
+    const STATUS: u64 = 0x6000;
+
+    /// Queue a request of three descriptors, the data being written by the
+    /// device for `IN` and `GET_ID`
+    fn request(memory: &GuestMemory, n: u16, header: (u32, u64), data_len: u32, write: bool) {
+        let data_flags = if write { DescriptorFlags::WRITE } else { 0 };
+        let descriptors = [
+            (HEADER, 16, DescriptorFlags::NEXT, 1u16),
+            (DATA, data_len, DescriptorFlags::NEXT | data_flags, 2),
+            (STATUS, 1, DescriptorFlags::WRITE, 0),
+        ];
+
+        for (index, (addr, len, flags, next)) in descriptors.into_iter().enumerate() {
+            let desc = DESC_TABLE + index as u64 * 16;
+            memory.write_obj(desc, &addr).unwrap();
+            memory.write_obj(desc + 8, &len).unwrap();
+            memory.write_obj(desc + 12, &[flags, next]).unwrap();
+        }
+
+        memory.write_obj(HEADER, &[header.0, 0]).unwrap();
+        memory.write_obj(HEADER + 8, &header.1).unwrap();
+        memory
+            .write_obj(AVAIL_RING + 4 + 2 * u64::from(n), &0u16)
+            .unwrap();
+        memory.write_obj(AVAIL_RING + 2, &(n + 1)).unwrap();
+    }
+
+    #[test]
+    fn requests_are_served_from_the_image() {
+        let path = std::env::temp_dir().join(format!("vmm-blk-{}.img", std::process::id()));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // 8 sectors and a partial one which isn't exposed
+        file.write_all(&[0xaa; 4096 + 100]).unwrap();
+
+        let image = RawImage::new(file.try_clone().unwrap()).unwrap();
+        let mut blk = VirtioBlk::new(Box::new(image)).id("disk0");
+        let mut config = [0; 8];
+        blk.read_config(0, &mut config);
+        assert_eq!(u64::from_le_bytes(config), 8);
+
+        // The padding at the end of the config space reads as zeroes
+        config.fill(0xff);
+        blk.read_config(56, &mut config);
+        assert_eq!(config, [1, 0, 0, 0, 0, 0, 0, 0]);
+
+        let memory = Arc::new(GuestMemory::with_ram(1 << 20).unwrap());
+        let queue = QueueConfig {
+            max_size: 256,
+            size: 16,
+            ready: true,
+            desc_table: DESC_TABLE,
+            driver_area: AVAIL_RING,
+            device_area: USED_RING,
+        };
+        let interrupt = VirtioInterrupt::new(Interrupt::new().unwrap());
+        blk.activate(
+            memory.clone(),
+            Features::VERSION_1,
+            &[queue],
+            interrupt.clone(),
+        )
+        .unwrap();
+
+        // Each request is made available in turn, with its status and the
+        // length the device wrote
+        let mut n = 0;
+        let mut run = |blk: &mut VirtioBlk, header: (u32, u64), data_len: u32, write: bool| {
+            request(&memory, n, header, data_len, write);
+            blk.queue_notify(0).unwrap();
+
+            let used_len = memory.read_obj::<u32>(USED_RING + 8 + 8 * u64::from(n));
+            n += 1;
+
+            (memory.read_obj::<u8>(STATUS).unwrap(), used_len.unwrap())
+        };
+
+        memory.write_slice(DATA, &[0x55; 512]).unwrap();
+        assert_eq!(run(&mut blk, (1, 1), 512, false), (0, 1));
+
+        let mut buf = [0; 1024];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..512], [0xaa; 512]);
+        assert_eq!(buf[512..], [0x55; 512]);
+
+        assert_eq!(run(&mut blk, (0, 7), 512, true), (0, 513));
+        assert_eq!(memory.read_obj::<[u8; 4]>(DATA).unwrap(), [0xaa; 4]);
+
+        // Past the end of the disk, then the ID and an unknown request
+        assert_eq!(run(&mut blk, (0, 8), 512, true), (1, 513));
+        assert_eq!(run(&mut blk, (8, 0), 20, true), (0, 21));
+        assert_eq!(memory.read_obj::<[u8; 6]>(DATA).unwrap(), *b"disk0\0");
+        assert_eq!(run(&mut blk, (42, 0), 512, true).0, 2);
+
+        // Write zeroes over the second sector
+        memory.write_obj(DATA, &[1u64, 1]).unwrap();
+        assert_eq!(run(&mut blk, (13, 0), 16, false).0, 0);
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[512..], [0; 512]);
+
+        let mut blk = blk.read_only(true);
+        assert_eq!(run(&mut blk, (1, 0), 512, false).0, 1);
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..512], [0xaa; 512]);
+
+        // The queue hasn't been set up again after the reset
+        blk.reset();
+        request(&memory, 7, (0, 0), 512, true);
+        blk.queue_notify(0).unwrap();
+        assert_eq!(memory.read_obj::<u16>(USED_RING + 2).unwrap(), 7);
+    }
+}
@@ -0,0 +1,443 @@
+//! virtio-mmio transport, version 2 (non-legacy) of the register layout, ref:
+//! https://docs.oasis-open.org/virtio/virtio/v1.2/cs01/virtio-v1.2-cs01.html#x1-1650002
+
+use crate::{
+    bus::BusDevice,
+    constants::IOAPIC_PINS,
+    memory::GuestMemory,
+    virtio::{DeviceStatus, Features, QueueConfig, VirtioDevice, VirtioInterrupt},
+};
+use std::sync::Arc;
+
+/// Where the first device is placed, in the MMIO gap below 4GiB
+pub const MMIO_BASE: u64 = 0xd000_0000;
+/// Size of the registers and configuration space of each device
+pub const MMIO_SIZE: u64 = 0x1000;
+/// IRQ of the first device, the following devices use the next ones
+pub const IRQ_BASE: u32 = 5;
+/// Each device takes one of the IOAPIC pins from `IRQ_BASE` onwards
+pub const MAX_DEVICES: usize = (IOAPIC_PINS - IRQ_BASE) as usize;
+
+/// "virt" in little endian
+const MAGIC_VALUE: u32 = 0x7472_6976;
+const VERSION: u32 = 2;
+/// The VMM doesn't have a PCI vendor ID
+const VENDOR_ID: u32 = 0;
+
+const REG_MAGIC_VALUE: u64 = 0x000;
+const REG_VERSION: u64 = 0x004;
+const REG_DEVICE_ID: u64 = 0x008;
+const REG_VENDOR_ID: u64 = 0x00c;
+const REG_DEVICE_FEATURES: u64 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u64 = 0x014;
+const REG_DRIVER_FEATURES: u64 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u64 = 0x024;
+const REG_QUEUE_SEL: u64 = 0x030;
+const REG_QUEUE_NUM_MAX: u64 = 0x034;
+const REG_QUEUE_NUM: u64 = 0x038;
+const REG_QUEUE_READY: u64 = 0x044;
+const REG_QUEUE_NOTIFY: u64 = 0x050;
+const REG_INTERRUPT_STATUS: u64 = 0x060;
+const REG_INTERRUPT_ACK: u64 = 0x064;
+const REG_STATUS: u64 = 0x070;
+const REG_QUEUE_DESC_LOW: u64 = 0x080;
+const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+const REG_QUEUE_DRIVER_LOW: u64 = 0x090;
+const REG_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const REG_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const REG_CONFIG_GENERATION: u64 = 0x0fc;
+const REG_CONFIG: u64 = 0x100;
+
+/// Kernel command line parameter describing a device to the guest, which
+/// requires `CONFIG_VIRTIO_MMIO_CMDLINE_DEVICES`
+pub fn cmdline_param(base: u64, irq: u32) -> String {
+    format!("virtio_mmio.device={}K@{base:#x}:{irq}", MMIO_SIZE >> 10)
+}
+
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    memory: Arc<GuestMemory>,
+    interrupt: VirtioInterrupt,
+    status: u8,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queues: Vec<QueueConfig>,
+}
+
+impl VirtioMmio {
+    pub fn new(
+        device: Box<dyn VirtioDevice>,
+        memory: Arc<GuestMemory>,
+        interrupt: VirtioInterrupt,
+    ) -> Self {
+        let queues = Self::initial_queues(device.as_ref());
+
+        Self {
+            device,
+            memory,
+            interrupt,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues,
+        }
+    }
+
+    fn initial_queues(device: &dyn VirtioDevice) -> Vec<QueueConfig> {
+        device
+            .queue_max_sizes()
+            .iter()
+            .map(|&max_size| QueueConfig {
+                max_size,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | Features::VERSION_1
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+        self.interrupt.ack(u32::MAX);
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queues = Self::initial_queues(self.device.as_ref());
+    }
+
+    /// The selected queue, if it exists and may still be set up, which is
+    /// only the case till it's enabled and the driver is done
+    fn queue_mut(&mut self) -> Option<&mut QueueConfig> {
+        let queue = self.queues.get_mut(self.queue_sel as usize)?;
+
+        (!queue.ready && self.status & DeviceStatus::DRIVER_OK == 0).then_some(queue)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+
+        // Bits can only be cleared by a reset
+        if self.status & !status != 0 {
+            return;
+        }
+
+        let added = status & !self.status;
+        let mut status = status;
+
+        // The driver reads the status back to find out whether the device
+        // accepted its features
+        if added & DeviceStatus::FEATURES_OK != 0
+            && (self.driver_features & !self.device_features() != 0
+                || self.driver_features & Features::VERSION_1 == 0)
+        {
+            status &= !DeviceStatus::FEATURES_OK;
+        }
+
+        if added & DeviceStatus::DRIVER_OK != 0 {
+            if status & DeviceStatus::FEATURES_OK == 0 {
+                return;
+            }
+
+            if let Err(err) = self.device.activate(
+                self.memory.clone(),
+                self.driver_features,
+                &self.queues,
+                self.interrupt.clone(),
+            ) {
+                eprintln!("virtio: failed to activate the device: {err}");
+                status |= DeviceStatus::DEVICE_NEEDS_RESET;
+            }
+        }
+
+        self.status = status;
+    }
+
+    fn notify(&mut self, index: u32) {
+        if self.status & DeviceStatus::DRIVER_OK == 0
+            || self.status & DeviceStatus::DEVICE_NEEDS_RESET != 0
+        {
+            return;
+        }
+
+        let Ok(index) = u16::try_from(index) else {
+            return;
+        };
+
+        if let Err(err) = self.device.queue_notify(index) {
+            eprintln!("virtio: failed to process queue {index}: {err}");
+            self.status |= DeviceStatus::DEVICE_NEEDS_RESET;
+
+            let _ = self.interrupt.signal_config_change();
+        }
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        let queue = self.queues.get(self.queue_sel as usize);
+
+        match offset {
+            REG_MAGIC_VALUE => MAGIC_VALUE,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_type(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            // Zero tells the driver that the queue doesn't exist
+            REG_QUEUE_NUM_MAX => queue.map_or(0, |queue| queue.max_size.into()),
+            REG_QUEUE_READY => queue.map_or(0, |queue| queue.ready.into()),
+            REG_INTERRUPT_STATUS => self.interrupt.status(),
+            REG_STATUS => self.status.into(),
+            // The configuration space never changes behind the driver's back
+            REG_CONFIG_GENERATION => 0,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        // Updates either half of a 64-bit value
+        let set_half = |target: &mut u64, high: bool| {
+            let shift = if high { 32 } else { 0 };
+            *target = (*target & !(0xFFFF_FFFF << shift)) | (u64::from(value) << shift);
+        };
+
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            // Features are frozen once the driver accepted them
+            REG_DRIVER_FEATURES
+                if self.status & DeviceStatus::FEATURES_OK == 0 && self.driver_features_sel < 2 =>
+            {
+                set_half(&mut self.driver_features, self.driver_features_sel == 1);
+            }
+            REG_QUEUE_SEL => self.queue_sel = value,
+            REG_QUEUE_NUM => {
+                if let Some(queue) = self.queue_mut() {
+                    queue.size = value as u16;
+                }
+            }
+            REG_QUEUE_READY if self.status & DeviceStatus::DRIVER_OK == 0 => {
+                if let Some(queue) = self.queues.get_mut(self.queue_sel as usize) {
+                    queue.ready = value == 1;
+                }
+            }
+            REG_QUEUE_DESC_LOW | REG_QUEUE_DESC_HIGH => {
+                if let Some(queue) = self.queue_mut() {
+                    set_half(&mut queue.desc_table, offset == REG_QUEUE_DESC_HIGH);
+                }
+            }
+            REG_QUEUE_DRIVER_LOW | REG_QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = self.queue_mut() {
+                    set_half(&mut queue.driver_area, offset == REG_QUEUE_DRIVER_HIGH);
+                }
+            }
+            REG_QUEUE_DEVICE_LOW | REG_QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.queue_mut() {
+                    set_half(&mut queue.device_area, offset == REG_QUEUE_DEVICE_HIGH);
+                }
+            }
+            REG_QUEUE_NOTIFY => self.notify(value),
+            REG_INTERRUPT_ACK => self.interrupt.ack(value),
+            REG_STATUS => self.set_status(value as u8),
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for VirtioMmio {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if offset >= REG_CONFIG {
+            self.device.read_config(offset - REG_CONFIG, data);
+            return;
+        }
+
+        // Registers are only ever accessed 32 bits at a time
+        if let Ok(data) = <&mut [u8; 4]>::try_from(data) {
+            *data = self.read_register(offset).to_le_bytes();
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if offset >= REG_CONFIG {
+            self.device.write_config(offset - REG_CONFIG, data);
+            return;
+        }
+
+        if let Ok(data) = <[u8; 4]>::try_from(data) {
+            self.write_register(offset, u32::from_le_bytes(data));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bus::BusDevice,
+        devices::{
+            virtio_mmio::{
+                VirtioMmio, REG_CONFIG, REG_DEVICE_FEATURES, REG_DEVICE_FEATURES_SEL,
+                REG_DEVICE_ID, REG_DRIVER_FEATURES, REG_DRIVER_FEATURES_SEL, REG_INTERRUPT_ACK,
+                REG_INTERRUPT_STATUS, REG_MAGIC_VALUE, REG_QUEUE_DESC_HIGH, REG_QUEUE_DESC_LOW,
+                REG_QUEUE_DEVICE_LOW, REG_QUEUE_DRIVER_LOW, REG_QUEUE_NOTIFY, REG_QUEUE_NUM,
+                REG_QUEUE_NUM_MAX, REG_QUEUE_READY, REG_QUEUE_SEL, REG_STATUS, REG_VERSION,
+            },
+            Interrupt,
+        },
+        memory::GuestMemory,
+        virtio::{QueueConfig, VirtioDevice, VirtioError, VirtioInterrupt},
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Negotiated features and queues, once activated
+    type Activation = Option<(u64, Vec<QueueConfig>)>;
+
+    /// Records what the transport tells it
+    #[derive(Clone, Default)]
+    struct DummyDevice {
+        activated: Arc<Mutex<Activation>>,
+        notified: Arc<Mutex<Vec<u16>>>,
+    }
+
+    impl VirtioDevice for DummyDevice {
+        fn device_type(&self) -> u32 {
+            42
+        }
+
+        fn features(&self) -> u64 {
+            1 << 5
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[256, 16]
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn activate(
+            &mut self,
+            _memory: Arc<GuestMemory>,
+            features: u64,
+            queues: &[QueueConfig],
+            _interrupt: VirtioInterrupt,
+        ) -> Result<(), VirtioError> {
+            *self.activated.lock().unwrap() = Some((features, queues.to_vec()));
+            Ok(())
+        }
+
+        fn queue_notify(&mut self, index: u16) -> Result<(), VirtioError> {
+            self.notified.lock().unwrap().push(index);
+            Ok(())
+        }
+
+        fn reset(&mut self) {
+            *self.activated.lock().unwrap() = None;
+        }
+    }
+
+    fn read(mmio: &mut VirtioMmio, offset: u64) -> u32 {
+        let mut data = [0; 4];
+        mmio.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write(mmio: &mut VirtioMmio, offset: u64, value: u32) {
+        mmio.write(offset, &value.to_le_bytes());
+    }
+
+    #[test]
+    fn driver_initialization() {
+        let device = DummyDevice::default();
+        let memory = Arc::new(GuestMemory::new());
+        let interrupt = VirtioInterrupt::new(Interrupt::new().unwrap());
+        let mut mmio = VirtioMmio::new(Box::new(device.clone()), memory, interrupt.clone());
+
+        assert_eq!(read(&mut mmio, REG_MAGIC_VALUE), 0x74726976);
+        assert_eq!(read(&mut mmio, REG_VERSION), 2);
+        assert_eq!(read(&mut mmio, REG_DEVICE_ID), 42);
+
+        write(&mut mmio, REG_STATUS, 0x3);
+
+        // VERSION_1 is always offered
+        write(&mut mmio, REG_DEVICE_FEATURES_SEL, 1);
+        assert_eq!(read(&mut mmio, REG_DEVICE_FEATURES), 1);
+        write(&mut mmio, REG_DEVICE_FEATURES_SEL, 0);
+        assert_eq!(read(&mut mmio, REG_DEVICE_FEATURES), 1 << 5);
+
+        // Features that weren't offered are refused
+        write(&mut mmio, REG_DRIVER_FEATURES_SEL, 0);
+        write(&mut mmio, REG_DRIVER_FEATURES, 1 << 6);
+        write(&mut mmio, REG_DRIVER_FEATURES_SEL, 1);
+        write(&mut mmio, REG_DRIVER_FEATURES, 1);
+        write(&mut mmio, REG_STATUS, 0xb);
+        assert_eq!(read(&mut mmio, REG_STATUS), 0x3);
+
+        write(&mut mmio, REG_DRIVER_FEATURES_SEL, 0);
+        write(&mut mmio, REG_DRIVER_FEATURES, 1 << 5);
+        write(&mut mmio, REG_STATUS, 0xb);
+        assert_eq!(read(&mut mmio, REG_STATUS), 0xb);
+
+        // Only the second queue is set up
+        write(&mut mmio, REG_QUEUE_SEL, 2);
+        assert_eq!(read(&mut mmio, REG_QUEUE_NUM_MAX), 0);
+        write(&mut mmio, REG_QUEUE_SEL, 1);
+        assert_eq!(read(&mut mmio, REG_QUEUE_NUM_MAX), 16);
+        write(&mut mmio, REG_QUEUE_NUM, 8);
+        write(&mut mmio, REG_QUEUE_DESC_LOW, 0x1000);
+        write(&mut mmio, REG_QUEUE_DESC_HIGH, 0x1);
+        write(&mut mmio, REG_QUEUE_DRIVER_LOW, 0x2000);
+        write(&mut mmio, REG_QUEUE_DEVICE_LOW, 0x3000);
+        write(&mut mmio, REG_QUEUE_READY, 1);
+
+        // Notifications are ignored till the driver is ready
+        write(&mut mmio, REG_QUEUE_NOTIFY, 1);
+        write(&mut mmio, REG_STATUS, 0xf);
+
+        let (features, queues) = device.activated.lock().unwrap().clone().unwrap();
+        assert_eq!(features, (1 << 32) | (1 << 5));
+        assert!(!queues[0].ready);
+        assert_eq!(
+            queues[1],
+            QueueConfig {
+                max_size: 16,
+                size: 8,
+                ready: true,
+                desc_table: 0x1_0000_1000,
+                driver_area: 0x2000,
+                device_area: 0x3000,
+            }
+        );
+
+        write(&mut mmio, REG_QUEUE_NOTIFY, 1);
+        assert_eq!(*device.notified.lock().unwrap(), [1]);
+
+        interrupt.signal_used_buffer().unwrap();
+        interrupt.signal_config_change().unwrap();
+        assert_eq!(read(&mut mmio, REG_INTERRUPT_STATUS), 0x3);
+        write(&mut mmio, REG_INTERRUPT_ACK, 0x1);
+        assert_eq!(read(&mut mmio, REG_INTERRUPT_STATUS), 0x2);
+
+        let mut config = [0; 2];
+        mmio.read(REG_CONFIG + 4, &mut config);
+        assert_eq!(config, [4, 4]);
+
+        write(&mut mmio, REG_STATUS, 0);
+        assert_eq!(read(&mut mmio, REG_STATUS), 0);
+        assert_eq!(read(&mut mmio, REG_INTERRUPT_STATUS), 0);
+        assert!(device.activated.lock().unwrap().is_none());
+    }
+}
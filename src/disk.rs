@@ -0,0 +1,112 @@
+//! Disk image formats that back block devices
+
+use crate::qcow2::{self, Qcow2Image};
+use nix::{
+    errno::Errno,
+    fcntl::{self, FallocateFlags},
+};
+use std::{
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    path::Path,
+};
+
+/// Largest chunk of zeroes written at once when the file system can't
+/// deallocate or zero ranges by itself
+const MAX_ZERO_CHUNK: usize = 1 << 20;
+
+/// Contents of a disk, addressed in bytes
+pub trait DiskImage: Send {
+    /// Size of the disk as seen by the guest
+    fn size(&self) -> u64;
+
+    /// Fill `buf` from `offset`, which must be within the disk
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Make the writes so far durable
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Make `len` bytes at `offset` read back as zeroes, deallocating them
+    /// from the image if `unmap` is set and the format allows it
+    fn zero_range(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()>;
+}
+
+/// Open the image at `path`, detecting its format from its contents
+pub fn open(path: &Path, read_only: bool) -> io::Result<Box<dyn DiskImage>> {
+    let file = File::options().read(true).write(!read_only).open(path)?;
+
+    if qcow2::probe(&file)? {
+        Ok(Box::new(Qcow2Image::open(file, path, read_only)?))
+    } else {
+        Ok(Box::new(RawImage::new(file)?))
+    }
+}
+
+/// Write zeroes to `len` bytes of `file` at `offset`
+pub(crate) fn write_zeroes(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let zeroes = vec![0; (len as usize).min(MAX_ZERO_CHUNK)];
+    let mut done = 0;
+
+    while done < len {
+        let chunk = &zeroes[..((len - done) as usize).min(MAX_ZERO_CHUNK)];
+        file.write_all_at(chunk, offset + done)?;
+        done += chunk.len() as u64;
+    }
+
+    Ok(())
+}
+
+/// A disk stored as is in a file
+pub struct RawImage {
+    file: File,
+    size: u64,
+}
+
+impl RawImage {
+    pub fn new(file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+
+        Ok(Self { file, size })
+    }
+}
+
+impl DiskImage for RawImage {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn zero_range(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        let mode = if unmap {
+            FallocateFlags::FALLOC_FL_PUNCH_HOLE
+        } else {
+            FallocateFlags::FALLOC_FL_ZERO_RANGE
+        };
+
+        match fcntl::fallocate(
+            self.file.as_raw_fd(),
+            mode | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            offset as i64,
+            len as i64,
+        ) {
+            Ok(()) => Ok(()),
+            // Not every file system can do either
+            Err(Errno::EOPNOTSUPP) => write_zeroes(&self.file, offset, len),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{bootparam::boot_e820_entry, memory::GuestMemory};
+
+/// Usable RAM
+pub const E820_RAM: u32 = 1;
+/// Reserved, must not be used by the guest
+pub const E820_RESERVED: u32 = 2;
+/// ACPI tables, can be reclaimed after they're parsed
+pub const E820_ACPI: u32 = 3;
+
+/// Start of the Extended BIOS Data Area
+pub const EBDA_START: u64 = 0x9fc00;
+/// Start of the legacy VGA/BIOS hole, which spans till 1MiB
+pub const LEGACY_HOLE_START: u64 = 0xa0000;
+/// Memory above 1MiB, where the kernel image is loaded
+pub const HIGH_MEMORY_START: u64 = 0x100000;
+
+/// Builds the memory map reported to the guest through E820
+#[derive(Clone, Debug, Default)]
+pub struct E820Table {
+    entries: Vec<boot_e820_entry>,
+}
+
+impl E820Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Derive the memory map from the regions of guest memory
+    /// The EBDA is reserved, the legacy hole below 1MiB and any gaps between
+    /// the regions (such as the 32-bit MMIO gap) are left out entirely
+    pub fn from_memory(memory: &GuestMemory) -> Self {
+        let mut table = Self::new();
+        let mut regions = memory.regions().collect::<Vec<_>>();
+
+        regions.sort_by_key(|region| region.guest_base());
+
+        for region in regions {
+            let (start, end) = (region.guest_base(), region.guest_end());
+
+            if start >= HIGH_MEMORY_START {
+                table.add(start, end - start, E820_RAM);
+                continue;
+            }
+
+            table.add(start, end.min(EBDA_START) - start, E820_RAM);
+
+            if end > EBDA_START {
+                table.add(
+                    EBDA_START,
+                    end.min(LEGACY_HOLE_START) - EBDA_START,
+                    E820_RESERVED,
+                );
+            }
+
+            if end > HIGH_MEMORY_START {
+                table.add(HIGH_MEMORY_START, end - HIGH_MEMORY_START, E820_RAM);
+            }
+        }
+
+        table
+    }
+
+    /// Add an entry, merging it with the previous one if they're contiguous
+    /// and of the same type
+    pub fn add(&mut self, addr: u64, size: u64, type_: u32) {
+        if size == 0 {
+            return;
+        }
+
+        if let Some(last) = self.entries.last_mut() {
+            let (last_addr, last_size, last_type) = (last.addr, last.size, last.type_);
+
+            if last_type == type_ && last_addr + last_size == addr {
+                last.size = last_size + size;
+                return;
+            }
+        }
+
+        self.entries.push(boot_e820_entry { addr, size, type_ });
+    }
+
+    pub fn entries(&self) -> &[boot_e820_entry] {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        e820::{E820Table, E820_RAM, E820_RESERVED},
+        memory::{GuestMemory, MMIO_GAP_END, MMIO_GAP_START},
+    };
+
+    #[test]
+    fn table_covers_high_memory() {
+        let memory = GuestMemory::with_ram(MMIO_GAP_START + (1 << 30)).unwrap();
+        let table = E820Table::from_memory(&memory);
+
+        let entries = table
+            .entries()
+            .iter()
+            .map(|entry| (entry.addr, entry.size, entry.type_))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            entries,
+            [
+                (0, 0x9fc00, E820_RAM),
+                (0x9fc00, 0x400, E820_RESERVED),
+                (0x100000, MMIO_GAP_START - 0x100000, E820_RAM),
+                (MMIO_GAP_END, 1 << 30, E820_RAM),
+            ]
+        );
+    }
+
+    #[test]
+    fn contiguous_entries_are_merged() {
+        let mut table = E820Table::new();
+
+        table.add(0x1000, 0x1000, E820_RAM);
+        table.add(0x2000, 0x1000, E820_RAM);
+        table.add(0x3000, 0x1000, E820_RESERVED);
+        table.add(0x4000, 0, E820_RAM);
+
+        assert_eq!(table.entries().len(), 2);
+        assert_eq!({ table.entries()[0].size }, 0x2000);
+    }
+}
@@ -0,0 +1,1173 @@
+//! GDB Remote Serial Protocol server, to debug the guest kernel with
+//! `target remote`, ref: https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//!
+//! The VM is debugged in all-stop mode, every vCPU shows up as a thread.
+//! Whenever a vCPU stops, the others are kicked out of `KVM_RUN` and all of
+//! them park on their `VcpuDebugger`, running the commands sent by the
+//! server thread till GDB resumes them.
+
+use crate::{
+    kvm::{GuestDebug, Vcpu, VcpuHandle},
+    memory::GuestMemory,
+    paging,
+    power::{PowerControl, VmExitStatus},
+};
+use kvm_bindings::{kvm_debug_exit_arch, kvm_regs, kvm_sregs};
+use nix::sys::pthread::Pthread;
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    io::{self, Read, Write},
+    mem,
+    net::{Shutdown, TcpStream},
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Sent by GDB to interrupt the running guest, outside of any packet
+const INTERRUPT: u8 = 0x03;
+const INT3: u8 = 0xCC;
+
+/// Largest packet we accept, also bounds memory reads
+const PACKET_SIZE: usize = 0x4000;
+
+/// Only tells GDB which architecture to use, the registers are the default
+/// ones of amd64
+const TARGET_XML: &str =
+    "<?xml version=\"1.0\"?><target><architecture>i386:x86-64</architecture></target>";
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// `Enn` replies, with errno values
+const EFAULT: &str = "E0e";
+const ENOSPC: &str = "E1c";
+const EINVAL: &str = "E16";
+
+/// #BP, raised by `int3`
+const BP_VECTOR: u32 = 3;
+/// DR0 to DR3
+const HW_BREAKPOINTS: usize = 4;
+
+/// A connected debugger
+pub enum GdbStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl GdbStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Self::Tcp(stream) => Self::Tcp(stream.try_clone()?),
+            Self::Unix(stream) => Self::Unix(stream.try_clone()?),
+        })
+    }
+
+    /// Disconnect GDB, which also unblocks any pending read
+    fn shutdown(&self) {
+        let _ = match self {
+            Self::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Self::Unix(stream) => stream.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for GdbStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for GdbStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+type Registers = (kvm_regs, kvm_sregs);
+
+/// Why a vCPU parked
+#[derive(Clone, Copy, Debug)]
+enum StopReason {
+    /// Kicked by the server, or about to run for the first time
+    Paused,
+    /// Breakpoint, watchpoint or single step
+    Debug(kvm_debug_exit_arch),
+}
+
+enum Event {
+    /// Received from GDB
+    Data(Vec<u8>),
+    /// GDB went away
+    Disconnected,
+    /// A vCPU parked and waits for commands
+    Stopped { vcpu: usize, reason: StopReason },
+    /// The VM stopped running
+    Exited(VmExitStatus),
+}
+
+enum VcpuCommand {
+    /// Replies with the registers
+    GetRegs,
+    /// Replies with the registers, once written
+    SetRegs(kvm_regs),
+    /// Run the guest again, with the given debug events exiting
+    Resume(GuestDebug),
+    /// Leave the vCPU alone, the VM is stopping
+    Release,
+}
+
+/// The vCPU thread's end of the debugger, see the module docs
+pub struct VcpuDebugger {
+    id: usize,
+    pause: Arc<AtomicBool>,
+    events: Sender<Event>,
+    commands: Receiver<VcpuCommand>,
+    replies: Sender<io::Result<Registers>>,
+}
+
+impl VcpuDebugger {
+    /// Wait for GDB to resume the vCPU, before it runs for the first time
+    pub fn wait_for_debugger(&self, vcpu: &mut Vcpu) -> io::Result<()> {
+        self.park(vcpu, StopReason::Paused)
+    }
+
+    /// Park the vCPU if `KVM_RUN` was interrupted because GDB is stopping
+    /// the VM
+    pub fn interrupted(&self, vcpu: &mut Vcpu) -> io::Result<()> {
+        if self.pause.load(Ordering::SeqCst) {
+            self.park(vcpu, StopReason::Paused)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Park the vCPU after it hit a breakpoint or watchpoint, or stepped
+    pub fn debug_exit(&self, vcpu: &mut Vcpu, debug: kvm_debug_exit_arch) -> io::Result<()> {
+        self.park(vcpu, StopReason::Debug(debug))
+    }
+
+    fn park(&self, vcpu: &mut Vcpu, reason: StopReason) -> io::Result<()> {
+        let stopped = Event::Stopped {
+            vcpu: self.id,
+            reason,
+        };
+
+        // Nobody is listening anymore once GDB detached
+        if self.events.send(stopped).is_err() {
+            return Ok(());
+        }
+
+        loop {
+            let registers = match self.commands.recv() {
+                Ok(VcpuCommand::GetRegs) => registers(vcpu),
+                Ok(VcpuCommand::SetRegs(regs)) => {
+                    vcpu.set_vcpu_regs(&regs).and_then(|()| registers(vcpu))
+                }
+                Ok(VcpuCommand::Resume(debug)) => return vcpu.set_guest_debug(&debug),
+                Ok(VcpuCommand::Release) | Err(_) => return Ok(()),
+            };
+
+            let _ = self.replies.send(registers);
+        }
+    }
+}
+
+fn registers(vcpu: &Vcpu) -> io::Result<Registers> {
+    Ok((vcpu.get_vcpu_regs()?, vcpu.get_vcpu_sregs()?))
+}
+
+/// Server's end of a vCPU
+struct VcpuControl {
+    commands: Sender<VcpuCommand>,
+    replies: Receiver<io::Result<Registers>>,
+}
+
+/// A GDB server that hasn't been connected to the vCPU threads yet
+pub struct GdbServer {
+    memory: Arc<GuestMemory>,
+    /// MAXPHYADDR of the vCPUs, for walking their page tables
+    phys_bits: u8,
+    power: PowerControl,
+    pause: Arc<AtomicBool>,
+    events: (Sender<Event>, Receiver<Event>),
+    vcpus: Vec<VcpuControl>,
+}
+
+impl GdbServer {
+    /// Create the server, along with the end of each vCPU which must be
+    /// passed to its thread
+    pub fn new(
+        memory: Arc<GuestMemory>,
+        phys_bits: u8,
+        power: PowerControl,
+        vcpus: usize,
+    ) -> (Self, Vec<VcpuDebugger>) {
+        let pause = Arc::new(AtomicBool::new(false));
+        let (events, events_rx) = mpsc::channel();
+
+        let (controls, debuggers) = (0..vcpus)
+            .map(|id| {
+                let (commands, commands_rx) = mpsc::channel();
+                let (replies, replies_rx) = mpsc::channel();
+
+                (
+                    VcpuControl {
+                        commands,
+                        replies: replies_rx,
+                    },
+                    VcpuDebugger {
+                        id,
+                        pause: pause.clone(),
+                        events: events.clone(),
+                        commands: commands_rx,
+                        replies,
+                    },
+                )
+            })
+            .unzip();
+
+        let server = Self {
+            memory,
+            phys_bits,
+            power,
+            pause,
+            events: (events, events_rx),
+            vcpus: controls,
+        };
+
+        (server, debuggers)
+    }
+
+    /// Serve GDB on a new thread, `vcpus` are used to kick the vCPU threads
+    /// and must be in the same order as the `VcpuDebugger`s
+    pub fn spawn(
+        self,
+        stream: GdbStream,
+        vcpus: Vec<(VcpuHandle, Pthread)>,
+    ) -> io::Result<Debugger> {
+        let (events, events_rx) = self.events;
+        let commands = self
+            .vcpus
+            .iter()
+            .map(|vcpu| vcpu.commands.clone())
+            .collect();
+
+        {
+            let (stream, events) = (stream.try_clone()?, events.clone());
+
+            thread::Builder::new()
+                .name("gdb-input".to_string())
+                .spawn(move || forward_input(stream, events))?;
+        }
+
+        let session = Session {
+            stream: stream.try_clone()?,
+            memory: self.memory,
+            phys_bits: self.phys_bits,
+            power: self.power,
+            pause: self.pause,
+            events: events_rx,
+            parked: vec![false; self.vcpus.len()],
+            inject_bp: vec![false; self.vcpus.len()],
+            vcpus: self.vcpus,
+            kicks: vcpus,
+            input: Vec::new(),
+            current: 0,
+            resume_thread: None,
+            last_stop: String::new(),
+            sw_breakpoints: BTreeMap::new(),
+            hw_breakpoints: [None; HW_BREAKPOINTS],
+        };
+
+        let thread = thread::Builder::new()
+            .name("gdb".to_string())
+            .spawn(move || session.run())?;
+
+        Ok(Debugger {
+            events,
+            commands,
+            stream,
+            thread,
+        })
+    }
+}
+
+/// Handle to the running GDB server
+pub struct Debugger {
+    events: Sender<Event>,
+    commands: Vec<Sender<VcpuCommand>>,
+    stream: GdbStream,
+    thread: JoinHandle<()>,
+}
+
+impl Debugger {
+    /// Tell GDB that the VM exited and release the parked vCPUs, which must
+    /// have been kicked beforehand so that they don't run the guest any further
+    pub fn stop(self, status: VmExitStatus) {
+        let _ = self.events.send(Event::Exited(status));
+
+        for commands in &self.commands {
+            let _ = commands.send(VcpuCommand::Release);
+        }
+
+        let _ = self.thread.join();
+        self.stream.shutdown();
+    }
+}
+
+fn forward_input(mut stream: GdbStream, events: Sender<Event>) {
+    let mut buf = [0; 4096];
+
+    loop {
+        let event = match stream.read(&mut buf) {
+            Ok(0) => Event::Disconnected,
+            Ok(len) => Event::Data(buf[..len].to_vec()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => Event::Disconnected,
+        };
+
+        let disconnected = matches!(event, Event::Disconnected);
+
+        if events.send(event).is_err() || disconnected {
+            return;
+        }
+    }
+}
+
+/// Why the session ended
+enum End {
+    Detached,
+    Killed,
+    Exited(VmExitStatus),
+    Io(io::Error),
+}
+
+impl From<io::Error> for End {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HwBreakpointKind {
+    Exec,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct HwBreakpoint {
+    kind: HwBreakpointKind,
+    addr: u64,
+    len: u64,
+}
+
+struct Session {
+    stream: GdbStream,
+    memory: Arc<GuestMemory>,
+    phys_bits: u8,
+    power: PowerControl,
+    pause: Arc<AtomicBool>,
+    events: Receiver<Event>,
+    vcpus: Vec<VcpuControl>,
+    kicks: Vec<(VcpuHandle, Pthread)>,
+    /// Which vCPUs wait for commands, all of them while the VM is stopped
+    parked: Vec<bool>,
+    /// vCPUs that stopped on an `int3` that isn't ours, which is handed back
+    /// to the guest once they're resumed
+    inject_bp: Vec<bool>,
+    /// Received but not handled yet
+    input: Vec<u8>,
+    /// vCPU selected by `Hg`, used for registers and memory
+    current: usize,
+    /// vCPU selected by `Hc`, used for single-stepping
+    resume_thread: Option<usize>,
+    /// Reply to `?`
+    last_stop: String,
+    /// Original byte of each `int3`, by virtual and physical address
+    sw_breakpoints: BTreeMap<u64, (u64, u8)>,
+    hw_breakpoints: [Option<HwBreakpoint>; HW_BREAKPOINTS],
+}
+
+impl Session {
+    fn run(mut self) {
+        let Err(end) = self.serve();
+
+        let result = match end {
+            End::Detached => self.detach(),
+            End::Killed => {
+                self.power.request(VmExitStatus::Killed);
+                Ok(())
+            }
+            End::Exited(status) => self
+                .send_packet(&format!("W{:02x}", status.exit_code()))
+                .map_err(End::Io),
+            End::Io(err) => Err(End::Io(err)),
+        };
+
+        if let Err(End::Io(err)) = result {
+            eprintln!("vmm: GDB connection failed: {err}");
+        }
+    }
+
+    fn serve(&mut self) -> Result<Infallible, End> {
+        // All vCPUs park before running for the first time
+        self.wait_for_stop()?;
+        self.last_stop = format!("T{SIGTRAP:02x}thread:1;");
+
+        loop {
+            match self.events.recv() {
+                Ok(Event::Data(data)) => self.input.extend(data),
+                Ok(Event::Stopped { vcpu, reason }) => self.vcpu_stopped(vcpu, reason)?,
+                Ok(Event::Disconnected) => return Err(End::Detached),
+                Ok(Event::Exited(status)) => return Err(End::Exited(status)),
+                Err(_) => return Err(End::Exited(self.power.wait())),
+            }
+
+            self.handle_input()?;
+        }
+    }
+
+    fn running(&self) -> bool {
+        !self.parked.iter().all(|&parked| parked)
+    }
+
+    /// Kick the running vCPUs and wait for all of them to park, returning
+    /// the stop reply of the first one that stopped by itself, if any
+    fn stop(&mut self) -> Result<Option<String>, End> {
+        self.pause.store(true, Ordering::SeqCst);
+
+        for (vcpu, (handle, thread)) in self.kicks.iter().enumerate() {
+            if !self.parked[vcpu] {
+                handle.kick(*thread)?;
+            }
+        }
+
+        self.wait_for_stop()
+    }
+
+    fn wait_for_stop(&mut self) -> Result<Option<String>, End> {
+        let mut reply = None;
+
+        while self.running() {
+            match self.events.recv() {
+                // Handled once every vCPU is parked
+                Ok(Event::Data(data)) => self.input.extend(data),
+                Ok(Event::Stopped { vcpu, reason }) => {
+                    self.parked[vcpu] = true;
+
+                    if let StopReason::Debug(debug) = reason {
+                        match self.stop_reply(vcpu, &debug) {
+                            Some(stop) => {
+                                reply.get_or_insert((vcpu, stop));
+                            }
+                            None => self.inject_bp[vcpu] = true,
+                        }
+                    }
+                }
+                Ok(Event::Disconnected) => return Err(End::Detached),
+                Ok(Event::Exited(status)) => return Err(End::Exited(status)),
+                Err(_) => return Err(End::Exited(self.power.wait())),
+            }
+        }
+
+        Ok(reply.map(|(vcpu, stop)| {
+            self.current = vcpu;
+            stop
+        }))
+    }
+
+    /// A vCPU parked by itself while the VM was running
+    fn vcpu_stopped(&mut self, vcpu: usize, reason: StopReason) -> Result<(), End> {
+        self.parked[vcpu] = true;
+
+        let stop = match reason {
+            StopReason::Debug(debug) => self.stop_reply(vcpu, &debug),
+            StopReason::Paused => None,
+        };
+
+        let Some(stop) = stop else {
+            // Not for us, hand the `int3` back to the guest
+            if let StopReason::Debug(_) = reason {
+                self.inject_bp[vcpu] = true;
+            }
+
+            return self.resume_vcpu(vcpu, false);
+        };
+
+        // Another vCPU may have stopped meanwhile, it doesn't matter which
+        // one is reported
+        self.stop()?;
+        self.current = vcpu;
+        self.report_stop(stop)
+    }
+
+    fn report_stop(&mut self, stop: String) -> Result<(), End> {
+        self.send_packet(&stop)?;
+        self.last_stop = stop;
+
+        Ok(())
+    }
+
+    /// Describe why a vCPU stopped, `None` if it ran into an `int3` of the
+    /// guest's own
+    fn stop_reply(&self, vcpu: usize, debug: &kvm_debug_exit_arch) -> Option<String> {
+        let thread = format!("thread:{:x};", vcpu + 1);
+
+        if debug.exception == BP_VECTOR {
+            return self
+                .sw_breakpoints
+                .contains_key(&debug.pc)
+                .then(|| format!("T{SIGTRAP:02x}{thread}swbreak:;"));
+        }
+
+        // Debug exceptions tell which debug register matched through DR6
+        let hit = (0..HW_BREAKPOINTS)
+            .filter(|&n| debug.dr6 & (1 << n) != 0)
+            .find_map(|n| self.hw_breakpoints[n]);
+
+        Some(match hit {
+            Some(HwBreakpoint {
+                kind: HwBreakpointKind::Exec,
+                ..
+            }) => format!("T{SIGTRAP:02x}{thread}hwbreak:;"),
+            Some(HwBreakpoint {
+                kind: HwBreakpointKind::Write,
+                addr,
+                ..
+            }) => format!("T{SIGTRAP:02x}{thread}watch:{addr:x};"),
+            Some(HwBreakpoint {
+                kind: HwBreakpointKind::Access,
+                addr,
+                ..
+            }) => format!("T{SIGTRAP:02x}{thread}awatch:{addr:x};"),
+            None => format!("T{SIGTRAP:02x}{thread}"),
+        })
+    }
+
+    fn guest_debug(&mut self, vcpu: usize, single_step: bool) -> GuestDebug {
+        let mut debug = GuestDebug {
+            single_step,
+            sw_breakpoints: !self.sw_breakpoints.is_empty(),
+            inject_bp: mem::take(&mut self.inject_bp[vcpu]),
+            ..Default::default()
+        };
+
+        for (n, breakpoint) in self.hw_breakpoints.iter().enumerate() {
+            let Some(breakpoint) = breakpoint else {
+                continue;
+            };
+
+            let rw = match breakpoint.kind {
+                HwBreakpointKind::Exec => 0b00,
+                HwBreakpointKind::Write => 0b01,
+                HwBreakpointKind::Access => 0b11,
+            };
+            let len = match breakpoint.len {
+                2 => 0b01,
+                8 => 0b10,
+                4 => 0b11,
+                _ => 0b00,
+            };
+
+            debug.addrs[n] = breakpoint.addr;
+            // Global enable bit, followed by the RW and LEN fields
+            debug.dr7 |= (1 << (2 * n + 1)) | (rw << (16 + 4 * n)) | (len << (18 + 4 * n));
+        }
+
+        debug
+    }
+
+    fn resume_vcpu(&mut self, vcpu: usize, single_step: bool) -> Result<(), End> {
+        let debug = self.guest_debug(vcpu, single_step);
+
+        // The vCPU is gone if the VM is stopping, which is reported through
+        // `Event::Exited`
+        let _ = self.vcpus[vcpu].commands.send(VcpuCommand::Resume(debug));
+        self.parked[vcpu] = false;
+
+        Ok(())
+    }
+
+    /// Resume every vCPU, or only step one of them
+    fn resume(&mut self, step: Option<usize>) -> Result<(), End> {
+        self.pause.store(false, Ordering::SeqCst);
+
+        for vcpu in 0..self.vcpus.len() {
+            match step {
+                Some(step) if step != vcpu => {}
+                _ => self.resume_vcpu(vcpu, step.is_some())?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<(), End> {
+        if self.running() {
+            self.stop()?;
+        }
+
+        for (_, (paddr, original)) in mem::take(&mut self.sw_breakpoints) {
+            let _ = self.memory.write_obj(paddr, &original);
+        }
+
+        self.hw_breakpoints = [None; HW_BREAKPOINTS];
+
+        self.resume(None)
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${data}#{:02x}", checksum(data.as_bytes()));
+
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    /// Handle everything received so far, GDB only sends packets while the
+    /// VM is stopped besides interrupts
+    fn handle_input(&mut self) -> Result<(), End> {
+        while let Some((packet, len)) = parse_packet(&self.input) {
+            self.input.drain(..len);
+
+            match packet {
+                Packet::Interrupt if self.running() => {
+                    let stop = self
+                        .stop()?
+                        .unwrap_or_else(|| format!("T{SIGINT:02x}thread:{:x};", self.current + 1));
+
+                    self.report_stop(stop)?;
+                }
+                Packet::Interrupt => {}
+                Packet::Corrupted => self.stream.write_all(b"-")?,
+                Packet::Command(data) => {
+                    self.stream.write_all(b"+")?;
+
+                    if self.running() {
+                        continue;
+                    }
+
+                    // Unsupported, like any command we don't know about
+                    let Some((kind, args)) = split_command(&data) else {
+                        self.send_packet("")?;
+                        continue;
+                    };
+
+                    if let Some(reply) = self.handle_command(kind, args)? {
+                        self.send_packet(&reply)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run a single command, returning its reply unless the VM was resumed,
+    /// in which case the reply is sent once it stops
+    fn handle_command(&mut self, kind: char, args: &str) -> Result<Option<String>, End> {
+        let reply = match kind {
+            '?' => self.last_stop.clone(),
+            'g' => {
+                let (regs, sregs) = self.registers(self.current)?;
+                hex(&encode_registers(&regs, &sregs))
+            }
+            'G' => match from_hex(args).and_then(|bytes| decode_registers(&bytes)) {
+                Some(regs) => {
+                    self.request(self.current, VcpuCommand::SetRegs(regs))?;
+                    "OK".to_string()
+                }
+                None => EINVAL.to_string(),
+            },
+            'm' => self.read_memory(args)?,
+            'M' => self.write_memory(args)?,
+            // Signals are never delivered to the guest, and resuming at
+            // another address isn't supported
+            'c' | 'C' => {
+                self.resume(None)?;
+                return Ok(None);
+            }
+            's' | 'S' => {
+                self.resume(Some(self.resume_thread.unwrap_or(self.current)))?;
+                return Ok(None);
+            }
+            'H' => self.select_thread(args),
+            'T' => match self.thread_id(args) {
+                Some(Some(_)) => "OK".to_string(),
+                _ => EINVAL.to_string(),
+            },
+            'Z' => self.set_breakpoint(args, true)?,
+            'z' => self.set_breakpoint(args, false)?,
+            'q' => self.query(args),
+            'v' => match args {
+                "Cont?" => "vCont;c;C;s;S".to_string(),
+                _ if args.starts_with("Cont;") => {
+                    return self.resume_actions(&args["Cont;".len()..]);
+                }
+                _ => String::new(),
+            },
+            'D' => {
+                self.send_packet("OK")?;
+                return Err(End::Detached);
+            }
+            'k' => return Err(End::Killed),
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+
+    fn query(&self, query: &str) -> String {
+        let (name, args) = query.split_once([':', ',']).unwrap_or((query, ""));
+
+        match name {
+            "Supported" => format!(
+                "PacketSize={PACKET_SIZE:x};qXfer:features:read+;swbreak+;hwbreak+;vContSupported+"
+            ),
+            "Attached" => "1".to_string(),
+            "C" => format!("QC{:x}", self.current + 1),
+            "fThreadInfo" => {
+                let threads = (1..=self.vcpus.len())
+                    .map(|thread| format!("{thread:x}"))
+                    .collect::<Vec<_>>();
+
+                format!("m{}", threads.join(","))
+            }
+            "sThreadInfo" => "l".to_string(),
+            "ThreadExtraInfo" => match self.thread_id(args) {
+                Some(Some(vcpu)) => hex(format!("vCPU {vcpu}").as_bytes()),
+                _ => EINVAL.to_string(),
+            },
+            "Xfer" => match args.strip_prefix("features:read:target.xml:") {
+                Some(range) => match parse_pair(range, ',') {
+                    Some((offset, len)) => {
+                        let xml = TARGET_XML.as_bytes();
+                        let start = (offset as usize).min(xml.len());
+                        let end = start.saturating_add(len as usize).min(xml.len());
+                        let more = if end < xml.len() { 'm' } else { 'l' };
+
+                        format!("{more}{}", String::from_utf8_lossy(&xml[start..end]))
+                    }
+                    None => EINVAL.to_string(),
+                },
+                None => String::new(),
+            },
+            _ => String::new(),
+        }
+    }
+
+    /// Parse a thread ID, `Some(None)` stands for any or all threads
+    fn thread_id(&self, id: &str) -> Option<Option<usize>> {
+        match id {
+            "0" | "-1" => Some(None),
+            id => match usize::from_str_radix(id, 16) {
+                Ok(thread) if (1..=self.vcpus.len()).contains(&thread) => Some(Some(thread - 1)),
+                _ => None,
+            },
+        }
+    }
+
+    fn select_thread(&mut self, args: &str) -> String {
+        let (operation, id) = args.split_at(args.len().min(1));
+
+        match (operation, self.thread_id(id)) {
+            ("g", Some(vcpu)) => self.current = vcpu.unwrap_or(self.current),
+            ("c", Some(vcpu)) => self.resume_thread = vcpu,
+            _ => return EINVAL.to_string(),
+        }
+
+        "OK".to_string()
+    }
+
+    /// `vCont`, stepping a thread leaves the others stopped
+    fn resume_actions(&mut self, actions: &str) -> Result<Option<String>, End> {
+        let mut step = None;
+
+        for action in actions.split(';') {
+            let (action, thread) = action.split_once(':').unwrap_or((action, "-1"));
+
+            if action.starts_with(['s', 'S']) {
+                match self.thread_id(thread) {
+                    Some(vcpu) => step = Some(vcpu.unwrap_or(self.current)),
+                    None => return Ok(Some(EINVAL.to_string())),
+                }
+            }
+        }
+
+        self.resume(step)?;
+
+        Ok(None)
+    }
+
+    fn request(&mut self, vcpu: usize, command: VcpuCommand) -> Result<Registers, End> {
+        let control = &self.vcpus[vcpu];
+
+        // The vCPU only goes away once the VM is stopping
+        if control.commands.send(command).is_err() {
+            return Err(End::Exited(self.power.wait()));
+        }
+
+        match control.replies.recv() {
+            Ok(registers) => Ok(registers?),
+            Err(_) => Err(End::Exited(self.power.wait())),
+        }
+    }
+
+    fn registers(&mut self, vcpu: usize) -> Result<Registers, End> {
+        self.request(vcpu, VcpuCommand::GetRegs)
+    }
+
+    /// Control registers of the current vCPU, which tell how its virtual
+    /// addresses are translated
+    fn sregs(&mut self) -> Result<kvm_sregs, End> {
+        Ok(self.registers(self.current)?.1)
+    }
+
+    fn read_memory(&mut self, args: &str) -> Result<String, End> {
+        let Some((addr, len)) = parse_pair(args, ',') else {
+            return Ok(EINVAL.to_string());
+        };
+
+        let sregs = self.sregs()?;
+        let mut data = vec![0; (len as usize).min(PACKET_SIZE / 2)];
+
+        Ok(
+            match paging::read_virt(&self.memory, &sregs, self.phys_bits, addr, &mut data) {
+                Ok(()) => hex(&data),
+                Err(_) => EFAULT.to_string(),
+            },
+        )
+    }
+
+    fn write_memory(&mut self, args: &str) -> Result<String, End> {
+        let Some((range, data)) = args.split_once(':') else {
+            return Ok(EINVAL.to_string());
+        };
+
+        let (Some((addr, len)), Some(data)) = (parse_pair(range, ','), from_hex(data)) else {
+            return Ok(EINVAL.to_string());
+        };
+
+        if data.len() as u64 != len {
+            return Ok(EINVAL.to_string());
+        }
+
+        let sregs = self.sregs()?;
+
+        Ok(
+            match paging::write_virt(&self.memory, &sregs, self.phys_bits, addr, &data) {
+                Ok(()) => "OK".to_string(),
+                Err(_) => EFAULT.to_string(),
+            },
+        )
+    }
+
+    /// `Z` and `z`, read watchpoints aren't supported as x86 can only trap
+    /// on both reads and writes
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> Result<String, End> {
+        let mut fields = args.splitn(3, ',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Ok(EINVAL.to_string());
+        };
+
+        let (Ok(addr), Ok(len)) = (u64::from_str_radix(addr, 16), u64::from_str_radix(len, 16))
+        else {
+            return Ok(EINVAL.to_string());
+        };
+
+        let kind = match kind {
+            "0" => return self.set_sw_breakpoint(addr, insert),
+            "1" => HwBreakpointKind::Exec,
+            "2" => HwBreakpointKind::Write,
+            "4" => HwBreakpointKind::Access,
+            _ => return Ok(String::new()),
+        };
+
+        let breakpoint = HwBreakpoint {
+            kind,
+            addr,
+            len: if kind == HwBreakpointKind::Exec {
+                1
+            } else {
+                len
+            },
+        };
+
+        // Watched ranges must be naturally aligned
+        if !matches!(breakpoint.len, 1 | 2 | 4 | 8) || addr % breakpoint.len != 0 {
+            return Ok(EINVAL.to_string());
+        }
+
+        let existing = self
+            .hw_breakpoints
+            .iter()
+            .position(|&slot| slot == Some(breakpoint));
+
+        match (insert, existing) {
+            (true, Some(_)) | (false, None) => {}
+            (true, None) => match self.hw_breakpoints.iter().position(Option::is_none) {
+                Some(slot) => self.hw_breakpoints[slot] = Some(breakpoint),
+                None => return Ok(ENOSPC.to_string()),
+            },
+            (false, Some(slot)) => self.hw_breakpoints[slot] = None,
+        }
+
+        Ok("OK".to_string())
+    }
+
+    fn set_sw_breakpoint(&mut self, addr: u64, insert: bool) -> Result<String, End> {
+        if !insert {
+            if let Some((paddr, original)) = self.sw_breakpoints.remove(&addr) {
+                let _ = self.memory.write_obj(paddr, &original);
+            }
+
+            return Ok("OK".to_string());
+        }
+
+        if self.sw_breakpoints.contains_key(&addr) {
+            return Ok("OK".to_string());
+        }
+
+        let sregs = self.sregs()?;
+        let Ok(paddr) =
+            paging::translate(&self.memory, &sregs, self.phys_bits, addr).map(|t| t.paddr)
+        else {
+            return Ok(EFAULT.to_string());
+        };
+
+        let Ok(original) = self.memory.read_obj::<u8>(paddr) else {
+            return Ok(EFAULT.to_string());
+        };
+
+        if self.memory.write_obj(paddr, &INT3).is_err() {
+            return Ok(EFAULT.to_string());
+        }
+
+        self.sw_breakpoints.insert(addr, (paddr, original));
+
+        Ok("OK".to_string())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Packet {
+    Interrupt,
+    Command(Vec<u8>),
+    /// The checksum didn't match, GDB sends it again once NACKed
+    Corrupted,
+}
+
+/// Parse the first packet out of `input`, along with how many bytes it
+/// spans, acknowledgements and garbage before it are skipped
+fn parse_packet(input: &[u8]) -> Option<(Packet, usize)> {
+    let start = input
+        .iter()
+        .position(|&byte| byte == b'$' || byte == INTERRUPT)?;
+
+    if input[start] == INTERRUPT {
+        return Some((Packet::Interrupt, start + 1));
+    }
+
+    let end = start + input[start..].iter().position(|&byte| byte == b'#')?;
+    let sum = input.get(end + 1..end + 3)?;
+    let data = unescape(&input[start + 1..end]);
+
+    let packet = match from_hex(&String::from_utf8_lossy(sum)) {
+        Some(sum) if sum[0] == checksum(&input[start + 1..end]) => Packet::Command(data),
+        _ => Packet::Corrupted,
+    };
+
+    Some((packet, end + 3))
+}
+
+/// Split a command into the character that names it and its arguments, which
+/// are only ever ASCII for the commands we support
+fn split_command(data: &[u8]) -> Option<(char, &str)> {
+    let command = std::str::from_utf8(data)
+        .ok()
+        .filter(|command| command.is_ascii())?;
+    let kind = command.chars().next()?;
+
+    Some((kind, &command[kind.len_utf8()..]))
+}
+
+/// `}` escapes the following byte, XORed with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => unescaped.extend(bytes.next().map(|byte| byte ^ 0x20)),
+            byte => unescaped.push(byte),
+        }
+    }
+
+    unescaped
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|n| u8::from_str_radix(hex.get(n..n + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse two hex numbers separated by `separator`, such as `addr,length`
+fn parse_pair(args: &str, separator: char) -> Option<(u64, u64)> {
+    let (first, second) = args.split_once(separator)?;
+
+    Some((
+        u64::from_str_radix(first, 16).ok()?,
+        u64::from_str_radix(second, 16).ok()?,
+    ))
+}
+
+/// The general purpose registers as laid out by GDB for amd64, followed by
+/// `rip`, `eflags` and the segment selectors, the rest is left out
+fn encode_registers(regs: &kvm_regs, sregs: &kvm_sregs) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    for reg in [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ] {
+        bytes.extend(reg.to_le_bytes());
+    }
+
+    bytes.extend((regs.rflags as u32).to_le_bytes());
+
+    for segment in [sregs.cs, sregs.ss, sregs.ds, sregs.es, sregs.fs, sregs.gs] {
+        bytes.extend(u32::from(segment.selector).to_le_bytes());
+    }
+
+    bytes
+}
+
+/// Inverse of `encode_registers`, the segment selectors are ignored since
+/// they can't be changed without reloading the descriptors
+fn decode_registers(bytes: &[u8]) -> Option<kvm_regs> {
+    let reg = |n: usize| {
+        Some(u64::from_le_bytes(
+            bytes.get(n * 8..n * 8 + 8)?.try_into().ok()?,
+        ))
+    };
+    let eflags = bytes.get(17 * 8..17 * 8 + 4)?;
+
+    Some(kvm_regs {
+        rax: reg(0)?,
+        rbx: reg(1)?,
+        rcx: reg(2)?,
+        rdx: reg(3)?,
+        rsi: reg(4)?,
+        rdi: reg(5)?,
+        rbp: reg(6)?,
+        rsp: reg(7)?,
+        r8: reg(8)?,
+        r9: reg(9)?,
+        r10: reg(10)?,
+        r11: reg(11)?,
+        r12: reg(12)?,
+        r13: reg(13)?,
+        r14: reg(14)?,
+        r15: reg(15)?,
+        rip: reg(16)?,
+        rflags: u32::from_le_bytes(eflags.try_into().ok()?).into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gdb::{
+        decode_registers, encode_registers, from_hex, hex, parse_packet, split_command, Packet,
+    };
+    use kvm_bindings::{kvm_regs, kvm_sregs};
+
+    #[test]
+    fn packets_are_framed() {
+        // Acks before the packet are skipped
+        assert_eq!(
+            parse_packet(b"+$qC#b4$g"),
+            Some((Packet::Command(b"qC".to_vec()), 7))
+        );
+        assert_eq!(parse_packet(b"$qC#b5"), Some((Packet::Corrupted, 6)));
+        assert_eq!(parse_packet(b"\x03$g#67"), Some((Packet::Interrupt, 1)));
+        assert_eq!(
+            parse_packet(b"$X0,1:}]#f9"),
+            Some((Packet::Command(b"X0,1:}".to_vec()), 11))
+        );
+
+        // Incomplete
+        assert_eq!(parse_packet(b"$g#6"), None);
+        assert_eq!(parse_packet(b"+"), None);
+    }
+
+    #[test]
+    fn commands_are_split() {
+        assert_eq!(split_command(b"m1000,4"), Some(('m', "1000,4")));
+        assert_eq!(split_command(b"g"), Some(('g', "")));
+        assert_eq!(split_command(b""), None);
+
+        // Non-ASCII, whether it's valid UTF-8 or not
+        let Some((Packet::Command(data), _)) = parse_packet("$\u{e9}#6c".as_bytes()) else {
+            panic!("packet wasn't parsed");
+        };
+        assert_eq!(split_command(&data), None);
+        assert_eq!(split_command(b"\xffm0,1"), None);
+    }
+
+    #[test]
+    fn registers_round_trip() {
+        let regs = kvm_regs {
+            rax: 1,
+            r15: 0xffff_ffff_8100_0000,
+            rip: 0x1000000,
+            rflags: 0x246,
+            ..Default::default()
+        };
+        let mut sregs = kvm_sregs::default();
+        sregs.cs.selector = 0x10;
+
+        let bytes = encode_registers(&regs, &sregs);
+
+        assert_eq!(bytes.len(), 17 * 8 + 7 * 4);
+        assert_eq!(
+            &bytes[17 * 8..17 * 8 + 8],
+            [0x46, 0x02, 0, 0, 0x10, 0, 0, 0]
+        );
+
+        let encoded = hex(&bytes);
+        assert_eq!(decode_registers(&from_hex(&encoded).unwrap()), Some(regs));
+    }
+}
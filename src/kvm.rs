@@ -1,23 +1,38 @@
-use crate::util::WrappedAutoFree;
+use crate::{memory::GuestRegion, util::WrappedAutoFree};
 use core::num::NonZeroUsize;
 use kvm_bindings::{
-    kvm_cpuid2, kvm_enable_cap, kvm_guest_debug, kvm_pit_config, kvm_regs, kvm_run as kvm_run_t,
-    kvm_sregs, kvm_userspace_memory_region, kvm_vcpu_events, CpuId, KVMIO, KVM_GUESTDBG_ENABLE,
-    KVM_GUESTDBG_SINGLESTEP,
+    kvm_cpuid2, kvm_debug_exit_arch, kvm_enable_cap, kvm_guest_debug, kvm_irqfd, kvm_pit_config,
+    kvm_regs, kvm_run as kvm_run_t, kvm_sregs, kvm_userspace_memory_region, kvm_vcpu_events, CpuId,
+    KVMIO, KVM_EXIT_DEBUG, KVM_EXIT_FAIL_ENTRY, KVM_EXIT_HLT, KVM_EXIT_INTERNAL_ERROR,
+    KVM_EXIT_INTR, KVM_EXIT_IO, KVM_EXIT_IO_IN, KVM_EXIT_IO_OUT, KVM_EXIT_IRQ_WINDOW_OPEN,
+    KVM_EXIT_MMIO, KVM_EXIT_SHUTDOWN, KVM_EXIT_SYSTEM_EVENT, KVM_GUESTDBG_ENABLE,
+    KVM_GUESTDBG_INJECT_BP, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP,
+    KVM_GUESTDBG_USE_SW_BP,
 };
 use nix::{
     errno::Errno,
     fcntl,
     fcntl::OFlag,
     libc,
-    sys::{mman, mman::MapFlags, mman::ProtFlags, stat::Mode},
+    sys::{
+        mman,
+        mman::MapFlags,
+        mman::ProtFlags,
+        pthread::{self, Pthread},
+        signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        stat::Mode,
+    },
 };
 use std::{
     ffi::c_int,
-    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    fmt,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    ptr, slice,
+    sync::Arc,
 };
 
 ioctl_write_int_bad!(kvm_create_vm, request_code_none!(KVMIO, 0x01));
+ioctl_write_int_bad!(kvm_check_extension, request_code_none!(KVMIO, 0x03));
 ioctl_write_int_bad!(kvm_get_vcpu_mmap_size, request_code_none!(KVMIO, 0x04));
 ioctl_write_int_bad!(kvm_run, request_code_none!(KVMIO, 0x80));
 ioctl_write_int_bad!(kvm_create_vcpu, request_code_none!(KVMIO, 0x41));
@@ -34,6 +49,7 @@ ioctl_write_ptr!(kvm_set_sregs, KVMIO, 0x84, kvm_sregs);
 ioctl_none!(kvm_create_irqchip, KVMIO, 0x60);
 ioctl_write_ptr!(kvm_create_pit2, KVMIO, 0x77, kvm_pit_config);
 ioctl_write_ptr!(kvm_set_guest_debug, KVMIO, 0x9b, kvm_guest_debug);
+ioctl_write_ptr!(kvm_irqfd, KVMIO, 0x76, kvm_irqfd);
 ioctl_write_ptr!(kvm_enable_capability, KVMIO, 0xa3, kvm_enable_cap);
 ioctl_read!(kvm_get_vcpu_events, KVMIO, 0x9f, kvm_vcpu_events);
 ioctl_readwrite!(kvm_get_supported_cpuid, KVMIO, 0x05, kvm_cpuid2);
@@ -49,37 +65,384 @@ unsafe fn kvm_set_tss_addr(fd: c_int, data: u64) -> nix::Result<c_int> {
     Errno::result(libc::ioctl(fd, request_code_none!(KVMIO, 0x47), data))
 }
 
+const KVM_CAP_NR_VCPUS: c_int = 9;
+const KVM_CAP_MAX_VCPUS: c_int = 66;
+/// Assumed when KVM reports neither limit, as the API documentation says
+const DEFAULT_MAX_VCPUS: c_int = 4;
+const KVM_CAP_SPLIT_IRQCHIP: u32 = 121;
+/// Keep interrupts from being injected while single-stepping, missing from
+/// kvm-bindings
+const KVM_GUESTDBG_BLOCKIRQ: u32 = 1 << 20;
+
+/// Sent to a vCPU thread to kick it out of `KVM_RUN`
+pub const KICK_SIGNAL: Signal = Signal::SIGUSR1;
+
+/// Number of pins on the IOAPIC emulated by userspace, when using a split irqchip
+const SPLIT_IRQCHIP_IOAPIC_PINS: u64 = 24;
+
+/// The TSS occupies 3 pages
+const TSS_SIZE: u64 = 3 * 0x1000;
+/// The identity map occupies a single page
+const IDENTITY_MAP_SIZE: u64 = 0x1000;
+
+/// Local and global enable bits of DR0 to DR3 in DR7
+const DR7_ENABLE_MASK: u64 = 0xFF;
+
+/// Debug events that exit to userspace, see `Vcpu::set_guest_debug`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestDebug {
+    /// Exit after every instruction, with interrupts blocked meanwhile
+    pub single_step: bool,
+    /// Exit on `int3` rather than delivering #BP to the guest
+    pub sw_breakpoints: bool,
+    /// Linear addresses held by DR0 to DR3
+    pub addrs: [u64; 4],
+    /// Which of DR0 to DR3 are enabled and what they match, hardware
+    /// breakpoints are only used if any of them is enabled
+    pub dr7: u64,
+    /// Deliver #BP to the guest on the next entry, to hand back an `int3`
+    /// that isn't ours
+    pub inject_bp: bool,
+}
+
+/// Where the interrupt controllers are emulated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqChip {
+    /// PIC, IOAPIC and LAPICs are all emulated by KVM
+    InKernel,
+    /// Only the LAPICs are emulated by KVM, the PIC and IOAPIC are left to userspace
+    Split,
+    /// No interrupt controllers at all, `hlt` exits to userspace
+    None,
+}
+
+#[derive(Debug)]
+pub enum VmError {
+    /// An ioctl failed
+    Io(std::io::Error),
+    /// At least one vCPU is required
+    NoVcpus,
+    /// KVM doesn't support this many vCPUs
+    TooManyVcpus { max: usize },
+    /// The PIT needs the in-kernel PIC and IOAPIC to deliver interrupts
+    PitWithoutIrqchip,
+    /// APs can only be brought up through a LAPIC
+    SmpWithoutIrqchip,
+    /// The TSS and identity map must be below 4GiB and must not overlap
+    InvalidSystemPages,
+    /// KVM reported nonsensical values
+    InvalidKvmState,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "KVM ioctl failed: {err}"),
+            Self::NoVcpus => write!(f, "at least one vCPU is required"),
+            Self::TooManyVcpus { max } => write!(f, "KVM supports at most {max} vCPUs"),
+            Self::PitWithoutIrqchip => write!(f, "the PIT requires an in-kernel irqchip"),
+            Self::SmpWithoutIrqchip => write!(f, "multiple vCPUs require an irqchip"),
+            Self::InvalidSystemPages => write!(
+                f,
+                "TSS and identity map must be below 4GiB and must not overlap"
+            ),
+            Self::InvalidKvmState => write!(f, "KVM returned invalid values"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+impl From<std::io::Error> for VmError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<Errno> for VmError {
+    fn from(err: Errno) -> Self {
+        Self::Io(err.into())
+    }
+}
+
+/// Configures and creates a VM along with its vCPUs
+#[derive(Clone, Debug)]
+pub struct VmBuilder {
+    irqchip: IrqChip,
+    pit: bool,
+    identity_map_addr: u64,
+    tss_addr: u64,
+    vcpus: u8,
+}
+
+impl Default for VmBuilder {
+    fn default() -> Self {
+        Self {
+            irqchip: IrqChip::InKernel,
+            pit: true,
+            // Right below the 4GiB boundary, in the region reserved for MMIO
+            identity_map_addr: 0xFFFFC000,
+            tss_addr: 0xFFFFD000,
+            vcpus: 1,
+        }
+    }
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn irqchip(mut self, irqchip: IrqChip) -> Self {
+        self.irqchip = irqchip;
+        self
+    }
+
+    /// Whether to create the in-kernel i8254 PIT
+    pub fn pit(mut self, pit: bool) -> Self {
+        self.pit = pit;
+        self
+    }
+
+    /// Guest physical address of the page used by KVM for the identity map
+    /// on Intel hosts, it must not be used by the guest
+    pub fn identity_map_addr(mut self, addr: u64) -> Self {
+        self.identity_map_addr = addr;
+        self
+    }
+
+    /// Guest physical address of the 3 pages used by KVM for the TSS on Intel
+    /// hosts, it must not be used by the guest
+    pub fn tss_addr(mut self, addr: u64) -> Self {
+        self.tss_addr = addr;
+        self
+    }
+
+    pub fn vcpus(mut self, vcpus: u8) -> Self {
+        self.vcpus = vcpus;
+        self
+    }
+
+    fn validate(&self) -> Result<(), VmError> {
+        if self.vcpus == 0 {
+            return Err(VmError::NoVcpus);
+        }
+
+        if self.pit && self.irqchip != IrqChip::InKernel {
+            return Err(VmError::PitWithoutIrqchip);
+        }
+
+        if self.vcpus > 1 && self.irqchip == IrqChip::None {
+            return Err(VmError::SmpWithoutIrqchip);
+        }
+
+        let (Some(identity_map_end), Some(tss_end)) = (
+            self.identity_map_addr.checked_add(IDENTITY_MAP_SIZE),
+            self.tss_addr.checked_add(TSS_SIZE),
+        ) else {
+            return Err(VmError::InvalidSystemPages);
+        };
+
+        let identity_map = self.identity_map_addr..identity_map_end;
+        let tss = self.tss_addr..tss_end;
+
+        if identity_map.end > 1 << 32
+            || tss.end > 1 << 32
+            || (identity_map.start < tss.end && tss.start < identity_map.end)
+        {
+            return Err(VmError::InvalidSystemPages);
+        }
+
+        Ok(())
+    }
+
+    /// Create the VM and all of its vCPUs, the vCPU IDs are contiguous and
+    /// start from 0
+    pub fn build(self) -> Result<(Kvm, Vec<Vcpu>), VmError> {
+        self.validate()?;
+
+        let kvm = Kvm::new()?;
+
+        let check = |cap| unsafe { kvm_check_extension(kvm.kvm.as_raw_fd(), cap) };
+
+        // Older kernels only report the recommended number of vCPUs
+        let max_vcpus = match check(KVM_CAP_MAX_VCPUS)? {
+            0 => match check(KVM_CAP_NR_VCPUS)? {
+                0 => DEFAULT_MAX_VCPUS,
+                nr_vcpus => nr_vcpus,
+            },
+            max_vcpus => max_vcpus,
+        };
+
+        if usize::from(self.vcpus) > max_vcpus as usize {
+            return Err(VmError::TooManyVcpus {
+                max: max_vcpus as usize,
+            });
+        }
+
+        unsafe {
+            match self.irqchip {
+                IrqChip::InKernel => {
+                    kvm_create_irqchip(kvm.vm.as_raw_fd())?;
+                }
+                IrqChip::Split => {
+                    let mut cap = kvm_enable_cap {
+                        cap: KVM_CAP_SPLIT_IRQCHIP,
+                        ..Default::default()
+                    };
+                    cap.args[0] = SPLIT_IRQCHIP_IOAPIC_PINS;
+
+                    kvm_enable_capability(kvm.vm.as_raw_fd(), &cap)?;
+                }
+                IrqChip::None => {}
+            }
+
+            if self.pit {
+                kvm_create_pit2(kvm.vm.as_raw_fd(), &kvm_pit_config::default())?;
+            }
+
+            // Must be done before creating any vCPUs
+            kvm_set_identity_map_addr(kvm.vm.as_raw_fd(), &self.identity_map_addr)?;
+        }
+
+        kvm.set_tss_addr(self.tss_addr)?;
+
+        let vcpus = (0..self.vcpus)
+            .map(|id| kvm.create_vcpu(id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((kvm, vcpus))
+    }
+}
+
+/// Reason for the vCPU returning to userspace, borrowing any data that is
+/// exchanged with the guest from the `kvm_run` mapping
+#[derive(Debug)]
+pub enum VmExit<'a> {
+    /// `in` instruction, `data` must be filled with what's read from `port`
+    /// `size` is the width of a single access, `data` may hold multiple
+    /// accesses for string instructions (`rep insb`)
+    IoIn {
+        port: u16,
+        size: u8,
+        data: &'a mut [u8],
+    },
+    /// `out` instruction, `data` contains what's written to `port`
+    IoOut { port: u16, size: u8, data: &'a [u8] },
+    /// Read from an unmapped guest physical address, `data` must be filled
+    /// with the value that's read
+    MmioRead { addr: u64, data: &'a mut [u8] },
+    /// Write to an unmapped guest physical address
+    MmioWrite { addr: u64, data: &'a [u8] },
+    /// The guest executed `hlt`, only seen without an in-kernel irqchip
+    Hlt,
+    /// Triple fault or another unrecoverable condition
+    Shutdown,
+    /// Debug exception, caused by single-stepping or a breakpoint
+    Debug(kvm_debug_exit_arch),
+    /// KVM failed to emulate an instruction or deliver an event
+    InternalError { suberror: u32, data: &'a [u64] },
+    /// The guest requested a shutdown, reset or crash through a paravirt
+    /// interface, `type_` is one of `KVM_SYSTEM_EVENT_*`
+    SystemEvent { type_: u32, flags: u64 },
+    /// The VM entry failed, `reason` is the hardware specific failure reason
+    FailEntry { reason: u64, cpu: u32 },
+    /// The guest is ready to accept an interrupt
+    IrqWindowOpen,
+    /// `KVM_RUN` was interrupted by a signal, such as a kick from a `VcpuHandle`
+    Intr,
+    /// Any other exit reason that we don't know how to handle
+    Unknown(u32),
+}
+
+/// Handle to a VM, vCPUs are created from it with `create_vcpu`
 pub struct Kvm {
     kvm: OwnedFd,
     vm: OwnedFd,
+    vcpu_mmap_size: NonZeroUsize,
+}
+
+struct KvmRunMapping(WrappedAutoFree<*mut kvm_run_t, Box<dyn FnOnce(*mut kvm_run_t) + Send>>);
+
+// The mapping is only ever accessed by the thread running the vCPU, through
+// `&mut Vcpu` (or `&Vcpu` for reads), besides `immediate_exit` which is
+// written through a `VcpuHandle`
+unsafe impl Send for KvmRunMapping {}
+unsafe impl Sync for KvmRunMapping {}
+
+/// A single virtual CPU, with its own `kvm_run` mapping
+pub struct Vcpu {
+    id: u8,
     vcpu: OwnedFd,
-    kvm_run: WrappedAutoFree<*mut kvm_run_t, Box<dyn FnOnce(*mut kvm_run_t)>>,
+    kvm_run: Arc<KvmRunMapping>,
+    kvm_run_size: usize,
+}
+
+/// Lets other threads stop a vCPU, keeps its `kvm_run` mapping alive
+#[derive(Clone)]
+pub struct VcpuHandle {
+    kvm_run: Arc<KvmRunMapping>,
+}
+
+impl VcpuHandle {
+    /// Make the vCPU return `VmExit::Intr` from `run`, now if `thread` is
+    /// currently running it and right away on any later call otherwise
+    /// `install_kick_handler` must have been called beforehand
+    pub fn kick(&self, thread: Pthread) -> Result<(), std::io::Error> {
+        // Covers the window where the vCPU is about to enter the guest, and
+        // would miss the signal
+        let run: *mut kvm_run_t = *self.kvm_run.0;
+        unsafe { ptr::addr_of_mut!((*run).immediate_exit).write_volatile(1) };
+
+        pthread::pthread_kill(thread, KICK_SIGNAL)?;
+
+        Ok(())
+    }
+}
+
+/// Handle `KICK_SIGNAL`, which would otherwise terminate the process, the
+/// handler does nothing besides interrupting `KVM_RUN`
+pub fn install_kick_handler() -> Result<(), std::io::Error> {
+    extern "C" fn handle_kick(_: c_int) {}
+
+    let action = SigAction::new(
+        SigHandler::Handler(handle_kick),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+
+    unsafe { signal::sigaction(KICK_SIGNAL, &action)? };
+
+    Ok(())
 }
 
 impl Kvm {
-    pub fn new() -> Result<Self, std::io::Error> {
+    /// Create a bare VM, without any interrupt controllers or vCPUs
+    /// See `VmBuilder` for creating a usable VM
+    pub fn new() -> Result<Self, VmError> {
         let kvm =
             unsafe { OwnedFd::from_raw_fd(fcntl::open("/dev/kvm", OFlag::O_RDWR, Mode::empty())?) };
         let vm = unsafe { OwnedFd::from_raw_fd(kvm_create_vm(kvm.as_raw_fd(), 0)?) };
 
-        // TODO refactor this, it should be done outside `new`
-        unsafe {
-            kvm_create_irqchip(vm.as_raw_fd())?;
-            kvm_create_pit2(vm.as_raw_fd(), &kvm_pit_config::default())?;
-
-            let idmap_addr = 0xFFFFC000;
-            kvm_set_identity_map_addr(vm.as_raw_fd(), &idmap_addr)?;
-        };
+        let vcpu_mmap_size = unsafe { kvm_get_vcpu_mmap_size(kvm.as_raw_fd(), 0)? };
+        let vcpu_mmap_size = usize::try_from(vcpu_mmap_size)
+            .ok()
+            .and_then(NonZeroUsize::new)
+            .ok_or(VmError::InvalidKvmState)?;
 
-        let vcpu = unsafe { OwnedFd::from_raw_fd(kvm_create_vcpu(vm.as_raw_fd(), 0)?) };
-
-        let mmap_size = NonZeroUsize::new(unsafe {
-            kvm_get_vcpu_mmap_size(kvm.as_raw_fd(), 0)?
-                .try_into()
-                .expect("KVM provided mmap_size doesn't fit usize!")
+        Ok(Self {
+            kvm,
+            vm,
+            vcpu_mmap_size,
         })
-        .expect("KVM provided zero usize!");
+    }
 
+    /// Create a vCPU, `id` is also used as its APIC ID
+    pub fn create_vcpu(&self, id: u8) -> Result<Vcpu, std::io::Error> {
+        let vcpu =
+            unsafe { OwnedFd::from_raw_fd(kvm_create_vcpu(self.vm.as_raw_fd(), id.into())?) };
+
+        let mmap_size = self.vcpu_mmap_size;
         let kvm_run = WrappedAutoFree::new(
             unsafe {
                 mman::mmap(
@@ -96,16 +459,18 @@ impl Kvm {
             }) as _,
         );
 
-        Ok(Self {
-            kvm,
-            vm,
+        Ok(Vcpu {
+            id,
             vcpu,
-            kvm_run,
+            kvm_run: Arc::new(KvmRunMapping(kvm_run)),
+            kvm_run_size: mmap_size.get(),
         })
     }
 
     pub fn set_user_memory_region(
         &self,
+        slot: u32,
+        flags: u32,
         guest_phys_addr: u64,
         memory_size: u64,
         userspace_addr: u64,
@@ -114,8 +479,8 @@ impl Kvm {
             kvm_set_user_memory_region(
                 self.vm.as_raw_fd(),
                 &kvm_userspace_memory_region {
-                    slot: 0,
-                    flags: 0,
+                    slot,
+                    flags,
                     guest_phys_addr,
                     memory_size,
                     userspace_addr,
@@ -126,6 +491,83 @@ impl Kvm {
         Ok(())
     }
 
+    /// Register a region of guest memory in its slot
+    pub fn register_region(&self, region: &GuestRegion) -> Result<(), std::io::Error> {
+        self.set_user_memory_region(
+            region.slot(),
+            region.flags(),
+            region.guest_base(),
+            region.size() as u64,
+            region.host_address(),
+        )
+    }
+
+    pub fn set_tss_addr(&self, addr: u64) -> Result<(), std::io::Error> {
+        unsafe { kvm_set_tss_addr(self.vm.as_raw_fd(), addr)? };
+
+        Ok(())
+    }
+
+    /// Report triple faults as `KVM_EXIT_SYSTEM_EVENT` rather than
+    /// `KVM_EXIT_SHUTDOWN`, useful when debugging
+    pub fn enable_triple_fault_event(&self) -> Result<(), std::io::Error> {
+        unsafe {
+            kvm_enable_capability(
+                self.vm.as_raw_fd(),
+                &kvm_enable_cap {
+                    // KVM_CAP_X86_TRIPLE_FAULT_EVENT
+                    cap: 218,
+                    ..Default::default()
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Inject an interrupt on `gsi` whenever the eventfd `fd` is signalled
+    pub fn register_irqfd(&self, fd: impl AsFd, gsi: u32) -> Result<(), std::io::Error> {
+        unsafe {
+            kvm_irqfd(
+                self.vm.as_raw_fd(),
+                &kvm_irqfd {
+                    fd: fd.as_fd().as_raw_fd() as u32,
+                    gsi,
+                    ..Default::default()
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// CPUID leaves supported by both the host CPU and KVM
+    pub fn supported_cpuid(&self) -> Result<CpuId, std::io::Error> {
+        let mut cpuid2 = CpuId::new(80).expect("should not fail to construct CpuId!");
+
+        unsafe {
+            kvm_get_supported_cpuid(self.kvm.as_raw_fd(), cpuid2.as_mut_fam_struct_ptr())?;
+        }
+
+        Ok(cpuid2)
+    }
+}
+
+impl Vcpu {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    fn kvm_run_ptr(&self) -> *mut kvm_run_t {
+        *self.kvm_run.0
+    }
+
+    pub fn handle(&self) -> VcpuHandle {
+        VcpuHandle {
+            kvm_run: self.kvm_run.clone(),
+        }
+    }
+
     pub fn get_vcpu_sregs(&self) -> Result<kvm_sregs, std::io::Error> {
         let mut sregs = kvm_sregs::default();
         unsafe { kvm_get_sregs(self.vcpu.as_raw_fd(), &mut sregs)? };
@@ -133,7 +575,7 @@ impl Kvm {
         Ok(sregs)
     }
 
-    pub fn set_vcpu_sregs(&self, sregs: *const kvm_sregs) -> Result<(), std::io::Error> {
+    pub fn set_vcpu_sregs(&self, sregs: &kvm_sregs) -> Result<(), std::io::Error> {
         unsafe { kvm_set_sregs(self.vcpu.as_raw_fd(), sregs)? };
 
         Ok(())
@@ -146,43 +588,47 @@ impl Kvm {
         Ok(regs)
     }
 
-    pub fn set_vcpu_regs(&self, regs: *const kvm_regs) -> Result<(), std::io::Error> {
+    pub fn set_vcpu_regs(&self, regs: &kvm_regs) -> Result<(), std::io::Error> {
         unsafe { kvm_set_regs(self.vcpu.as_raw_fd(), regs)? };
 
         Ok(())
     }
 
-    pub fn set_tss_addr(&self, addr: u64) -> Result<(), std::io::Error> {
-        unsafe { kvm_set_tss_addr(self.vm.as_raw_fd(), addr)? };
-
-        Ok(())
-    }
+    /// Configure which debug events exit to userspace as `VmExit::Debug`,
+    /// everything is disabled with `GuestDebug::default()`
+    pub fn set_guest_debug(&self, debug: &GuestDebug) -> Result<(), std::io::Error> {
+        let mut control = 0;
 
-    pub fn enable_debug(&mut self) -> Result<(), std::io::Error> {
-        let mut dbg = kvm_guest_debug {
-            control: KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_SINGLESTEP,
-            ..Default::default()
-        };
+        if debug.single_step {
+            control |= KVM_GUESTDBG_SINGLESTEP | KVM_GUESTDBG_BLOCKIRQ;
+        }
 
-        dbg.arch.debugreg[7] = 0x00000400;
+        if debug.sw_breakpoints {
+            control |= KVM_GUESTDBG_USE_SW_BP;
+        }
 
-        unsafe {
-            kvm_set_guest_debug(self.vcpu.as_raw_fd(), &dbg)?;
+        if debug.dr7 & DR7_ENABLE_MASK != 0 {
+            control |= KVM_GUESTDBG_USE_HW_BP;
         }
 
-        unsafe {
-            kvm_enable_capability(
-                self.vm.as_raw_fd(),
-                &kvm_enable_cap {
-                    // KVM_CAP_X86_TRIPLE_FAULT_EVENT
-                    cap: 218,
-                    ..Default::default()
-                },
-            )?;
+        if debug.inject_bp {
+            control |= KVM_GUESTDBG_INJECT_BP;
+        }
 
-            (*(*self.kvm_run)).kvm_valid_regs = 7;
+        if control != 0 {
+            control |= KVM_GUESTDBG_ENABLE;
         }
 
+        let mut dbg = kvm_guest_debug {
+            control,
+            ..Default::default()
+        };
+
+        dbg.arch.debugreg[..4].copy_from_slice(&debug.addrs);
+        dbg.arch.debugreg[7] = debug.dr7;
+
+        unsafe { kvm_set_guest_debug(self.vcpu.as_raw_fd(), &dbg)? };
+
         Ok(())
     }
 
@@ -196,24 +642,184 @@ impl Kvm {
         Ok(events)
     }
 
-    pub fn setup_cpuid(&self) -> Result<(), std::io::Error> {
-        let mut cpuid2 = CpuId::new(80).expect("should not fail to construct CpuId!");
+    /// Set the CPUID leaves reported to the guest, patching in the APIC ID
+    /// so that each vCPU can be told apart
+    pub fn setup_cpuid(&self, cpuid: &CpuId) -> Result<(), std::io::Error> {
+        let mut cpuid = cpuid.clone();
+
+        for entry in cpuid.as_mut_slice() {
+            match entry.function {
+                // EBX[31:24] contains the initial APIC ID
+                0x1 => {
+                    entry.ebx = (entry.ebx & 0x00FFFFFF) | (u32::from(self.id) << 24);
+                }
+                // EDX contains the x2APIC ID
+                0xB | 0x1F => {
+                    entry.edx = self.id.into();
+                }
+                _ => {}
+            }
+        }
 
         unsafe {
-            kvm_get_supported_cpuid(self.kvm.as_raw_fd(), cpuid2.as_mut_fam_struct_ptr())?;
-            kvm_set_cpuid2(self.vcpu.as_raw_fd(), cpuid2.as_fam_struct_ptr())?;
+            kvm_set_cpuid2(self.vcpu.as_raw_fd(), cpuid.as_fam_struct_ptr())?;
         };
 
         Ok(())
     }
 
-    pub fn run(&self) -> Result<*const kvm_run_t, std::io::Error> {
-        unsafe {
-            kvm_run(self.vcpu.as_raw_fd(), 0)?;
-        }
+    pub fn run(&mut self) -> Result<VmExit<'_>, std::io::Error> {
+        match unsafe { kvm_run(self.vcpu.as_raw_fd(), 0) } {
+            // Kicked through a `VcpuHandle`, or interrupted by another signal
+            Err(Errno::EINTR) => {
+                // So that the vCPU can be resumed after being kicked
+                let run = self.kvm_run_ptr();
+                unsafe { ptr::addr_of_mut!((*run).immediate_exit).write_volatile(0) };
+
+                return Ok(VmExit::Intr);
+            }
+            result => result?,
+        };
 
         // The `kvm_run` struct is filled with new data as it was associated
         // with the `vcpu` FD in the mmap() call
-        Ok(*self.kvm_run as *const kvm_run_t)
+        let run = unsafe { &mut *self.kvm_run_ptr() };
+
+        let exit = match run.exit_reason {
+            KVM_EXIT_IO => {
+                let io = unsafe { run.__bindgen_anon_1.io };
+                let offset = io.data_offset as usize;
+                let len = io.size as usize * io.count as usize;
+
+                // The data lives in the same mapping, right after `kvm_run`
+                if offset
+                    .checked_add(len)
+                    .is_none_or(|end| end > self.kvm_run_size)
+                {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "I/O exit data is outside of the kvm_run mapping",
+                    ));
+                }
+
+                let data = unsafe {
+                    slice::from_raw_parts_mut((self.kvm_run_ptr() as *mut u8).add(offset), len)
+                };
+
+                match io.direction as u32 {
+                    KVM_EXIT_IO_IN => VmExit::IoIn {
+                        port: io.port,
+                        size: io.size,
+                        data,
+                    },
+                    KVM_EXIT_IO_OUT => VmExit::IoOut {
+                        port: io.port,
+                        size: io.size,
+                        data,
+                    },
+                    _ => VmExit::Unknown(run.exit_reason),
+                }
+            }
+            KVM_EXIT_MMIO => {
+                let mmio = unsafe { &mut run.__bindgen_anon_1.mmio };
+                let len = (mmio.len as usize).min(mmio.data.len());
+
+                if mmio.is_write != 0 {
+                    VmExit::MmioWrite {
+                        addr: mmio.phys_addr,
+                        data: &mmio.data[..len],
+                    }
+                } else {
+                    VmExit::MmioRead {
+                        addr: mmio.phys_addr,
+                        data: &mut mmio.data[..len],
+                    }
+                }
+            }
+            KVM_EXIT_HLT => VmExit::Hlt,
+            KVM_EXIT_SHUTDOWN => VmExit::Shutdown,
+            KVM_EXIT_DEBUG => VmExit::Debug(unsafe { run.__bindgen_anon_1.debug.arch }),
+            KVM_EXIT_INTERNAL_ERROR => {
+                let internal = unsafe { &run.__bindgen_anon_1.internal };
+                let ndata = (internal.ndata as usize).min(internal.data.len());
+
+                VmExit::InternalError {
+                    suberror: internal.suberror,
+                    data: &internal.data[..ndata],
+                }
+            }
+            KVM_EXIT_SYSTEM_EVENT => {
+                let event = unsafe { run.__bindgen_anon_1.system_event };
+
+                VmExit::SystemEvent {
+                    type_: event.type_,
+                    flags: event.flags,
+                }
+            }
+            KVM_EXIT_FAIL_ENTRY => {
+                let fail_entry = unsafe { run.__bindgen_anon_1.fail_entry };
+
+                VmExit::FailEntry {
+                    reason: fail_entry.hardware_entry_failure_reason,
+                    cpu: fail_entry.cpu,
+                }
+            }
+            KVM_EXIT_IRQ_WINDOW_OPEN => VmExit::IrqWindowOpen,
+            KVM_EXIT_INTR => VmExit::Intr,
+            reason => VmExit::Unknown(reason),
+        };
+
+        Ok(exit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvm::{IrqChip, VmBuilder, VmError};
+
+    #[test]
+    fn builder_rejects_invalid_configs() {
+        assert!(matches!(
+            VmBuilder::new().vcpus(0).validate(),
+            Err(VmError::NoVcpus)
+        ));
+        assert!(matches!(
+            VmBuilder::new().irqchip(IrqChip::Split).validate(),
+            Err(VmError::PitWithoutIrqchip)
+        ));
+        assert!(matches!(
+            VmBuilder::new()
+                .irqchip(IrqChip::None)
+                .pit(false)
+                .vcpus(2)
+                .validate(),
+            Err(VmError::SmpWithoutIrqchip)
+        ));
+    }
+
+    #[test]
+    fn builder_rejects_overlapping_system_pages() {
+        assert!(matches!(
+            VmBuilder::new()
+                .identity_map_addr(0xFFFFE000)
+                .tss_addr(0xFFFFD000)
+                .validate(),
+            Err(VmError::InvalidSystemPages)
+        ));
+        assert!(matches!(
+            VmBuilder::new().tss_addr(0xFFFFF000).validate(),
+            Err(VmError::InvalidSystemPages)
+        ));
+        assert!(matches!(
+            VmBuilder::new()
+                .identity_map_addr(u64::MAX - 0x1000)
+                .validate(),
+            Err(VmError::InvalidSystemPages)
+        ));
+        assert!(VmBuilder::new()
+            .irqchip(IrqChip::None)
+            .pit(false)
+            .validate()
+            .is_ok());
     }
 }
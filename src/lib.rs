@@ -10,7 +10,23 @@ pub mod bootparam {
     include!(concat!(env!("OUT_DIR"), "/bootparam.rs"));
 }
 
+pub mod acpi;
+pub mod aml;
+pub mod bus;
+pub mod cli;
 pub mod constants;
+pub mod devices;
+pub mod disk;
+pub mod e820;
+pub mod gdb;
 pub mod kvm;
 pub mod linux_loader;
+pub mod memory;
+pub mod mptable;
+pub mod paging;
+pub mod power;
+pub mod pvh;
+pub mod qcow2;
 pub mod util;
+pub mod virtio;
+pub mod virtqueue;
@@ -1,13 +1,23 @@
 use crate::{
-    bootparam::{boot_e820_entry, boot_params, CAN_USE_HEAP, LOADED_HIGH},
+    bootparam::{
+        boot_e820_entry, boot_params, setup_data, CAN_USE_HEAP, E820_MAX_ENTRIES_ZEROPAGE,
+        LOADED_HIGH, SETUP_E820_EXT, XLF_CAN_BE_LOADED_ABOVE_4G, XLF_KERNEL_64,
+    },
     constants::SegmentFlags,
+    util::ByteValued,
 };
 use kvm_bindings::kvm_segment;
-use std::{mem, ptr};
+use std::{fmt, mem, ptr};
+
+// SAFETY: the zero page is packed, with explicit padding, and only made of
+// integers
+unsafe impl ByteValued for boot_params {}
 
 pub struct BzImage<'a> {
     bz_image: &'a [u8],
     boot_params: boot_params,
+    /// `setup_data` blobs and their types, see `add_setup_data`
+    setup_data: Vec<(u32, Vec<u8>)>,
 }
 
 #[derive(Debug)]
@@ -18,8 +28,90 @@ pub enum LoaderError {
     InvalidImage,
     /// Too many E820 entries
     TooManyEntries,
+    /// `setup_data` needs boot protocol 2.09
+    SetupDataUnsupported,
+    /// The `setup_data` blob is larger than 4GiB
+    SetupDataTooLarge,
+    /// The boot protocol is older than `MIN_PROTOCOL_VERSION`
+    UnsupportedProtocol(u16),
+    /// The ELF image doesn't contain a PVH entry point note
+    NoPvhEntry,
+    /// The kernel doesn't have the 64-bit entry point (`XLF_KERNEL_64`)
+    No64BitEntry,
+    /// The kernel can't be placed above 4GiB (`XLF_CAN_BE_LOADED_ABOVE_4G`)
+    NotLoadableAbove4G,
+    /// `kernel_alignment` isn't a power of two
+    InvalidAlignment(u32),
+    /// The memory the kernel needs from its load address (`init_size`) runs
+    /// past the end of RAM
+    KernelTooLarge { addr: u64, size: u64 },
+    /// The initramfs doesn't fit between the kernel and the end of low memory
+    InitramfsTooLarge,
+    /// Command line parameters can't contain NUL bytes
+    InvalidCmdline,
+    /// The command line is longer than what the kernel accepts
+    CmdlineTooLong { len: usize, max: usize },
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ImageTooSmall => write!(f, "kernel image is truncated"),
+            Self::InvalidImage => write!(f, "not a bzImage or ELF vmlinux"),
+            Self::TooManyEntries => write!(f, "too many E820 entries"),
+            Self::SetupDataUnsupported => {
+                write!(
+                    f,
+                    "kernel doesn't support setup_data, boot protocol 2.09 is required"
+                )
+            }
+            Self::SetupDataTooLarge => write!(f, "setup_data blob is too large"),
+            Self::UnsupportedProtocol(version) => write!(
+                f,
+                "boot protocol {}.{:02} is unsupported, at least {}.{:02} is required",
+                version >> 8,
+                version & 0xFF,
+                MIN_PROTOCOL_VERSION >> 8,
+                MIN_PROTOCOL_VERSION & 0xFF
+            ),
+            Self::NoPvhEntry => write!(f, "ELF image doesn't support PVH boot"),
+            Self::No64BitEntry => write!(f, "kernel doesn't support the 64-bit boot protocol"),
+            Self::NotLoadableAbove4G => write!(f, "kernel can't be loaded above 4GiB"),
+            Self::InvalidAlignment(align) => write!(f, "invalid kernel alignment {align:#x}"),
+            Self::KernelTooLarge { addr, size } => write!(
+                f,
+                "kernel needs {size:#x} bytes of memory at {addr:#x}, which doesn't fit in RAM"
+            ),
+            Self::InitramfsTooLarge => write!(f, "initramfs doesn't fit in guest memory"),
+            Self::InvalidCmdline => write!(f, "kernel command line contains a NUL byte"),
+            Self::CmdlineTooLong { len, max } => write!(
+                f,
+                "kernel command line is {len} bytes long, the kernel accepts at most {max}"
+            ),
+        }
+    }
 }
 
+impl std::error::Error for LoaderError {}
+
+/// 32-bit CS for PVH boot, placed at 0x8
+/// See `pack_segment` for more details
+pub const CODE32_SEGMENT: kvm_segment = kvm_segment {
+    base: 0,
+    limit: 0xFFFFFFFF,
+    selector: 0x8,
+    type_: SegmentFlags::CODE_SEGMENT | SegmentFlags::CODE_READ,
+    present: 1,
+    dpl: 0,
+    db: 1,
+    s: 1,
+    l: 0,
+    g: 1,
+    avl: 0,
+    unusable: 0,
+    padding: 0,
+};
+
 /// CS, placed at 0x10
 /// See `pack_segment` for more details
 pub const CODE_SEGMENT: kvm_segment = kvm_segment {
@@ -56,6 +148,83 @@ pub const DATA_SEGMENT: kvm_segment = kvm_segment {
     padding: 0,
 };
 
+/// Place an initramfs of `size` bytes as high as possible below `end`, but
+/// above `kernel_end`
+pub fn place_initramfs(kernel_end: u64, end: u64, size: u32) -> Result<u32, LoaderError> {
+    end.checked_sub(size.into())
+        // Page aligned, like other bootloaders do
+        .map(|addr| addr & !0xFFF)
+        .filter(|&addr| addr >= kernel_end)
+        .and_then(|addr| addr.try_into().ok())
+        .ok_or(LoaderError::InitramfsTooLarge)
+}
+
+/// Where the protected-mode code is loaded when the kernel isn't relocatable
+const DEFAULT_LOAD_ADDR: u64 = 0x100000;
+
+/// Oldest supported boot protocol, which added `cmdline_size` and is the
+/// first one that 64-bit kernels are known to implement
+pub const MIN_PROTOCOL_VERSION: u16 = 0x206;
+
+/// Kernel command line, made up of space separated parameters
+#[derive(Clone, Debug, Default)]
+pub struct Cmdline {
+    line: String,
+}
+
+impl Cmdline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append one or more space separated parameters
+    pub fn push(&mut self, params: &str) -> Result<(), LoaderError> {
+        if params.contains('\0') {
+            return Err(LoaderError::InvalidCmdline);
+        }
+
+        let params = params.trim();
+
+        if params.is_empty() {
+            return Ok(());
+        }
+
+        if !self.line.is_empty() {
+            self.line.push(' ');
+        }
+
+        self.line.push_str(params);
+
+        Ok(())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.line
+    }
+
+    /// Make sure the command line is at most `max` bytes long, excluding the
+    /// NUL terminator
+    pub fn check_len(&self, max: usize) -> Result<(), LoaderError> {
+        let len = self.line.len();
+
+        if len > max {
+            return Err(LoaderError::CmdlineTooLong { len, max });
+        }
+
+        Ok(())
+    }
+
+    /// The command line as the NUL terminated string that the kernel expects
+    pub fn to_bytes_with_nul(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.line.len() + 1);
+
+        bytes.extend_from_slice(self.line.as_bytes());
+        bytes.push(0);
+
+        bytes
+    }
+}
+
 /// Start offset of the 32-bit (non-real-mode) kernel
 fn kernel_byte_offset(boot_params: &boot_params) -> usize {
     (match boot_params.hdr.setup_sects as usize {
@@ -68,9 +237,6 @@ fn kernel_byte_offset(boot_params: &boot_params) -> usize {
 impl<'a> BzImage<'a> {
     pub fn new(
         bz_image: &'a [u8],
-        cmdline_addr: u32,
-        initramfs_addr: Option<u32>,
-        initramfs_size: Option<u32>,
         e820_entries: &[boot_e820_entry],
     ) -> Result<BzImage<'a>, LoaderError> {
         // The setup_header is located at offset 0x1f1 (`hdr` field) from the start
@@ -109,37 +275,115 @@ impl<'a> BzImage<'a> {
             return Err(LoaderError::ImageTooSmall);
         }
 
+        if boot_params.hdr.version < MIN_PROTOCOL_VERSION {
+            return Err(LoaderError::UnsupportedProtocol(boot_params.hdr.version));
+        }
+
+        // We always jump straight to the 64-bit entry point
+        if u32::from(boot_params.hdr.xloadflags) & XLF_KERNEL_64 == 0 {
+            return Err(LoaderError::No64BitEntry);
+        }
+
         // VGA display
         boot_params.hdr.vid_mode = 0xFFFF;
 
         // "Undefined" Bootloader ID
         boot_params.hdr.type_of_loader = 0xFF;
 
-        // LOADED_HIGH: the protected-mode code is loaded at 0x100000
+        // LOADED_HIGH: the protected-mode code is loaded at 0x100000, or
+        // wherever `load_addr` says for relocatable kernels
         // CAN_USE_HEAP: Self explanatory
         boot_params.hdr.loadflags |= (LOADED_HIGH | CAN_USE_HEAP) as u8;
 
-        boot_params.hdr.ramdisk_image = initramfs_addr.unwrap_or(0);
-        boot_params.hdr.ramdisk_size = initramfs_size.unwrap_or(0);
+        // No initramfs unless `set_initramfs` is called
+        boot_params.hdr.ramdisk_image = 0;
+        boot_params.hdr.ramdisk_size = 0;
 
         // https://www.kernel.org/doc/html/latest/arch/x86/boot.html#sample-boot-configuration
         // 0xe000 - 0x200
         boot_params.hdr.heap_end_ptr = 0xde00;
-        // The command line parameters can be located anywhere in 64-bit mode
-        // Must be NUL terminated
-        boot_params.hdr.cmd_line_ptr = cmdline_addr;
+        // No command line unless `set_cmdline` is called
+        boot_params.hdr.cmd_line_ptr = 0;
         boot_params.ext_cmd_line_ptr = 0;
 
-        boot_params.e820_entries = e820_entries
-            .len()
-            .try_into()
-            .map_err(|_| LoaderError::TooManyEntries)?;
+        // Entries that don't fit in the zero page are passed through setup_data
+        let (e820_entries, e820_ext) =
+            e820_entries.split_at(e820_entries.len().min(E820_MAX_ENTRIES_ZEROPAGE as usize));
+
+        boot_params.e820_entries = e820_entries.len() as u8;
         boot_params.e820_table[..e820_entries.len()].copy_from_slice(e820_entries);
 
-        Ok(Self {
+        let mut loader = Self {
             bz_image,
             boot_params,
-        })
+            setup_data: Vec::new(),
+        };
+
+        if !e820_ext.is_empty() {
+            let data = e820_ext
+                .iter()
+                .flat_map(|entry| {
+                    let (addr, size, type_) = (entry.addr, entry.size, entry.type_);
+
+                    [
+                        &addr.to_le_bytes()[..],
+                        &size.to_le_bytes(),
+                        &type_.to_le_bytes(),
+                    ]
+                    .concat()
+                })
+                .collect::<Vec<_>>();
+
+            loader
+                .add_setup_data(SETUP_E820_EXT, data)
+                .map_err(|_| LoaderError::TooManyEntries)?;
+        }
+
+        Ok(loader)
+    }
+
+    /// Attach a `SETUP_*` blob to pass to the kernel, such as `SETUP_RNG_SEED`
+    /// for early entropy, `SETUP_DTB` or `SETUP_IMA`
+    /// The blobs are chained together by `link_setup_data`
+    pub fn add_setup_data(&mut self, type_: u32, data: Vec<u8>) -> Result<(), LoaderError> {
+        if self.boot_params.hdr.version < 0x209 {
+            return Err(LoaderError::SetupDataUnsupported);
+        }
+
+        if u32::try_from(data.len()).is_err() {
+            return Err(LoaderError::SetupDataTooLarge);
+        }
+
+        self.setup_data.push((type_, data));
+
+        Ok(())
+    }
+
+    /// Lay out the `setup_data` blobs as a linked list starting at `addr`,
+    /// returning the bytes to be written there, `hdr.setup_data` is updated
+    /// to point to the head of the list
+    pub fn link_setup_data(&mut self, addr: u64) -> Vec<u8> {
+        let header_size = mem::size_of::<setup_data>();
+        let mut bytes = Vec::new();
+
+        for (n, (type_, data)) in self.setup_data.iter().enumerate() {
+            // Each node is 8 byte aligned
+            let next = if n + 1 == self.setup_data.len() {
+                0
+            } else {
+                addr + (bytes.len() + header_size + data.len()).next_multiple_of(8) as u64
+            };
+
+            bytes.extend_from_slice(&next.to_le_bytes());
+            bytes.extend_from_slice(&type_.to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(data);
+            bytes.resize(bytes.len().next_multiple_of(8), 0);
+        }
+
+        self.boot_params.hdr.setup_data = if bytes.is_empty() { 0 } else { addr };
+
+        bytes
     }
 
     /// Get the boot parameters
@@ -147,6 +391,138 @@ impl<'a> BzImage<'a> {
         self.boot_params
     }
 
+    /// Boot protocol version implemented by the kernel, the major version is
+    /// in the upper byte, e.g. 0x20f for 2.15
+    pub fn protocol_version(&self) -> u16 {
+        self.boot_params.hdr.version
+    }
+
+    /// The kernel's version string, e.g. "6.8.6 (user@host) #1 SMP ..."
+    pub fn kernel_version(&self) -> Option<&'a str> {
+        // The offset is relative to the end of the boot sector
+        let offset = match self.boot_params.hdr.kernel_version {
+            0 => return None,
+            offset => usize::from(offset) + 0x200,
+        };
+
+        let version = self
+            .bz_image
+            .get(offset..kernel_byte_offset(&self.boot_params))?;
+        let len = version.iter().position(|&byte| byte == 0)?;
+
+        std::str::from_utf8(&version[..len]).ok()
+    }
+
+    /// Maximum length of the command line, excluding the NUL terminator
+    pub fn cmdline_size(&self) -> usize {
+        self.boot_params.hdr.cmdline_size as usize
+    }
+
+    /// Tell the kernel about the command line loaded at `addr`
+    pub fn set_cmdline(&mut self, addr: u64, cmdline: &Cmdline) -> Result<(), LoaderError> {
+        cmdline.check_len(self.cmdline_size())?;
+
+        // The command line can be located anywhere in 64-bit mode, the upper
+        // half of the address goes in `ext_cmd_line_ptr`
+        if addr >> 32 != 0 && !self.loadable_above_4g() {
+            return Err(LoaderError::NotLoadableAbove4G);
+        }
+
+        self.boot_params.hdr.cmd_line_ptr = addr as u32;
+        self.boot_params.ext_cmd_line_ptr = (addr >> 32) as u32;
+
+        Ok(())
+    }
+
+    fn loadable_above_4g(&self) -> bool {
+        u32::from(self.boot_params.hdr.xloadflags) & XLF_CAN_BE_LOADED_ABOVE_4G != 0
+    }
+
+    /// Memory that the kernel needs from its load address while it's
+    /// decompressing itself, which is larger than the image for sane kernels
+    pub fn init_size(&self) -> u64 {
+        let image_size = self.kernel32_slice().len() as u64;
+
+        if self.boot_params.hdr.version < 0x20a {
+            image_size
+        } else {
+            image_size.max(self.boot_params.hdr.init_size.into())
+        }
+    }
+
+    /// Find the address to load the protected-mode code at, such that the
+    /// kernel fits below `ram_end` (the end of RAM below 4GiB)
+    /// Relocatable kernels are placed at their `pref_address` if possible to
+    /// avoid relocating themselves, otherwise as low as `kernel_alignment`
+    /// allows above 1MiB, other kernels are always placed at 1MiB
+    pub fn load_addr(&self, ram_end: u64) -> Result<u64, LoaderError> {
+        let hdr = &self.boot_params.hdr;
+        let size = self.init_size();
+        let fits = |addr: u64| addr.checked_add(size).is_some_and(|end| end <= ram_end);
+
+        let addr = if hdr.relocatable_kernel != 0 {
+            let align = hdr.kernel_alignment;
+
+            if !align.is_power_of_two() {
+                return Err(LoaderError::InvalidAlignment(align));
+            }
+
+            let lowest = DEFAULT_LOAD_ADDR.next_multiple_of(align.into());
+
+            if hdr.version >= 0x20a && hdr.pref_address >= lowest && fits(hdr.pref_address) {
+                hdr.pref_address
+            } else {
+                lowest
+            }
+        } else {
+            DEFAULT_LOAD_ADDR
+        };
+
+        if !fits(addr) {
+            return Err(LoaderError::KernelTooLarge { addr, size });
+        }
+
+        if addr + size > 1 << 32 && !self.loadable_above_4g() {
+            return Err(LoaderError::NotLoadableAbove4G);
+        }
+
+        Ok(addr)
+    }
+
+    /// Highest address that the initramfs may occupy
+    pub fn initramfs_addr_max(&self) -> u32 {
+        self.boot_params.hdr.initrd_addr_max
+    }
+
+    /// Find the address to load an initramfs of `size` bytes at, as high as
+    /// possible below both `initramfs_addr_max` and `ram_end` (the end of RAM
+    /// below 4GiB), without overlapping the kernel loaded at `kernel_addr`
+    pub fn initramfs_addr(
+        &self,
+        kernel_addr: u64,
+        size: u32,
+        ram_end: u64,
+    ) -> Result<u32, LoaderError> {
+        let kernel_end = kernel_addr + self.init_size();
+
+        place_initramfs(
+            kernel_end,
+            ram_end.min(u64::from(self.initramfs_addr_max()) + 1),
+            size,
+        )
+    }
+
+    /// Tell the kernel about the initramfs loaded at `addr`
+    pub fn set_initramfs(&mut self, addr: u32, size: u32) {
+        self.boot_params.hdr.ramdisk_image = addr;
+        self.boot_params.hdr.ramdisk_size = size;
+    }
+
+    /// Point the kernel to the ACPI RSDP, instead of having it scan the BIOS area
+    pub fn set_acpi_rsdp_addr(&mut self, addr: u64) {
+        self.boot_params.acpi_rsdp_addr = addr;
+    }
+
     /// Get a slice to the image, pointing to the 32-bit startup code
     pub fn kernel32_slice(&self) -> &'a [u8] {
         &self.bz_image[kernel_byte_offset(&self.boot_params)..]
@@ -156,10 +532,34 @@ impl<'a> BzImage<'a> {
 #[cfg(test)]
 mod tests {
     use crate::{
-        linux_loader::{CODE_SEGMENT, DATA_SEGMENT},
+        bootparam::{boot_e820_entry, SETUP_E820_EXT, SETUP_RNG_SEED},
+        linux_loader::{BzImage, Cmdline, LoaderError, CODE32_SEGMENT, CODE_SEGMENT, DATA_SEGMENT},
         util::pack_segment,
     };
 
+    /// Minimal image with a valid setup header and 4 setup sectors
+    fn fake_bz_image(initrd_addr_max: u32, init_size: u32) -> Vec<u8> {
+        let mut image = vec![0; 0x4000];
+
+        // Boot protocol 2.15
+        image[0x206..0x208].copy_from_slice(&0x20fu16.to_le_bytes());
+        image[0x238..0x23c].copy_from_slice(&24u32.to_le_bytes());
+
+        image[0x1f1] = 4;
+        image[0x1fe..0x200].copy_from_slice(&0xAA55u16.to_le_bytes());
+        image[0x200..0x202].copy_from_slice(&[0xEB, 106]);
+        image[0x202..0x206].copy_from_slice(b"HdrS");
+        image[0x22c..0x230].copy_from_slice(&initrd_addr_max.to_le_bytes());
+        image[0x236..0x238].copy_from_slice(&3u16.to_le_bytes());
+        image[0x260..0x264].copy_from_slice(&init_size.to_le_bytes());
+
+        // Version string right after the header, in the setup code
+        image[0x20e..0x210].copy_from_slice(&0x100u16.to_le_bytes());
+        image[0x300..0x31d].copy_from_slice(b"6.8.6 (vmm@localhost) #1 SMP\0");
+
+        image
+    }
+
     #[test]
     fn pack_cs() {
         assert_eq!(
@@ -168,6 +568,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn pack_cs32() {
+        assert_eq!(
+            pack_segment(&CODE32_SEGMENT),
+            0b11001111100110100000000000000000000000001111111111111111
+        );
+    }
+
     #[test]
     fn pack_ds() {
         assert_eq!(
@@ -175,4 +583,176 @@ mod tests {
             0b11001111100100100000000000000000000000001111111111111111
         );
     }
+
+    #[test]
+    fn initramfs_placement() {
+        let image = fake_bz_image(0x7fffffff, 0x400000);
+        let mut loader = BzImage::new(&image, &[]).unwrap();
+
+        // Placed right below the end of RAM, page aligned
+        assert_eq!(
+            loader.initramfs_addr(0x100000, 0x1800, 0x8000000).unwrap(),
+            0x7ffe000
+        );
+        // Clamped to `initrd_addr_max`, even with RAM above it
+        assert_eq!(
+            loader.initramfs_addr(0x100000, 0x1000, 0xC0000000).unwrap(),
+            0x7ffff000
+        );
+        // Mustn't overlap with the memory the kernel needs to decompress itself
+        assert!(matches!(
+            loader.initramfs_addr(0x100000, 0x800000, 0x800000),
+            Err(LoaderError::InitramfsTooLarge)
+        ));
+
+        loader.set_initramfs(0x7ffe000, 0x1800);
+        assert_eq!({ loader.boot_params().hdr.ramdisk_image }, 0x7ffe000);
+    }
+
+    #[test]
+    fn cmdline_is_validated() {
+        let mut cmdline = Cmdline::new();
+
+        cmdline.push("console=ttyS0").unwrap();
+        cmdline.push("  ").unwrap();
+        cmdline.push("quiet ").unwrap();
+        assert_eq!(cmdline.to_bytes_with_nul(), b"console=ttyS0 quiet\0");
+        assert!(matches!(
+            cmdline.push("init=/bin/sh\0"),
+            Err(LoaderError::InvalidCmdline)
+        ));
+
+        let image = fake_bz_image(0x7fffffff, 0);
+        let mut loader = BzImage::new(&image, &[]).unwrap();
+
+        loader.set_cmdline(0x1_0002_0000, &cmdline).unwrap();
+        assert_eq!({ loader.boot_params().hdr.cmd_line_ptr }, 0x20000);
+        assert_eq!({ loader.boot_params().ext_cmd_line_ptr }, 1);
+
+        cmdline.push("panic=-1").unwrap();
+        assert!(matches!(
+            loader.set_cmdline(0x20000, &cmdline),
+            Err(LoaderError::CmdlineTooLong { len: 28, max: 24 })
+        ));
+        cmdline.check_len(28).unwrap();
+        assert!(matches!(
+            cmdline.check_len(27),
+            Err(LoaderError::CmdlineTooLong { len: 28, max: 27 })
+        ));
+    }
+
+    #[test]
+    fn load_addr_respects_header() {
+        let mut image = fake_bz_image(0x7fffffff, 0x800000);
+
+        // Relocatable with 2MiB alignment and a preferred load address of 16MiB
+        image[0x230..0x234].copy_from_slice(&0x200000u32.to_le_bytes());
+        image[0x234] = 1;
+        image[0x258..0x260].copy_from_slice(&0x1000000u64.to_le_bytes());
+
+        let loader = BzImage::new(&image, &[]).unwrap();
+
+        assert_eq!(loader.init_size(), 0x800000);
+        assert_eq!(loader.load_addr(0x40000000).unwrap(), 0x1000000);
+        // Falls back to the lowest aligned address when there's no room
+        assert_eq!(loader.load_addr(0x1000000).unwrap(), 0x200000);
+        assert!(matches!(
+            loader.load_addr(0x800000),
+            Err(LoaderError::KernelTooLarge { .. })
+        ));
+
+        image[0x234] = 0;
+        let loader = BzImage::new(&image, &[]).unwrap();
+
+        assert_eq!(loader.load_addr(0x40000000).unwrap(), 0x100000);
+
+        image[0x234] = 1;
+        image[0x230..0x234].copy_from_slice(&0x300000u32.to_le_bytes());
+        let loader = BzImage::new(&image, &[]).unwrap();
+
+        assert!(matches!(
+            loader.load_addr(0x40000000),
+            Err(LoaderError::InvalidAlignment(0x300000))
+        ));
+
+        // No 64-bit entry point
+        image[0x236..0x238].copy_from_slice(&0u16.to_le_bytes());
+
+        assert!(matches!(
+            BzImage::new(&image, &[]),
+            Err(LoaderError::No64BitEntry)
+        ));
+    }
+
+    #[test]
+    fn protocol_and_kernel_version() {
+        let mut image = fake_bz_image(0x7fffffff, 0);
+        let loader = BzImage::new(&image, &[]).unwrap();
+
+        assert_eq!(loader.protocol_version(), 0x20f);
+        assert_eq!(
+            loader.kernel_version(),
+            Some("6.8.6 (vmm@localhost) #1 SMP")
+        );
+
+        image[0x20e..0x210].copy_from_slice(&0u16.to_le_bytes());
+        let loader = BzImage::new(&image, &[]).unwrap();
+
+        assert_eq!(loader.kernel_version(), None);
+
+        image[0x206..0x208].copy_from_slice(&0x205u16.to_le_bytes());
+
+        assert!(matches!(
+            BzImage::new(&image, &[]),
+            Err(LoaderError::UnsupportedProtocol(0x205))
+        ));
+    }
+
+    #[test]
+    fn setup_data_is_chained() {
+        let image = fake_bz_image(0x7fffffff, 0);
+        let e820 = (0..130)
+            .map(|n| boot_e820_entry {
+                addr: n << 20,
+                size: 1 << 20,
+                type_: 1,
+            })
+            .collect::<Vec<_>>();
+
+        let mut loader = BzImage::new(&image, &e820).unwrap();
+        loader
+            .add_setup_data(SETUP_RNG_SEED, vec![0xAA; 3])
+            .unwrap();
+
+        let bytes = loader.link_setup_data(0x11000);
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        assert_eq!({ loader.boot_params().e820_entries }, 128);
+        assert_eq!({ loader.boot_params().hdr.setup_data }, 0x11000);
+
+        // The 2 extra E820 entries take 40 bytes, padded to 8
+        assert_eq!(u64_at(0), 0x11000 + 16 + 40);
+        assert_eq!(u32_at(8), SETUP_E820_EXT);
+        assert_eq!(u32_at(12), 40);
+        assert_eq!(u64_at(16), 128 << 20);
+
+        assert_eq!(u64_at(56), 0);
+        assert_eq!(u32_at(64), SETUP_RNG_SEED);
+        assert_eq!(u32_at(68), 3);
+        assert_eq!(&bytes[72..], [0xAA, 0xAA, 0xAA, 0, 0, 0, 0, 0]);
+
+        let mut image = image;
+        image[0x206..0x208].copy_from_slice(&0x208u16.to_le_bytes());
+        let mut loader = BzImage::new(&image, &[]).unwrap();
+
+        assert!(matches!(
+            loader.add_setup_data(SETUP_RNG_SEED, vec![0; 32]),
+            Err(LoaderError::SetupDataUnsupported)
+        ));
+        assert!(loader.link_setup_data(0x11000).is_empty());
+        assert_eq!({ loader.boot_params().hdr.setup_data }, 0);
+    }
 }
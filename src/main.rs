@@ -1,180 +1,838 @@
-use kvm_bindings::{KVM_EXIT_DEBUG, KVM_EXIT_HLT, KVM_EXIT_IO};
-use nix::sys::{mman, mman::MapFlags, mman::ProtFlags};
-use std::{env, fs::File, io::Read, num::NonZeroUsize, os::fd::BorrowedFd, slice};
+use kvm_bindings::{
+    kvm_regs, kvm_sregs, KVM_SYSTEM_EVENT_CRASH, KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN,
+};
+use nix::{
+    fcntl::{self, FcntlArg, OFlag},
+    poll::{self, PollFd, PollFlags},
+    pty,
+    sys::termios::{self, SetArg, Termios},
+    unistd,
+};
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    io::{self, Read, Write},
+    net::TcpListener,
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, IntoRawFd},
+        unix::{
+            fs::OpenOptionsExt,
+            net::{UnixListener, UnixStream},
+            thread::JoinHandleExt,
+        },
+    },
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    thread,
+};
 use vmm::{
-    bootparam::boot_e820_entry, kvm::Kvm, linux_loader::BzImage, util, util::WrappedAutoFree,
+    acpi,
+    bootparam::SETUP_RNG_SEED,
+    bus::Bus,
+    cli::{Args, CliError, GdbAddress, RebootPolicy, SerialBackend, USAGE},
+    constants::PageTables,
+    devices::{
+        acpi_pm::{self, AcpiPm},
+        debug_exit::{self, DebugExit},
+        i8042::{self, I8042},
+        serial::{self, Serial},
+        virtio_blk::VirtioBlk,
+        virtio_mmio::{self, VirtioMmio},
+        Interrupt,
+    },
+    disk,
+    e820::E820Table,
+    gdb::{GdbServer, GdbStream, VcpuDebugger},
+    kvm::{self, Vcpu, VmBuilder, VmExit},
+    linux_loader::{BzImage, Cmdline, LoaderError},
+    memory::{GuestMemory, MMIO_GAP_START},
+    mptable::{self, MpTableError},
+    paging,
+    power::{PowerControl, VmExitStatus},
+    pvh::{self, PvhImage},
+    util,
+    util::WrappedAutoFree,
+    virtio::{VirtioDevice, VirtioInterrupt},
 };
 
-const MAPPING_SIZE: usize = 1 << 30;
-
-const CMDLINE: &[u8] = b"console=ttyS0 earlyprintk=ttyS0 rdinit=/init\0";
-
 const ADDR_BOOT_PARAMS: usize = 0x10000;
+/// Right after the boot params, till the command line
+const ADDR_SETUP_DATA: usize = 0x11000;
+/// Takes the place of the boot params when booting through PVH
+const ADDR_PVH_START_INFO: usize = ADDR_BOOT_PARAMS;
 const ADDR_CMDLINE: usize = 0x20000;
-const ADDR_KERNEL32: usize = 0x100000;
-const ADDR_INITRAMFS: usize = 0xf000000;
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let kvm = Kvm::new()?;
-    let mut bz_image = Vec::new();
-
-    File::open(env::args().nth(1).expect("no bzImage passed!"))
-        .expect("failed to open bzImage!")
-        .read_to_end(&mut bz_image)
-        .expect("failed to read!");
-
-    let mut initramfs = Vec::new();
-
-    File::open(env::args().nth(2).expect("no initramfs passed!"))
-        .expect("failed to open initramfs")
-        .read_to_end(&mut initramfs)
-        .expect("failed to read!");
-
-    let loader = BzImage::new(
-        &bz_image,
-        ADDR_CMDLINE.try_into().expect("cmdline address too large!"),
-        Some(
-            ADDR_INITRAMFS
+
+/// Size of the entropy seed passed through setup_data
+const RNG_SEED_SIZE: usize = 32;
+
+/// Ctrl-A, followed by `x` to exit
+const ESCAPE_KEY: u8 = 0x01;
+
+/// Host side of the serial port, which input is read from
+enum SerialInput {
+    Stdio,
+    /// Non-blocking master side of the pty, along with the slave side which
+    /// is kept open so that the master doesn't hang up when clients detach
+    Pty(File, File),
+    Socket(UnixListener, SocketWriter),
+}
+
+/// Where GDB connects to, see `--gdb`
+enum GdbListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl GdbListener {
+    fn bind(addr: &GdbAddress) -> Result<Self, Box<dyn Error>> {
+        let listener = match addr {
+            GdbAddress::Tcp(addr) => TcpListener::bind(addr).map(Self::Tcp),
+            GdbAddress::Socket(path) => UnixListener::bind(path).map(Self::Unix),
+        };
+
+        Ok(listener.map_err(|err| format!("failed to bind to {addr}: {err}"))?)
+    }
+
+    /// Wait for GDB to connect, giving up with `None` if the VM is stopped in
+    /// the meantime
+    fn accept(&self, power: &PowerControl) -> io::Result<Option<GdbStream>> {
+        loop {
+            if power.status().is_some() {
+                return Ok(None);
+            }
+
+            let fd = match self {
+                Self::Tcp(listener) => listener.as_fd(),
+                Self::Unix(listener) => listener.as_fd(),
+            };
+
+            match poll::poll(&mut [PollFd::new(&fd, PollFlags::POLLIN)], 100) {
+                Ok(0) | Err(nix::Error::EINTR) => continue,
+                Ok(_) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Some(match self {
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+
+                // Packets are small and exchanged one at a time
+                stream.set_nodelay(true)?;
+                GdbStream::Tcp(stream)
+            }
+            Self::Unix(listener) => GdbStream::Unix(listener.accept()?.0),
+        }))
+    }
+}
+
+/// Forwards output to the currently connected client, if any
+#[derive(Clone, Default)]
+struct SocketWriter(Arc<Mutex<Option<UnixStream>>>);
+
+impl Write for SocketWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stream = self.0.lock().unwrap();
+
+        if let Some(client) = stream.as_mut() {
+            if client.write_all(buf).is_err() {
+                *stream = None;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Drops output instead of blocking when nobody is reading the other end
+struct NonBlockingWriter(File);
+
+impl Write for NonBlockingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.write(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(CliError::Help) => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("vmm: {err}\nTry 'vmm --help' for more information");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(status) => ExitCode::from(status.exit_code()),
+        Err(err) => {
+            eprintln!("vmm: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<VmExitStatus, Box<dyn Error>> {
+    kvm::install_kick_handler()?;
+
+    // The serial port outlives the VM, so that the host side stays connected
+    // across reboots
+    let interrupt = Interrupt::new()?;
+    let (output, input) = open_serial_backend(&args.serial)?;
+    let serial = Arc::new(Mutex::new(Serial::new(interrupt.try_clone()?, output)));
+
+    // Removes the socket when dropped
+    let _socket = match &args.serial {
+        SerialBackend::Socket(path) => Some(WrappedAutoFree::new(path.clone(), |path| {
+            let _ = fs::remove_file(path);
+        })),
+        _ => None,
+    };
+
+    // GDB stays connected to the same address across reboots
+    let gdb = args.gdb.as_ref().map(GdbListener::bind).transpose()?;
+
+    let _gdb_socket = match &args.gdb {
+        Some(GdbAddress::Socket(path)) => Some(WrappedAutoFree::new(path.clone(), |path| {
+            let _ = fs::remove_file(path);
+        })),
+        _ => None,
+    };
+
+    // Restores the terminal when dropped
+    let _terminal = match input {
+        Some(SerialInput::Stdio) => setup_terminal()?,
+        _ => None,
+    };
+
+    // Outlives the VM too, so that Ctrl-A x also stops it while rebooting
+    let power = PowerControl::new();
+
+    if let Some(input) = input {
+        let (serial, power) = (serial.clone(), power.clone());
+
+        thread::Builder::new()
+            .name("serial-input".to_string())
+            .spawn(move || match input {
+                SerialInput::Stdio => forward_input(&serial, &power),
+                SerialInput::Pty(master, _slave) => {
+                    if let Err(err) = forward_raw(master, &serial) {
+                        eprintln!("vmm: failed to read from pty: {err}");
+                    }
+                }
+                SerialInput::Socket(listener, writer) => {
+                    forward_socket(&listener, &writer, &serial)
+                }
+            })?;
+    }
+
+    loop {
+        match boot(args, &serial, &interrupt, &power, gdb.as_ref())? {
+            VmExitStatus::Reboot if args.reboot == RebootPolicy::Restart => {
+                eprintln!("vmm: guest rebooted, restarting");
+                serial.lock().unwrap().reset();
+                power.reset();
+            }
+            status => return Ok(status),
+        }
+    }
+}
+
+/// Create a VM with the kernel loaded into it and run it till it stops
+fn boot(
+    args: &Args,
+    serial: &Arc<Mutex<Serial>>,
+    interrupt: &Interrupt,
+    power: &PowerControl,
+    gdb: Option<&GdbListener>,
+) -> Result<VmExitStatus, Box<dyn Error>> {
+    let (kvm, vcpus) = VmBuilder::new().vcpus(args.cpus).build()?;
+    let memory = Arc::new(GuestMemory::with_ram(args.memory)?);
+
+    let kernel = fs::read(&args.kernel)
+        .map_err(|err| format!("failed to read {}: {err}", args.kernel.display()))?;
+
+    let initramfs = args
+        .initrd
+        .as_ref()
+        .map(|path| {
+            fs::read(path).map_err(|err| format!("failed to read {}: {err}", path.display()))
+        })
+        .transpose()?;
+
+    if args.disks.len() > virtio_mmio::MAX_DEVICES {
+        return Err(format!(
+            "too many virtio devices, at most {} are supported",
+            virtio_mmio::MAX_DEVICES
+        )
+        .into());
+    }
+
+    let mut virtio_devices: Vec<Box<dyn VirtioDevice>> = Vec::new();
+
+    for (n, disk) in args.disks.iter().enumerate() {
+        let image = disk::open(&disk.path, disk.read_only)
+            .map_err(|err| format!("failed to open {}: {err}", disk.path.display()))?;
+
+        let blk = VirtioBlk::new(image)
+            .read_only(disk.read_only)
+            .block_size(disk.block_size)?
+            .packed_ring(disk.packed)
+            .id(&format!("vmm-disk{n}"));
+
+        virtio_devices.push(Box::new(blk));
+    }
+
+    // Paravirtual devices, found by the guest through the command line. They
+    // go before the user's parameters, as anything after `--` is for init
+    let mut cmdline = Cmdline::new();
+    let mut mmio_bus = Bus::new();
+
+    for (n, device) in virtio_devices.into_iter().enumerate() {
+        let base = virtio_mmio::MMIO_BASE + n as u64 * virtio_mmio::MMIO_SIZE;
+        let irq = virtio_mmio::IRQ_BASE + n as u32;
+        let interrupt = Interrupt::new()?;
+
+        kvm.register_irqfd(&interrupt, irq)?;
+        mmio_bus.insert(
+            Arc::new(Mutex::new(VirtioMmio::new(
+                device,
+                memory.clone(),
+                VirtioInterrupt::new(interrupt),
+            ))),
+            base,
+            virtio_mmio::MMIO_SIZE,
+        )?;
+        cmdline.push(&virtio_mmio::cmdline_param(base, irq))?;
+    }
+
+    cmdline.push(&args.cmdline)?;
+
+    let rsdp_addr = acpi::setup_acpi(
+        &memory,
+        args.cpus,
+        &[serial::aml(0, serial::COM1_PORT, serial::COM1_IRQ)],
+    )?;
+
+    let (regs, sregs) = if pvh::is_elf(&kernel) {
+        load_pvh(&memory, &kernel, initramfs.as_deref(), &cmdline, rsdp_addr)?
+    } else {
+        load_bz_image(&memory, &kernel, initramfs.as_deref(), &cmdline, rsdp_addr)?
+    };
+
+    util::setup_gdt(&memory)?;
+    util::setup_paging(&memory)?;
+
+    // The MADT describes all the vCPUs, guests without ACPI only get to see
+    // them through the MP table, which has little room
+    match mptable::setup_mptable(&memory, args.cpus) {
+        Err(MpTableError::TooLarge) => {
+            eprintln!("vmm: too many vCPUs for the MP table, the guest needs ACPI to find them")
+        }
+        result => result?,
+    }
+
+    for region in memory.regions() {
+        kvm.register_region(region)?;
+    }
+
+    let cpuid = kvm.supported_cpuid()?;
+
+    for vcpu in &vcpus {
+        vcpu.setup_cpuid(&cpuid)?;
+
+        // Only the BSP starts running the kernel, the APs are woken up
+        // by the kernel through INIT-SIPI-SIPI, handled by the in-kernel LAPIC
+        if vcpu.id() == 0 {
+            vcpu.set_vcpu_regs(&regs)?;
+            vcpu.set_vcpu_sregs(&sregs)?;
+        }
+    }
+
+    kvm.register_irqfd(interrupt, serial::COM1_IRQ)?;
+
+    let mut pio_bus = Bus::new();
+
+    pio_bus.insert(
+        serial.clone(),
+        serial::COM1_PORT.into(),
+        serial::PORT_COUNT.into(),
+    )?;
+    pio_bus.insert(
+        Arc::new(Mutex::new(I8042::new(power.clone()))),
+        i8042::DATA_PORT.into(),
+        i8042::PORT_COUNT.into(),
+    )?;
+    pio_bus.insert(
+        Arc::new(Mutex::new(AcpiPm::new(power.clone()))),
+        acpi_pm::SLEEP_CONTROL_PORT.into(),
+        acpi_pm::PORT_COUNT.into(),
+    )?;
+
+    if let Some(port) = args.debug_exit {
+        pio_bus.insert(
+            Arc::new(Mutex::new(DebugExit::new(power.clone()))),
+            port.into(),
+            debug_exit::PORT_COUNT.into(),
+        )?;
+    }
+
+    let pio_bus = Arc::new(pio_bus);
+    let mmio_bus = Arc::new(mmio_bus);
+    let verbose = args.verbose;
+
+    // The vCPUs wait for GDB before running
+    let (gdb, debuggers) = match gdb {
+        Some(listener) => {
+            eprintln!(
+                "vmm: waiting for GDB to connect to {}",
+                args.gdb.as_ref().expect("listening without an address")
+            );
+
+            let Some(stream) = listener.accept(power)? else {
+                return Ok(power.wait());
+            };
+            let (server, debuggers) = GdbServer::new(
+                memory.clone(),
+                paging::phys_bits(&cpuid),
+                power.clone(),
+                vcpus.len(),
+            );
+
+            (
+                Some((server, stream)),
+                debuggers.into_iter().map(Some).collect(),
+            )
+        }
+        None => (None, vcpus.iter().map(|_| None).collect::<Vec<_>>()),
+    };
+
+    let threads = vcpus
+        .into_iter()
+        .zip(debuggers)
+        .map(|(vcpu, debugger)| {
+            let (pio_bus, mmio_bus, power) = (pio_bus.clone(), mmio_bus.clone(), power.clone());
+            let handle = vcpu.handle();
+
+            thread::Builder::new()
+                .name(format!("vcpu{}", vcpu.id()))
+                .spawn(move || {
+                    let result = run_vcpu(vcpu, &pio_bus, &mmio_bus, &power, verbose, debugger);
+
+                    // Don't leave the other vCPUs running without us
+                    if result.is_err() {
+                        power.request(VmExitStatus::Crash);
+                    }
+
+                    result
+                })
+                .map(|thread| (thread, handle))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let debugger = gdb
+        .map(|(server, stream)| {
+            let vcpus = threads
+                .iter()
+                .map(|(thread, handle)| (handle.clone(), thread.as_pthread_t()))
+                .collect();
+
+            server.spawn(stream, vcpus)
+        })
+        .transpose()?;
+
+    let status = power.wait();
+
+    for (thread, handle) in &threads {
+        handle.kick(thread.as_pthread_t())?;
+    }
+
+    if let Some(debugger) = debugger {
+        debugger.stop(status);
+    }
+
+    for (thread, _) in threads {
+        thread.join().map_err(|_| "vCPU thread panicked")??;
+    }
+
+    Ok(status)
+}
+
+/// Load a bzImage along with its boot parameters, the kernel is entered
+/// through the 64-bit boot protocol
+fn load_bz_image(
+    memory: &GuestMemory,
+    bz_image: &[u8],
+    initramfs: Option<&[u8]>,
+    cmdline: &Cmdline,
+    rsdp_addr: u64,
+) -> Result<(kvm_regs, kvm_sregs), Box<dyn Error>> {
+    let mut loader = BzImage::new(bz_image, E820Table::from_memory(memory).entries())?;
+    let version = loader.protocol_version();
+
+    eprintln!(
+        "vmm: booting Linux {} (protocol {}.{:02})",
+        loader
+            .kernel_version()
+            .and_then(|version| version.split_whitespace().next())
+            .unwrap_or("(unknown version)"),
+        version >> 8,
+        version & 0xFF
+    );
+    let ram_end = memory.end().min(MMIO_GAP_START);
+    let kernel_addr = loader.load_addr(ram_end)?;
+
+    if let Some(initramfs) = initramfs {
+        let size = initramfs
+            .len()
+            .try_into()
+            .map_err(|_| LoaderError::InitramfsTooLarge)?;
+        let addr = loader.initramfs_addr(kernel_addr, size, ram_end)?;
+
+        memory.write_slice(addr.into(), initramfs)?;
+        loader.set_initramfs(addr, size);
+    }
+
+    loader.set_cmdline(ADDR_CMDLINE as u64, cmdline)?;
+    loader.set_acpi_rsdp_addr(rsdp_addr);
+
+    // Seed the kernel's RNG early, older kernels ignore it
+    if loader.protocol_version() >= 0x209 {
+        let mut seed = vec![0; RNG_SEED_SIZE];
+
+        File::open("/dev/urandom")?.read_exact(&mut seed)?;
+        loader.add_setup_data(SETUP_RNG_SEED, seed)?;
+    }
+
+    let setup_data = loader.link_setup_data(ADDR_SETUP_DATA as u64);
+
+    if setup_data.len() > ADDR_CMDLINE - ADDR_SETUP_DATA {
+        return Err(LoaderError::SetupDataTooLarge.into());
+    }
+
+    memory.write_slice(ADDR_SETUP_DATA as u64, &setup_data)?;
+
+    memory.write_obj(ADDR_BOOT_PARAMS as u64, &loader.boot_params())?;
+    memory.write_slice(kernel_addr, loader.kernel32_slice())?;
+    memory.write_slice(ADDR_CMDLINE as u64, &cmdline.to_bytes_with_nul())?;
+
+    Ok((
+        util::setup_regs(
+            // 64-bit code is located 512 bytes ahead of the 32-bit code
+            kernel_addr + 0x200,
+            // boot params are stored in rsi
+            ADDR_BOOT_PARAMS as u64,
+        ),
+        util::setup_sregs(),
+    ))
+}
+
+/// Load the segments of an ELF vmlinux along with `hvm_start_info`, the
+/// kernel is entered through its 32-bit PVH entry point
+fn load_pvh(
+    memory: &GuestMemory,
+    vmlinux: &[u8],
+    initramfs: Option<&[u8]>,
+    cmdline: &Cmdline,
+    rsdp_addr: u64,
+) -> Result<(kvm_regs, kvm_sregs), Box<dyn Error>> {
+    let image = PvhImage::new(vmlinux)?;
+
+    image.load(memory)?;
+
+    let initramfs = initramfs
+        .map(|initramfs| -> Result<_, Box<dyn Error>> {
+            let size = initramfs
+                .len()
                 .try_into()
-                .expect("initramfs address too large!"),
+                .map_err(|_| LoaderError::InitramfsTooLarge)?;
+            let addr = image.initramfs_addr(size, memory.end().min(MMIO_GAP_START))?;
+
+            memory.write_slice(addr.into(), initramfs)?;
+
+            Ok((addr.into(), size.into()))
+        })
+        .transpose()?;
+
+    // PVH doesn't have a limit of its own, but the page directories come next
+    cmdline.check_len(PageTables::PD - ADDR_CMDLINE - 1)?;
+    memory.write_slice(ADDR_CMDLINE as u64, &cmdline.to_bytes_with_nul())?;
+
+    pvh::setup_start_info(
+        memory,
+        ADDR_PVH_START_INFO as u64,
+        ADDR_CMDLINE as u64,
+        rsdp_addr,
+        initramfs,
+        E820Table::from_memory(memory).entries(),
+    )?;
+
+    Ok((
+        util::setup_pvh_regs(image.entry(), ADDR_PVH_START_INFO as u64),
+        util::setup_pvh_sregs(),
+    ))
+}
+
+/// Open the host side of the serial port, returning where the guest's output
+/// goes and where its input comes from, if anywhere
+#[allow(clippy::type_complexity)]
+fn open_serial_backend(
+    backend: &SerialBackend,
+) -> Result<(Box<dyn Write + Send>, Option<SerialInput>), Box<dyn Error>> {
+    Ok(match backend {
+        SerialBackend::Stdio => (Box::new(io::stdout()), Some(SerialInput::Stdio)),
+        SerialBackend::File(path) => (
+            Box::new(
+                File::create(path)
+                    .map_err(|err| format!("failed to create {}: {err}", path.display()))?,
+            ),
+            None,
         ),
-        Some(initramfs.len().try_into().expect("initramfs too big")),
-        &[
-            // Memory before the EBDA entry
-            boot_e820_entry {
-                addr: 0,
-                size: 0x9fc00,
-                // E820_RAM
-                type_: 1,
-            },
-            // Reserved EBDA entry
-            boot_e820_entry {
-                addr: 0x9fc00,
-                size: 1 << 10,
-                // E820_RESERVED,
-                type_: 2,
-            },
-            // Memory after the beginning of the kernel image
-            boot_e820_entry {
-                addr: 0x100000,
-                size: MAPPING_SIZE as u64 - 0x100000,
-                type_: 1,
-            },
-        ],
-    )
-    .expect("failed to construct loader!");
-
-    // Create a mapping for the "user" memory region where we'll copy the
-    // startup code into
-    let wrapped_mapping = WrappedAutoFree::new(
-        unsafe {
-            mman::mmap(
-                None,
-                NonZeroUsize::new(MAPPING_SIZE).expect("unreachable, passed > 0"),
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_ANONYMOUS | MapFlags::MAP_SHARED,
-                None::<BorrowedFd>,
-                0,
-            )?
-        },
-        |map| unsafe {
-            mman::munmap(map, MAPPING_SIZE).expect("failed to unmap user memory region!");
-        },
-    );
+        SerialBackend::Pty => {
+            let master = pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY)?;
+
+            pty::grantpt(&master)?;
+            pty::unlockpt(&master)?;
+
+            let path = pty::ptsname_r(&master)?;
+            let slave = File::options()
+                .read(true)
+                .write(true)
+                .custom_flags(OFlag::O_NOCTTY.bits())
+                .open(&path)?;
+
+            // Don't echo the input back or mangle newlines in the output
+            let mut raw = termios::tcgetattr(&slave)?;
+            termios::cfmakeraw(&mut raw);
+            termios::tcsetattr(&slave, SetArg::TCSANOW, &raw)?;
 
-    let mapped_slice = unsafe { slice::from_raw_parts_mut(*wrapped_mapping as _, MAPPING_SIZE) };
-
-    unsafe {
-        std::ptr::copy_nonoverlapping(
-            &loader.boot_params(),
-            wrapped_mapping.add(ADDR_BOOT_PARAMS) as *mut _,
-            1,
-        );
-        let kernel32 = loader.kernel32_slice();
-        std::ptr::copy_nonoverlapping(
-            kernel32.as_ptr(),
-            wrapped_mapping.add(ADDR_KERNEL32) as *mut _,
-            kernel32.len(),
-        );
-        std::ptr::copy_nonoverlapping(
-            CMDLINE.as_ptr(),
-            wrapped_mapping.add(ADDR_CMDLINE) as *mut _,
-            CMDLINE.len(),
-        );
-        std::ptr::copy_nonoverlapping(
-            initramfs.as_ptr(),
-            wrapped_mapping.add(ADDR_INITRAMFS) as *mut _,
-            initramfs.len(),
-        );
-    }
-
-    util::setup_gdt(mapped_slice);
-    util::setup_paging(mapped_slice);
-
-    kvm.set_user_memory_region(0x0, MAPPING_SIZE as u64, *wrapped_mapping as u64)?;
-    kvm.set_vcpu_regs(&util::setup_regs(
-        // 64-bit code is located 512 bytes ahead of the 32-bit code
-        ADDR_KERNEL32 as u64 + 0x200,
-        // boot params are stored in rsi
-        ADDR_BOOT_PARAMS as u64,
-    ))?;
-    kvm.set_vcpu_sregs(&util::setup_sregs())?;
-    kvm.set_tss_addr(0xFFFFD000)?;
-    kvm.setup_cpuid()?;
-
-    let mut buffer = String::new();
+            fcntl::fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+            let master = unsafe { File::from_raw_fd(master.into_raw_fd()) };
+
+            eprintln!("vmm: serial port is connected to {path}");
+
+            (
+                Box::new(NonBlockingWriter(master.try_clone()?)),
+                Some(SerialInput::Pty(master, slave)),
+            )
+        }
+        SerialBackend::Socket(path) => {
+            let listener = UnixListener::bind(path)
+                .map_err(|err| format!("failed to bind to {}: {err}", path.display()))?;
+            let writer = SocketWriter::default();
+
+            (
+                Box::new(writer.clone()),
+                Some(SerialInput::Socket(listener, writer)),
+            )
+        }
+        SerialBackend::None => (Box::new(io::sink()), None),
+    })
+}
+
+/// Put the terminal in raw mode so that every key press is passed through
+/// to the guest, if stdin is a terminal at all
+#[allow(clippy::type_complexity)]
+fn setup_terminal() -> Result<Option<WrappedAutoFree<Termios, fn(Termios)>>, nix::Error> {
+    if !unistd::isatty(io::stdin().as_raw_fd())? {
+        return Ok(None);
+    }
+
+    let original = termios::tcgetattr(io::stdin())?;
+    let mut raw = original.clone();
+
+    termios::cfmakeraw(&mut raw);
+    termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &raw)?;
+
+    Ok(Some(WrappedAutoFree::new(original, |original| {
+        let _ = termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &original);
+    })))
+}
+
+/// Feed stdin into the serial port, stopping the VM on Ctrl-A x
+fn forward_input(serial: &Mutex<Serial>, power: &PowerControl) {
+    let mut stdin = io::stdin().lock();
+    let mut buf = [0; 64];
+    let mut escaped = false;
 
     loop {
-        let kvm_run = kvm.run()?;
+        let len = match stdin.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                eprintln!("failed to read from stdin: {err}");
+                return;
+            }
+        };
+
+        let mut input = Vec::with_capacity(len);
 
-        unsafe {
-            match (*kvm_run).exit_reason {
-                KVM_EXIT_HLT => {
-                    eprintln!("KVM_EXIT_HLT");
-                    break;
+        for &byte in &buf[..len] {
+            match (escaped, byte) {
+                (true, b'x') => {
+                    power.request(VmExitStatus::Killed);
+                    return;
                 }
-                KVM_EXIT_DEBUG => {
-                    eprintln!(
-                        "{:#?}\n{:#?}",
-                        kvm.get_vcpu_regs(),
-                        (*kvm_run).__bindgen_anon_1.debug
-                    );
+                // Pressing the escape key twice sends it to the guest
+                (true, ESCAPE_KEY) => input.push(ESCAPE_KEY),
+                (true, byte) => input.extend([ESCAPE_KEY, byte]),
+                (false, ESCAPE_KEY) => {
+                    escaped = true;
+                    continue;
                 }
-                // TODO abstract out this struct so we don't have to write hacky
-                // C-style code here
-                KVM_EXIT_IO => {
-                    let port = (*kvm_run).__bindgen_anon_1.io.port;
-                    let byte = *((kvm_run as u64 + (*kvm_run).__bindgen_anon_1.io.data_offset)
-                        as *const u8);
-
-                    if port == 0x3f8 {
-                        match byte {
-                            b'\r' | b'\n' => {
-                                println!("{buffer}");
-                                buffer.clear();
-                            }
-                            c => {
-                                buffer.push(c as char);
-                            }
-                        }
-                    }
+                (false, byte) => input.push(byte),
+            }
+
+            escaped = false;
+        }
 
-                    eprintln!("IO for port {port}: {byte:#X}");
+        serial.lock().unwrap().enqueue_input(&input);
+    }
+}
+
+/// Feed input into the serial port as-is till EOF
+fn forward_raw(mut input: impl Read + AsFd, serial: &Mutex<Serial>) -> io::Result<()> {
+    let mut buf = [0; 64];
+
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => serial.lock().unwrap().enqueue_input(&buf[..len]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                poll::poll(&mut [PollFd::new(&input, PollFlags::POLLIN)], -1)?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Accept clients one at a time, connecting each of them to the serial port
+fn forward_socket(listener: &UnixListener, writer: &SocketWriter, serial: &Mutex<Serial>) {
+    for client in listener.incoming() {
+        let result = client.and_then(|client| {
+            *writer.0.lock().unwrap() = Some(client.try_clone()?);
+            forward_raw(client, serial)
+        });
+
+        *writer.0.lock().unwrap() = None;
 
-                    // `in` instruction, tell it that we're ready to receive data (XMTRDY)
-                    // arch/x86/boot/tty.c
-                    if (*kvm_run).__bindgen_anon_1.io.direction == 0 {
-                        *((kvm_run as *mut u8)
-                            .add((*kvm_run).__bindgen_anon_1.io.data_offset as usize)) = 0x20;
+        if let Err(err) = result {
+            eprintln!("vmm: serial socket client error: {err}");
+        }
+    }
+}
+
+/// Run the vCPU till the VM is asked to stop, by this vCPU or anything else
+fn run_vcpu(
+    mut vcpu: Vcpu,
+    pio_bus: &Bus,
+    mmio_bus: &Bus,
+    power: &PowerControl,
+    verbose: bool,
+    debugger: Option<VcpuDebugger>,
+) -> Result<(), std::io::Error> {
+    let id = vcpu.id();
+
+    if let Some(debugger) = &debugger {
+        debugger.wait_for_debugger(&mut vcpu)?;
+    }
+
+    loop {
+        let status = match vcpu.run()? {
+            // Nothing can wake the vCPU up, only seen without an irqchip
+            VmExit::Hlt => VmExitStatus::PowerOff,
+            VmExit::Debug(debug) => {
+                match &debugger {
+                    Some(debugger) => debugger.debug_exit(&mut vcpu, debug)?,
+                    None => eprintln!("vCPU {id}: unexpected debug exit: {debug:?}"),
+                }
+                continue;
+            }
+            VmExit::IoOut { port, size, data } => {
+                // String instructions perform multiple accesses at once
+                for chunk in data.chunks(size.into()) {
+                    if !pio_bus.write(port.into(), chunk) && verbose {
+                        eprintln!("unhandled write to port {port:#x}: {chunk:x?}");
                     }
                 }
-                reason => {
-                    eprintln!("Unhandled exit reason: {reason}");
-                    break;
+                continue;
+            }
+            VmExit::IoIn { port, size, data } => {
+                for chunk in data.chunks_mut(size.into()) {
+                    if !pio_bus.read(port.into(), chunk) {
+                        if verbose {
+                            eprintln!("unhandled read from port {port:#x}");
+                        }
+
+                        // Nothing is connected, the bus floats high
+                        chunk.fill(0xFF);
+                    }
                 }
+                continue;
             }
-        }
-    }
+            VmExit::MmioWrite { addr, data } => {
+                if !mmio_bus.write(addr, data) && verbose {
+                    eprintln!("unhandled MMIO write to {addr:#x}: {data:x?}");
+                }
+                continue;
+            }
+            VmExit::MmioRead { addr, data } => {
+                if !mmio_bus.read(addr, data) {
+                    if verbose {
+                        eprintln!("unhandled MMIO read from {addr:#x}");
+                    }
 
-    Ok(())
+                    data.fill(0xFF);
+                }
+                continue;
+            }
+            // Kicked because the VM is stopping, by GDB, or a stray signal
+            VmExit::Intr if power.status().is_some() => return Ok(()),
+            VmExit::Intr => {
+                if let Some(debugger) = &debugger {
+                    debugger.interrupted(&mut vcpu)?;
+                }
+                continue;
+            }
+            VmExit::Shutdown => {
+                eprintln!("vCPU {id}: triple fault");
+                VmExitStatus::Crash
+            }
+            VmExit::SystemEvent { type_, .. } => match type_ {
+                KVM_SYSTEM_EVENT_SHUTDOWN => VmExitStatus::PowerOff,
+                KVM_SYSTEM_EVENT_RESET => VmExitStatus::Reboot,
+                KVM_SYSTEM_EVENT_CRASH => VmExitStatus::Crash,
+                type_ => {
+                    eprintln!("vCPU {id}: unhandled system event {type_}");
+                    VmExitStatus::Crash
+                }
+            },
+            exit => {
+                eprintln!("vCPU {id}: unhandled exit reason: {exit:?}");
+                VmExitStatus::Crash
+            }
+        };
+
+        power.request(status);
+
+        return Ok(());
+    }
 }
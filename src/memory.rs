@@ -0,0 +1,287 @@
+use crate::util::{ByteValued, WrappedAutoFree};
+use nix::{
+    errno::Errno,
+    libc,
+    sys::{mman, mman::MapFlags, mman::ProtFlags},
+};
+use std::{ffi::c_void, fmt, mem, num::NonZeroUsize, os::fd::BorrowedFd, ptr};
+
+/// Start of the 32-bit MMIO gap, RAM that doesn't fit below it is placed at 4GiB
+pub const MMIO_GAP_START: u64 = 0xC0000000;
+/// End of the 32-bit MMIO gap
+pub const MMIO_GAP_END: u64 = 1 << 32;
+
+#[derive(Debug)]
+pub enum MemoryError {
+    /// Regions must have a non-zero, page aligned size and base
+    InvalidRegion,
+    /// The region overlaps with an existing one
+    Overlap,
+    /// The access isn't entirely contained in a single region
+    InvalidAddress(u64),
+    /// Failed to allocate the backing memory
+    Mmap(Errno),
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRegion => write!(f, "invalid memory region"),
+            Self::Overlap => write!(f, "memory region overlaps with an existing one"),
+            Self::InvalidAddress(addr) => write!(f, "invalid guest address {addr:#x}"),
+            Self::Mmap(err) => write!(f, "failed to allocate guest memory: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+/// A contiguous chunk of guest physical memory, backed by an anonymous mapping
+pub struct GuestRegion {
+    slot: u32,
+    guest_base: u64,
+    size: usize,
+    flags: u32,
+    mapping: WrappedAutoFree<*mut c_void, Box<dyn FnOnce(*mut c_void) + Send>>,
+}
+
+impl GuestRegion {
+    /// KVM memory slot of the region
+    pub fn slot(&self) -> u32 {
+        self.slot
+    }
+
+    pub fn guest_base(&self) -> u64 {
+        self.guest_base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// `KVM_MEM_*` flags, such as `KVM_MEM_READONLY` or `KVM_MEM_LOG_DIRTY_PAGES`
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Address of the backing memory in our address space
+    pub fn host_address(&self) -> u64 {
+        *self.mapping as u64
+    }
+
+    /// Guest physical address right after the end of the region
+    pub fn guest_end(&self) -> u64 {
+        self.guest_base + self.size as u64
+    }
+
+    fn contains(&self, addr: u64, len: usize) -> bool {
+        addr >= self.guest_base
+            && addr
+                .checked_add(len as u64)
+                .is_some_and(|end| end <= self.guest_end())
+    }
+}
+
+/// All of the guest's physical memory, made up of multiple regions
+#[derive(Default)]
+pub struct GuestMemory {
+    regions: Vec<GuestRegion>,
+}
+
+// The mappings are owned by us and only ever accessed through raw copies, the
+// guest itself may modify them at any point anyways
+unsafe impl Send for GuestMemory {}
+unsafe impl Sync for GuestMemory {}
+
+impl GuestMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create `size` bytes of RAM, leaving a hole for MMIO right below 4GiB
+    pub fn with_ram(size: u64) -> Result<Self, MemoryError> {
+        let mut memory = Self::new();
+
+        let low_size = size.min(MMIO_GAP_START);
+        memory.add_region(0, low_size, 0)?;
+
+        if size > low_size {
+            memory.add_region(MMIO_GAP_END, size - low_size, 0)?;
+        }
+
+        Ok(memory)
+    }
+
+    /// Add a region at `guest_base` spanning `size` bytes, backed by a new
+    /// anonymous mapping, the region is assigned the next free slot
+    pub fn add_region(
+        &mut self,
+        guest_base: u64,
+        size: u64,
+        flags: u32,
+    ) -> Result<(), MemoryError> {
+        let page_size = 0x1000;
+
+        if size == 0 || (size | guest_base) & (page_size - 1) != 0 {
+            return Err(MemoryError::InvalidRegion);
+        }
+
+        let guest_end = guest_base
+            .checked_add(size)
+            .ok_or(MemoryError::InvalidRegion)?;
+
+        if self
+            .regions
+            .iter()
+            .any(|region| guest_base < region.guest_end() && region.guest_base < guest_end)
+        {
+            return Err(MemoryError::Overlap);
+        }
+
+        let size = NonZeroUsize::new(size.try_into().map_err(|_| MemoryError::InvalidRegion)?)
+            .ok_or(MemoryError::InvalidRegion)?;
+
+        let mapping = WrappedAutoFree::new(
+            unsafe {
+                mman::mmap(
+                    None,
+                    size,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                    MapFlags::MAP_ANONYMOUS | MapFlags::MAP_SHARED | MapFlags::MAP_NORESERVE,
+                    None::<BorrowedFd>,
+                    0,
+                )
+                .map_err(MemoryError::Mmap)?
+            },
+            Box::new(move |map| unsafe {
+                mman::munmap(map, size.get()).expect("failed to unmap guest memory!");
+            }) as _,
+        );
+
+        self.regions.push(GuestRegion {
+            slot: self.regions.len() as u32,
+            guest_base,
+            size: size.get(),
+            flags,
+            mapping,
+        });
+
+        Ok(())
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &GuestRegion> {
+        self.regions.iter()
+    }
+
+    /// Guest physical address right after the highest region
+    pub fn end(&self) -> u64 {
+        self.regions
+            .iter()
+            .map(GuestRegion::guest_end)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Pointer to `len` bytes of memory at `addr`, all of which must belong
+    /// to the same region
+    fn host_ptr(&self, addr: u64, len: usize) -> Result<*mut u8, MemoryError> {
+        let region = self
+            .regions
+            .iter()
+            .find(|region| region.contains(addr, len))
+            .ok_or(MemoryError::InvalidAddress(addr))?;
+
+        Ok(unsafe { (*region.mapping as *mut u8).add((addr - region.guest_base) as usize) })
+    }
+
+    /// Check whether `len` bytes at `addr` are backed by a single region
+    pub fn check_range(&self, addr: u64, len: usize) -> bool {
+        self.host_ptr(addr, len).is_ok()
+    }
+
+    pub fn read_slice(&self, addr: u64, buf: &mut [u8]) -> Result<(), MemoryError> {
+        let src = self.host_ptr(addr, buf.len())?;
+
+        unsafe {
+            ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len());
+        }
+
+        Ok(())
+    }
+
+    pub fn write_slice(&self, addr: u64, buf: &[u8]) -> Result<(), MemoryError> {
+        let dst = self.host_ptr(addr, buf.len())?;
+
+        unsafe {
+            ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len());
+        }
+
+        Ok(())
+    }
+
+    /// Read a plain-old-data object
+    pub fn read_obj<T: ByteValued>(&self, addr: u64) -> Result<T, MemoryError> {
+        let src = self.host_ptr(addr, mem::size_of::<T>())?;
+
+        Ok(unsafe { ptr::read_unaligned(src as *const T) })
+    }
+
+    pub fn write_obj<T: ByteValued>(&self, addr: u64, val: &T) -> Result<(), MemoryError> {
+        let dst = self.host_ptr(addr, mem::size_of::<T>())?;
+
+        unsafe {
+            ptr::write_unaligned(dst as *mut T, *val);
+        }
+
+        Ok(())
+    }
+
+    /// Fill `len` bytes at `addr` with zeroes
+    pub fn zero(&self, addr: u64, len: usize) -> Result<(), MemoryError> {
+        let dst = self.host_ptr(addr, len)?;
+
+        unsafe {
+            libc::memset(dst as *mut c_void, 0, len);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{GuestMemory, MemoryError, MMIO_GAP_END, MMIO_GAP_START};
+
+    #[test]
+    fn ram_is_split_around_mmio_gap() {
+        let memory = GuestMemory::with_ram(MMIO_GAP_START + 0x200000).unwrap();
+        let regions = memory.regions().collect::<Vec<_>>();
+
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].guest_end(), MMIO_GAP_START);
+        assert_eq!(regions[1].guest_base(), MMIO_GAP_END);
+        assert_eq!(regions[1].slot(), 1);
+        assert_eq!(memory.end(), MMIO_GAP_END + 0x200000);
+    }
+
+    #[test]
+    fn accesses_are_bounds_checked() {
+        let mut memory = GuestMemory::new();
+        memory.add_region(0x1000, 0x1000, 0).unwrap();
+
+        assert!(matches!(
+            memory.add_region(0x0, 0x2000, 0),
+            Err(MemoryError::Overlap)
+        ));
+
+        memory.write_obj(0x1ff8, &0xdeadbeefu64).unwrap();
+        assert_eq!(memory.read_obj::<u64>(0x1ff8).unwrap(), 0xdeadbeef);
+
+        assert!(matches!(
+            memory.write_obj(0x1ffc, &0u64),
+            Err(MemoryError::InvalidAddress(0x1ffc))
+        ));
+        assert!(memory.write_slice(0x0, &[0]).is_err());
+        assert!(memory.read_obj::<u8>(u64::MAX).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+//! Intel MultiProcessor Specification 1.4 tables, which is how a guest
+//! without ACPI discovers its processors, the IOAPIC and how the ISA
+//! interrupts are wired to it
+
+use crate::{
+    constants::TIMER_GSI,
+    e820::LEGACY_HOLE_START,
+    memory::{GuestMemory, MemoryError},
+    util::{as_bytes, checksum, ByteValued},
+};
+use std::{fmt, mem};
+
+/// The MP floating pointer is placed at the start of the EBDA
+pub const MPTABLE_START: usize = 0x9fc00;
+
+/// The configuration table is placed right after the floating pointer, and
+/// must fit in the rest of the EBDA which is reserved in the E820 map
+const MPTABLE_END: usize = LEGACY_HOLE_START as usize;
+
+const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee00000;
+const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec00000;
+
+const MPC_SPEC: u8 = 4;
+const APIC_VERSION: u8 = 0x14;
+const IO_APIC_VERSION: u8 = 0x11;
+
+/// Family 6, matches what KVM reports by default
+const CPU_SIGNATURE: u32 = 0x600;
+/// FPU and APIC present
+const CPU_FEATURES: u32 = (1 << 0) | (1 << 9);
+
+const MP_PROCESSOR: u8 = 0;
+const MP_BUS: u8 = 1;
+const MP_IOAPIC: u8 = 2;
+const MP_INTSRC: u8 = 3;
+const MP_LINTSRC: u8 = 4;
+
+/// Interrupt types
+const MP_INT: u8 = 0;
+const MP_NMI: u8 = 1;
+const MP_EXTINT: u8 = 3;
+
+/// Polarity and trigger mode conform to the specification of the bus
+const MP_IRQPOL_DEFAULT: u16 = 0;
+
+const ISA_BUS_ID: u8 = 0;
+const ISA_IRQ_COUNT: u8 = 16;
+/// Cascade from the slave PIC, never delivered through the IOAPIC
+const ISA_CASCADE_IRQ: u8 = 2;
+
+/// Destination of a local interrupt that's wired to all the LAPICs
+const MP_APIC_ALL: u8 = 0xff;
+
+const CPU_ENABLED: u8 = 1 << 0;
+const CPU_BOOTPROCESSOR: u8 = 1 << 1;
+const MPC_APIC_USABLE: u8 = 1 << 0;
+
+#[derive(Debug)]
+pub enum MpTableError {
+    /// APIC IDs are 8-bit, and one is taken by the IOAPIC
+    TooManyCpus,
+    /// The table doesn't fit in the EBDA, which happens past about 40 CPUs
+    TooLarge,
+    /// Failed to write the table to guest memory
+    Memory(MemoryError),
+}
+
+impl fmt::Display for MpTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyCpus => write!(f, "unsupported number of CPUs for the MP table"),
+            Self::TooLarge => write!(f, "MP table doesn't fit in the EBDA"),
+            Self::Memory(err) => write!(f, "failed to write MP table: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for MpTableError {}
+
+impl From<MemoryError> for MpTableError {
+    fn from(err: MemoryError) -> Self {
+        Self::Memory(err)
+    }
+}
+
+/// MP Floating Pointer Structure
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MpFloating {
+    signature: [u8; 4],
+    physptr: u32,
+    length: u8,
+    specification: u8,
+    checksum: u8,
+    feature1: u8,
+    feature2: u8,
+    feature3: u8,
+    feature4: u8,
+    feature5: u8,
+}
+
+/// MP Configuration Table Header
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MpcTable {
+    signature: [u8; 4],
+    length: u16,
+    spec: u8,
+    checksum: u8,
+    oem: [u8; 8],
+    productid: [u8; 12],
+    oemptr: u32,
+    oemsize: u16,
+    oemcount: u16,
+    lapic: u32,
+    ext_length: u16,
+    ext_checksum: u8,
+    reserved: u8,
+}
+
+/// Processor Entry
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MpcCpu {
+    type_: u8,
+    apicid: u8,
+    apicver: u8,
+    cpuflag: u8,
+    cpufeature: u32,
+    featureflag: u32,
+    reserved: [u32; 2],
+}
+
+/// Bus Entry
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MpcBus {
+    type_: u8,
+    busid: u8,
+    bustype: [u8; 6],
+}
+
+/// I/O APIC Entry
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MpcIoapic {
+    type_: u8,
+    apicid: u8,
+    apicver: u8,
+    flags: u8,
+    apicaddr: u32,
+}
+
+/// I/O Interrupt Assignment Entry
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MpcIntsrc {
+    type_: u8,
+    irqtype: u8,
+    irqflag: u16,
+    srcbus: u8,
+    srcbusirq: u8,
+    dstapic: u8,
+    dstirq: u8,
+}
+
+/// Local Interrupt Assignment Entry
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MpcLintsrc {
+    type_: u8,
+    irqtype: u8,
+    irqflag: u16,
+    srcbusid: u8,
+    srcbusirq: u8,
+    destapic: u8,
+    destapiclint: u8,
+}
+
+// SAFETY: the tables are packed and only made of integers
+unsafe impl ByteValued for MpFloating {}
+unsafe impl ByteValued for MpcTable {}
+unsafe impl ByteValued for MpcCpu {}
+unsafe impl ByteValued for MpcBus {}
+unsafe impl ByteValued for MpcIoapic {}
+unsafe impl ByteValued for MpcIntsrc {}
+unsafe impl ByteValued for MpcLintsrc {}
+
+/// ID of the IOAPIC, placed right after the last LAPIC ID
+pub fn ioapic_id(num_cpus: u8) -> u8 {
+    num_cpus
+}
+
+/// Write the MP floating pointer and configuration table describing
+/// `num_cpus` processors (with vCPU 0 as the BSP), the ISA bus, the IOAPIC
+/// and the interrupt assignments
+pub fn setup_mptable(memory: &GuestMemory, num_cpus: u8) -> Result<(), MpTableError> {
+    if num_cpus == 0 || num_cpus == u8::MAX {
+        return Err(MpTableError::TooManyCpus);
+    }
+
+    let table_start = MPTABLE_START + mem::size_of::<MpFloating>();
+    let mut entries = Vec::new();
+    let mut count = 0;
+
+    for id in 0..num_cpus {
+        entries.extend_from_slice(as_bytes(&MpcCpu {
+            type_: MP_PROCESSOR,
+            apicid: id,
+            apicver: APIC_VERSION,
+            cpuflag: CPU_ENABLED | if id == 0 { CPU_BOOTPROCESSOR } else { 0 },
+            cpufeature: CPU_SIGNATURE,
+            featureflag: CPU_FEATURES,
+            ..Default::default()
+        }));
+        count += 1;
+    }
+
+    entries.extend_from_slice(as_bytes(&MpcBus {
+        type_: MP_BUS,
+        busid: ISA_BUS_ID,
+        bustype: *b"ISA   ",
+    }));
+    count += 1;
+
+    entries.extend_from_slice(as_bytes(&MpcIoapic {
+        type_: MP_IOAPIC,
+        apicid: ioapic_id(num_cpus),
+        apicver: IO_APIC_VERSION,
+        flags: MPC_APIC_USABLE,
+        apicaddr: IO_APIC_DEFAULT_PHYS_BASE,
+    }));
+    count += 1;
+
+    // The ISA IRQs are identity mapped to the IOAPIC pins, except for the timer
+    for irq in (0..ISA_IRQ_COUNT).filter(|&irq| irq != ISA_CASCADE_IRQ) {
+        entries.extend_from_slice(as_bytes(&MpcIntsrc {
+            type_: MP_INTSRC,
+            irqtype: MP_INT,
+            irqflag: MP_IRQPOL_DEFAULT,
+            srcbus: ISA_BUS_ID,
+            srcbusirq: irq,
+            dstapic: ioapic_id(num_cpus),
+            dstirq: if irq == 0 { TIMER_GSI as u8 } else { irq },
+        }));
+        count += 1;
+    }
+
+    // The PIC is connected to LINT0 of every LAPIC, and NMIs to LINT1
+    for (irqtype, lint) in [(MP_EXTINT, 0), (MP_NMI, 1)] {
+        entries.extend_from_slice(as_bytes(&MpcLintsrc {
+            type_: MP_LINTSRC,
+            irqtype,
+            irqflag: MP_IRQPOL_DEFAULT,
+            srcbusid: ISA_BUS_ID,
+            srcbusirq: 0,
+            destapic: MP_APIC_ALL,
+            destapiclint: lint,
+        }));
+        count += 1;
+    }
+
+    let length = mem::size_of::<MpcTable>() + entries.len();
+
+    if table_start + length > MPTABLE_END {
+        return Err(MpTableError::TooLarge);
+    }
+
+    let mut table = MpcTable {
+        signature: *b"PCMP",
+        length: length as u16,
+        spec: MPC_SPEC,
+        oem: *b"VMM     ",
+        productid: *b"VMM         ",
+        oemcount: count,
+        lapic: APIC_DEFAULT_PHYS_BASE,
+        ..Default::default()
+    };
+
+    // The checksum covers the header and all the entries
+    table.checksum = checksum(as_bytes(&table)).wrapping_add(checksum(&entries));
+
+    let mut floating = MpFloating {
+        signature: *b"_MP_",
+        physptr: table_start as u32,
+        // In 16-byte units
+        length: 1,
+        specification: MPC_SPEC,
+        ..Default::default()
+    };
+
+    floating.checksum = checksum(as_bytes(&floating));
+
+    memory.write_obj(MPTABLE_START as u64, &floating)?;
+    memory.write_obj(table_start as u64, &table)?;
+    memory.write_slice((table_start + mem::size_of::<MpcTable>()) as u64, &entries)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        memory::GuestMemory,
+        mptable::{setup_mptable, MpFloating, MpTableError, MpcTable, MPTABLE_START},
+        util::{as_bytes, checksum},
+    };
+    use std::mem;
+
+    #[test]
+    fn tables_are_consistent() {
+        let memory = GuestMemory::with_ram(0x200000).unwrap();
+
+        setup_mptable(&memory, 4).unwrap();
+
+        let floating = memory.read_obj::<MpFloating>(MPTABLE_START as u64).unwrap();
+        let physptr = floating.physptr;
+
+        assert_eq!(&floating.signature, b"_MP_");
+        assert_eq!(checksum(as_bytes(&floating)), 0);
+
+        let table = memory.read_obj::<MpcTable>(physptr.into()).unwrap();
+        let mut bytes = vec![0; table.length.into()];
+        memory.read_slice(physptr.into(), &mut bytes).unwrap();
+
+        assert_eq!(&table.signature, b"PCMP");
+        assert_eq!(checksum(&bytes), 0);
+
+        // Walk the entries, processors and the bus are 20 and 8 bytes long
+        let mut types = Vec::new();
+        let mut offset = mem::size_of::<MpcTable>();
+
+        while offset < bytes.len() {
+            types.push(bytes[offset]);
+            offset += if bytes[offset] == 0 { 20 } else { 8 };
+        }
+
+        assert_eq!(offset, bytes.len());
+        assert_eq!(types.len(), usize::from(table.oemcount));
+        assert_eq!(&types[..6], [0, 0, 0, 0, 1, 2]);
+        // 15 ISA IRQs and two local interrupts
+        assert_eq!(types.iter().filter(|&&type_| type_ == 3).count(), 15);
+        assert_eq!(types.iter().filter(|&&type_| type_ == 4).count(), 2);
+
+        // The processor entries would spill out of the EBDA
+        assert!(matches!(
+            setup_mptable(&memory, 64),
+            Err(MpTableError::TooLarge)
+        ));
+        assert!(matches!(
+            setup_mptable(&memory, u8::MAX),
+            Err(MpTableError::TooManyCpus)
+        ));
+    }
+}
@@ -0,0 +1,413 @@
+//! Walks the guest's page tables, to access its memory by virtual address
+//! such as when debugging it or dumping it after a crash
+
+use crate::{
+    constants::{Cr0Flags, Cr4Flags, EferFlags, PageFlags},
+    memory::{GuestMemory, MemoryError},
+};
+use kvm_bindings::{kvm_sregs, CpuId};
+use std::fmt;
+
+/// Bits 51:12 of a table entry, the address of the next table or the page
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+/// PAT bit of the entries mapping large pages, which takes the place of an
+/// address bit
+const PAT_LARGE: u64 = 1 << 12;
+const PAGE_SIZE: u64 = 0x1000;
+const ENTRY_SIZE: u64 = 8;
+
+/// Reports the physical and linear address widths
+const CPUID_ADDRESS_SIZES: u32 = 0x8000_0008;
+/// Physical address width of CPUs that don't report it
+const DEFAULT_PHYS_BITS: u8 = 36;
+
+#[derive(Debug)]
+pub enum TranslateError {
+    /// Legacy 32-bit and PAE paging aren't supported
+    UnsupportedMode,
+    /// The address isn't sign extended from bit 47, or 56 with 5-level paging
+    NonCanonical(u64),
+    /// The entry at `entry_addr` in a level `level` table isn't present, the
+    /// page table being level 1 and the PML4 or PML5 the highest level
+    NotPresent { level: u8, entry_addr: u64 },
+    /// The entry at `entry_addr` in a level `level` table has reserved bits
+    /// set, such as a large page in the PML4, an unaligned large page, an
+    /// address beyond MAXPHYADDR or NX while it isn't enabled in EFER
+    Reserved {
+        level: u8,
+        entry_addr: u64,
+        entry: u64,
+    },
+    /// A table isn't backed by guest memory
+    Memory(MemoryError),
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedMode => write!(f, "unsupported paging mode"),
+            Self::NonCanonical(addr) => write!(f, "non-canonical address {addr:#x}"),
+            Self::NotPresent { level, entry_addr } => {
+                write!(f, "level {level} entry at {entry_addr:#x} is not present")
+            }
+            Self::Reserved {
+                level,
+                entry_addr,
+                entry,
+            } => write!(
+                f,
+                "level {level} entry at {entry_addr:#x} has reserved bits set: {entry:#x}"
+            ),
+            Self::Memory(err) => write!(f, "failed to read page tables: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TranslateError {}
+
+impl From<MemoryError> for TranslateError {
+    fn from(err: MemoryError) -> Self {
+        Self::Memory(err)
+    }
+}
+
+/// Where a virtual address is mapped to, and how the page may be accessed
+/// once all levels are combined
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Translation {
+    pub paddr: u64,
+    /// 4KiB, 2MiB or 1GiB, the size of the whole address space without paging
+    pub page_size: u64,
+    pub writable: bool,
+    pub user: bool,
+    pub executable: bool,
+}
+
+/// MAXPHYADDR, the number of physical address bits of a vCPU with `cpuid`
+pub fn phys_bits(cpuid: &CpuId) -> u8 {
+    cpuid
+        .as_slice()
+        .iter()
+        .find(|entry| entry.function == CPUID_ADDRESS_SIZES)
+        .map_or(DEFAULT_PHYS_BITS, |entry| entry.eax as u8)
+}
+
+/// Translate `vaddr` using the paging mode and page tables of a vCPU, taken
+/// from CR0, CR3, CR4 and EFER, with `phys_bits` physical address bits
+pub fn translate(
+    memory: &GuestMemory,
+    sregs: &kvm_sregs,
+    phys_bits: u8,
+    vaddr: u64,
+) -> Result<Translation, TranslateError> {
+    if sregs.cr0 & Cr0Flags::PG == 0 {
+        return Ok(Translation {
+            paddr: vaddr,
+            page_size: 1 << 32,
+            writable: true,
+            user: true,
+            executable: true,
+        });
+    }
+
+    if sregs.efer & EferFlags::LMA == 0 {
+        return Err(TranslateError::UnsupportedMode);
+    }
+
+    let levels = if sregs.cr4 & Cr4Flags::LA57 != 0 {
+        5
+    } else {
+        4
+    };
+
+    // The unused upper bits must all match the highest translated bit
+    let bits = 12 + 9 * levels;
+    if ((vaddr as i64) << (64 - bits) >> (64 - bits)) as u64 != vaddr {
+        return Err(TranslateError::NonCanonical(vaddr));
+    }
+
+    // Address bits beyond MAXPHYADDR, and NX unless it's enabled, are
+    // reserved in the entries of every level
+    let mut reserved_always = ADDR_MASK & !((1 << phys_bits.min(52)) - 1);
+
+    if sregs.efer & EferFlags::NXE == 0 {
+        reserved_always |= PageFlags::NO_EXECUTE;
+    }
+
+    let mut table = sregs.cr3 & ADDR_MASK;
+    let mut translation = Translation {
+        paddr: 0,
+        page_size: 0,
+        writable: true,
+        user: true,
+        executable: true,
+    };
+
+    for level in (1..=levels).rev() {
+        let shift = 12 + 9 * (u64::from(level) - 1);
+        let entry_addr = table + ((vaddr >> shift) & 0x1FF) * ENTRY_SIZE;
+        let entry: u64 = memory.read_obj(entry_addr)?;
+
+        if entry & PageFlags::PRESENT == 0 {
+            return Err(TranslateError::NotPresent { level, entry_addr });
+        }
+
+        if entry & reserved_always != 0 {
+            return Err(TranslateError::Reserved {
+                level,
+                entry_addr,
+                entry,
+            });
+        }
+
+        translation.writable &= entry & PageFlags::READ_WRITE != 0;
+        translation.user &= entry & PageFlags::USER != 0;
+        translation.executable &= entry & PageFlags::NO_EXECUTE == 0;
+
+        let large = level > 1 && entry & PageFlags::PAGE_SIZE != 0;
+
+        if level == 1 || large {
+            let page_size = 1 << shift;
+
+            // Only PDPTs and PDs can map 1GiB and 2MiB pages, their address
+            // bits below the page size are reserved besides the PAT bit
+            let reserved = match level {
+                1 => 0,
+                2 | 3 => (page_size - 1) & ADDR_MASK & !PAT_LARGE,
+                _ => u64::MAX,
+            };
+
+            if entry & reserved != 0 {
+                return Err(TranslateError::Reserved {
+                    level,
+                    entry_addr,
+                    entry,
+                });
+            }
+
+            translation.page_size = page_size;
+            translation.paddr = (entry & ADDR_MASK & !(page_size - 1)) | (vaddr & (page_size - 1));
+
+            return Ok(translation);
+        }
+
+        table = entry & ADDR_MASK;
+    }
+
+    unreachable!("level 1 entries always map a page")
+}
+
+/// Call `f` with the physical address and length of every page spanned by
+/// `len` bytes at `vaddr`, all pages are translated before `f` is called
+fn for_each_page(
+    memory: &GuestMemory,
+    sregs: &kvm_sregs,
+    phys_bits: u8,
+    vaddr: u64,
+    len: usize,
+    mut f: impl FnMut(u64, usize) -> Result<(), MemoryError>,
+) -> Result<(), TranslateError> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+
+    while offset < len {
+        let addr = vaddr.wrapping_add(offset as u64);
+        let chunk = (len - offset).min((PAGE_SIZE - (addr & (PAGE_SIZE - 1))) as usize);
+
+        chunks.push((translate(memory, sregs, phys_bits, addr)?.paddr, chunk));
+        offset += chunk;
+    }
+
+    for (paddr, len) in chunks {
+        f(paddr, len)?;
+    }
+
+    Ok(())
+}
+
+/// Read guest memory by virtual address, which may span multiple pages
+pub fn read_virt(
+    memory: &GuestMemory,
+    sregs: &kvm_sregs,
+    phys_bits: u8,
+    vaddr: u64,
+    buf: &mut [u8],
+) -> Result<(), TranslateError> {
+    let mut offset = 0;
+
+    for_each_page(memory, sregs, phys_bits, vaddr, buf.len(), |paddr, len| {
+        memory.read_slice(paddr, &mut buf[offset..offset + len])?;
+        offset += len;
+
+        Ok(())
+    })
+}
+
+/// Write guest memory by virtual address, regardless of the page permissions
+pub fn write_virt(
+    memory: &GuestMemory,
+    sregs: &kvm_sregs,
+    phys_bits: u8,
+    vaddr: u64,
+    buf: &[u8],
+) -> Result<(), TranslateError> {
+    let mut offset = 0;
+
+    for_each_page(memory, sregs, phys_bits, vaddr, buf.len(), |paddr, len| {
+        memory.write_slice(paddr, &buf[offset..offset + len])?;
+        offset += len;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        constants::{Cr4Flags, EferFlags, PageFlags},
+        memory::GuestMemory,
+        paging::{read_virt, translate, write_virt, TranslateError},
+        util,
+    };
+
+    const PRESENT_RW: u64 = PageFlags::PRESENT | PageFlags::READ_WRITE;
+    const PHYS_BITS: u8 = 46;
+
+    #[test]
+    fn translate_identity_map() {
+        let memory = GuestMemory::with_ram(64 << 20).unwrap();
+        util::setup_paging(&memory).unwrap();
+
+        let sregs = util::setup_sregs();
+        let translation = translate(&memory, &sregs, PHYS_BITS, 0x1234567).unwrap();
+
+        assert_eq!(translation.paddr, 0x1234567);
+        assert_eq!(translation.page_size, 2 << 20);
+        assert!(translation.writable && !translation.user && translation.executable);
+
+        // Only the first GiB is mapped with 64MiB of RAM
+        assert!(matches!(
+            translate(&memory, &sregs, PHYS_BITS, 1 << 30),
+            Err(TranslateError::NotPresent {
+                level: 3,
+                entry_addr: 0x2008
+            })
+        ));
+        assert!(matches!(
+            translate(&memory, &sregs, PHYS_BITS, 1 << 47),
+            Err(TranslateError::NonCanonical(_))
+        ));
+
+        let real_mode = Default::default();
+        assert_eq!(
+            translate(&memory, &real_mode, PHYS_BITS, 0xdead)
+                .unwrap()
+                .paddr,
+            0xdead
+        );
+    }
+
+    #[test]
+    fn translate_higher_half() {
+        let memory = GuestMemory::with_ram(16 << 20).unwrap();
+        let mut sregs = util::setup_sregs();
+
+        // PML5 -> PML4 -> PDPT -> PD -> PT, at 0x10000 onwards
+        let table = |n: u64| 0x10000 + n * 0x1000;
+        let vaddr = 0xffff_ffff_8020_1234u64;
+        let index = |level: u64| (vaddr >> (12 + 9 * (level - 1))) & 0x1FF;
+
+        sregs.cr3 = table(0);
+        sregs.cr4 |= Cr4Flags::LA57;
+        sregs.efer |= EferFlags::NXE;
+
+        for level in (2..=5).rev() {
+            let entry = table(6 - level) | PRESENT_RW;
+            memory
+                .write_obj(table(5 - level) + index(level) * 8, &entry)
+                .unwrap();
+        }
+
+        // A read-only, no-execute 4KiB page
+        let pte = 0x300000 | PageFlags::PRESENT | PageFlags::NO_EXECUTE;
+        memory.write_obj(table(4) + index(1) * 8, &pte).unwrap();
+
+        let translation = translate(&memory, &sregs, PHYS_BITS, vaddr).unwrap();
+        assert_eq!(translation.paddr, 0x300234);
+        assert_eq!(translation.page_size, 0x1000);
+        assert!(!translation.writable && !translation.executable);
+
+        // 57-bit addresses are canonical with 5-level paging only
+        assert!(matches!(
+            translate(&memory, &sregs, PHYS_BITS, 0x00ff_0000_0000_0000),
+            Err(TranslateError::NotPresent { level: 5, .. })
+        ));
+
+        // Misaligned 2MiB page in the PD
+        let pde = 0x301000 | PRESENT_RW | PageFlags::PAGE_SIZE;
+        memory.write_obj(table(3) + index(2) * 8, &pde).unwrap();
+
+        assert!(matches!(
+            translate(&memory, &sregs, PHYS_BITS, vaddr),
+            Err(TranslateError::Reserved { level: 2, .. })
+        ));
+
+        // Accesses are split across pages, the next 2MiB isn't mapped
+        let pde = 0x400000 | PRESENT_RW | PageFlags::PAGE_SIZE;
+        memory.write_obj(table(3) + index(2) * 8, &pde).unwrap();
+
+        write_virt(
+            &memory,
+            &sregs,
+            PHYS_BITS,
+            0xffff_ffff_8020_0ffe,
+            &[1, 2, 3, 4],
+        )
+        .unwrap();
+        assert_eq!(memory.read_obj::<u32>(0x400ffe).unwrap(), 0x04030201);
+
+        let mut buf = [0; 4];
+        read_virt(&memory, &sregs, PHYS_BITS, 0xffff_ffff_8020_0ffe, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert!(read_virt(&memory, &sregs, PHYS_BITS, 0xffff_ffff_803f_fffe, &mut buf).is_err());
+    }
+
+    #[test]
+    fn reserved_bits_fault() {
+        let memory = GuestMemory::with_ram(16 << 20).unwrap();
+        let mut sregs = util::setup_sregs();
+
+        // PML4 -> PDPT mapping 1GiB pages
+        sregs.cr3 = 0x10000;
+        memory.write_obj(0x10000, &(0x11000 | PRESENT_RW)).unwrap();
+
+        let pdpte = (3 << 30) | PRESENT_RW | PageFlags::PAGE_SIZE;
+        memory.write_obj(0x11000 + 8, &pdpte).unwrap();
+
+        let translation = translate(&memory, &sregs, PHYS_BITS, 0x7654_3210).unwrap();
+        assert_eq!(translation.paddr, 0xf654_3210);
+        assert_eq!(translation.page_size, 1 << 30);
+        assert!(translation.executable);
+
+        // NX is a reserved bit unless it's enabled in EFER
+        memory
+            .write_obj(0x11000 + 8, &(pdpte | PageFlags::NO_EXECUTE))
+            .unwrap();
+        assert!(matches!(
+            translate(&memory, &sregs, PHYS_BITS, 0x7654_3210),
+            Err(TranslateError::Reserved { level: 3, .. })
+        ));
+
+        sregs.efer |= EferFlags::NXE;
+        let translation = translate(&memory, &sregs, PHYS_BITS, 0x7654_3210).unwrap();
+        assert!(!translation.executable);
+
+        // So are address bits beyond MAXPHYADDR
+        memory.write_obj(0x11000 + 8, &((1 << 40) | pdpte)).unwrap();
+        assert!(translate(&memory, &sregs, PHYS_BITS, 0x7654_3210).is_ok());
+        assert!(matches!(
+            translate(&memory, &sregs, 39, 0x7654_3210),
+            Err(TranslateError::Reserved { level: 3, .. })
+        ));
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Why the VM stopped running
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmExitStatus {
+    /// The guest powered itself off
+    PowerOff,
+    /// The guest asked to be reset
+    Reboot,
+    /// The guest triple faulted, panicked or couldn't be run any further
+    Crash,
+    /// The VM was stopped from the host, with Ctrl-A x or by GDB
+    Killed,
+    /// The guest asked for the VMM to exit with the given code, through the
+    /// debug exit device
+    Exit(u8),
+}
+
+impl VmExitStatus {
+    /// Exit code of the VMM process, 1 and 2 are taken by VMM and usage errors
+    /// unless the guest picks the code itself. Ours are even so that they
+    /// can't be mistaken for the odd codes of the debug exit device
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::PowerOff => 0,
+            Self::Reboot => 4,
+            Self::Crash => 6,
+            Self::Killed => 8,
+            Self::Exit(code) => code,
+        }
+    }
+}
+
+/// Shared between the devices and the vCPU threads to stop the VM, only the
+/// first request is kept
+#[derive(Clone, Default)]
+pub struct PowerControl {
+    status: Arc<(Mutex<Option<VmExitStatus>>, Condvar)>,
+}
+
+impl PowerControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self, status: VmExitStatus) {
+        let (lock, condvar) = &*self.status;
+        let mut current = lock.lock().unwrap();
+
+        if current.is_none() {
+            *current = Some(status);
+            condvar.notify_all();
+        }
+    }
+
+    /// The requested status, if the VM is stopping
+    pub fn status(&self) -> Option<VmExitStatus> {
+        *self.status.0.lock().unwrap()
+    }
+
+    /// Forget about the last request, before booting the VM again
+    pub fn reset(&self) {
+        *self.status.0.lock().unwrap() = None;
+    }
+
+    /// Block till the VM is asked to stop
+    pub fn wait(&self) -> VmExitStatus {
+        let (lock, condvar) = &*self.status;
+        let current = condvar
+            .wait_while(lock.lock().unwrap(), |status| status.is_none())
+            .unwrap();
+
+        current.expect("woken up without a status")
+    }
+}
@@ -0,0 +1,491 @@
+//! Direct boot of an uncompressed ELF vmlinux through its PVH entry point
+//! Ref: https://xenbits.xen.org/docs/unstable/misc/pvh.html
+
+use crate::{
+    bootparam::boot_e820_entry,
+    linux_loader::{place_initramfs, LoaderError},
+    memory::{GuestMemory, MemoryError},
+    util::ByteValued,
+};
+use std::mem;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+/// Name of the notes that the entry point is stored in, including the NUL
+const XEN_NOTE_NAME: &[u8] = b"Xen\0";
+/// Physical address of the 32-bit entry point
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
+/// Identifies `hvm_start_info`, "xEn3" with the top bit of each byte set
+const HVM_START_MAGIC: u32 = 0x336ec578;
+/// Version 1 adds the memory map
+const HVM_START_VERSION: u32 = 1;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct Elf64Ehdr {
+    ident: [u8; 16],
+    type_: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct Elf64Phdr {
+    type_: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct Elf64Nhdr {
+    namesz: u32,
+    descsz: u32,
+    type_: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct HvmStartInfo {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct HvmModlistEntry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct HvmMemmapTableEntry {
+    addr: u64,
+    size: u64,
+    type_: u32,
+    reserved: u32,
+}
+
+// SAFETY: the ELF headers are packed, and the start info structures have
+// naturally aligned fields, all of them only made of integers
+unsafe impl ByteValued for Elf64Ehdr {}
+unsafe impl ByteValued for Elf64Phdr {}
+unsafe impl ByteValued for Elf64Nhdr {}
+unsafe impl ByteValued for HvmStartInfo {}
+unsafe impl ByteValued for HvmModlistEntry {}
+unsafe impl ByteValued for HvmMemmapTableEntry {}
+
+/// Read a plain-old-data struct from `offset` into `image`
+fn read_struct<T: ByteValued>(image: &[u8], offset: u64) -> Result<T, LoaderError> {
+    let offset = usize::try_from(offset).map_err(|_| LoaderError::ImageTooSmall)?;
+
+    if image.len().saturating_sub(offset) < mem::size_of::<T>() {
+        return Err(LoaderError::ImageTooSmall);
+    }
+
+    Ok(unsafe { std::ptr::read_unaligned(image[offset..].as_ptr().cast()) })
+}
+
+/// Check whether the image looks like an ELF file, rather than a bzImage
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(ELF_MAGIC)
+}
+
+pub struct PvhImage<'a> {
+    image: &'a [u8],
+    segments: Vec<Elf64Phdr>,
+    entry: u32,
+}
+
+impl<'a> PvhImage<'a> {
+    pub fn new(image: &'a [u8]) -> Result<Self, LoaderError> {
+        let ehdr = read_struct::<Elf64Ehdr>(image, 0)?;
+
+        if !is_elf(image)
+            || ehdr.ident[4] != ELFCLASS64
+            || ehdr.ident[5] != ELFDATA2LSB
+            || ehdr.type_ != ET_EXEC
+            || ehdr.machine != EM_X86_64
+            || usize::from(ehdr.phentsize) != mem::size_of::<Elf64Phdr>()
+        {
+            return Err(LoaderError::InvalidImage);
+        }
+
+        let phdrs = (0..u64::from(ehdr.phnum))
+            .map(|n| {
+                let offset = n
+                    .checked_mul(u64::from(ehdr.phentsize))
+                    .and_then(|offset| offset.checked_add(ehdr.phoff))
+                    .ok_or(LoaderError::InvalidImage)?;
+
+                read_struct::<Elf64Phdr>(image, offset)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut entry = None;
+
+        for phdr in phdrs.iter().filter(|phdr| phdr.type_ == PT_NOTE) {
+            entry = entry.or(Self::find_entry(image, phdr)?);
+        }
+
+        let segments = phdrs
+            .into_iter()
+            .filter(|phdr| phdr.type_ == PT_LOAD)
+            .collect::<Vec<_>>();
+
+        for segment in &segments {
+            if segment.paddr.checked_add(segment.memsz).is_none() {
+                return Err(LoaderError::InvalidImage);
+            }
+
+            if segment.filesz > segment.memsz
+                || segment
+                    .offset
+                    .checked_add(segment.filesz)
+                    .is_none_or(|end| end > image.len() as u64)
+            {
+                return Err(LoaderError::ImageTooSmall);
+            }
+        }
+
+        Ok(Self {
+            image,
+            segments,
+            entry: entry.ok_or(LoaderError::NoPvhEntry)?,
+        })
+    }
+
+    /// Look for the PVH entry point in the notes of a `PT_NOTE` segment
+    fn find_entry(image: &[u8], phdr: &Elf64Phdr) -> Result<Option<u32>, LoaderError> {
+        let align = |size: u32| u64::from(size).next_multiple_of(4);
+
+        let mut offset = phdr.offset;
+        let end = phdr
+            .offset
+            .checked_add(phdr.filesz)
+            .ok_or(LoaderError::InvalidImage)?;
+
+        while let Some(name) = offset
+            .checked_add(mem::size_of::<Elf64Nhdr>() as u64)
+            .filter(|&name| name <= end)
+        {
+            let nhdr = read_struct::<Elf64Nhdr>(image, offset)?;
+            let desc = name
+                .checked_add(align(nhdr.namesz))
+                .ok_or(LoaderError::InvalidImage)?;
+
+            // Each note must fit in the segment
+            offset = desc
+                .checked_add(align(nhdr.descsz))
+                .filter(|&next| next <= end)
+                .ok_or(LoaderError::InvalidImage)?;
+
+            if nhdr.type_ != XEN_ELFNOTE_PHYS32_ENTRY
+                || nhdr.namesz as usize != XEN_NOTE_NAME.len()
+                || read_struct::<[u8; 4]>(image, name)? != XEN_NOTE_NAME
+            {
+                continue;
+            }
+
+            // The entry point is a 32-bit address, even if the note is wider
+            return read_struct::<u32>(image, desc).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    /// Physical address of the 32-bit entry point
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    /// Guest physical address right after the highest segment, which was
+    /// checked not to overflow
+    pub fn end(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| segment.paddr + segment.memsz)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Copy the loadable segments to their physical addresses, zeroing
+    /// the part of each one that isn't backed by the file (such as .bss)
+    pub fn load(&self, memory: &GuestMemory) -> Result<(), MemoryError> {
+        for segment in &self.segments {
+            let (offset, filesz) = (segment.offset as usize, segment.filesz as usize);
+
+            memory.write_slice(segment.paddr, &self.image[offset..offset + filesz])?;
+            memory.zero(
+                segment.paddr + segment.filesz,
+                (segment.memsz - segment.filesz) as usize,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the address to load an initramfs of `size` bytes at, as high as
+    /// possible below `ram_end` (the end of RAM below 4GiB) and after the kernel
+    pub fn initramfs_addr(&self, size: u32, ram_end: u64) -> Result<u32, LoaderError> {
+        place_initramfs(self.end(), ram_end, size)
+    }
+}
+
+/// Write `hvm_start_info` at `addr`, followed by the module list and memory
+/// map that it points to, the initramfs is passed as the only module
+pub fn setup_start_info(
+    memory: &GuestMemory,
+    addr: u64,
+    cmdline_addr: u64,
+    rsdp_addr: u64,
+    initramfs: Option<(u64, u64)>,
+    e820_entries: &[boot_e820_entry],
+) -> Result<(), MemoryError> {
+    let modlist_addr = addr + mem::size_of::<HvmStartInfo>() as u64;
+    let memmap_addr = modlist_addr + mem::size_of::<HvmModlistEntry>() as u64;
+
+    if let Some((paddr, size)) = initramfs {
+        memory.write_obj(
+            modlist_addr,
+            &HvmModlistEntry {
+                paddr,
+                size,
+                ..Default::default()
+            },
+        )?;
+    }
+
+    for (n, entry) in e820_entries.iter().enumerate() {
+        memory.write_obj(
+            memmap_addr + (n * mem::size_of::<HvmMemmapTableEntry>()) as u64,
+            &HvmMemmapTableEntry {
+                addr: entry.addr,
+                size: entry.size,
+                type_: entry.type_,
+                reserved: 0,
+            },
+        )?;
+    }
+
+    memory.write_obj(
+        addr,
+        &HvmStartInfo {
+            magic: HVM_START_MAGIC,
+            version: HVM_START_VERSION,
+            nr_modules: initramfs.is_some().into(),
+            modlist_paddr: if initramfs.is_some() { modlist_addr } else { 0 },
+            cmdline_paddr: cmdline_addr,
+            rsdp_paddr: rsdp_addr,
+            memmap_paddr: memmap_addr,
+            memmap_entries: e820_entries.len() as u32,
+            ..Default::default()
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bootparam::boot_e820_entry,
+        e820::E820_RAM,
+        linux_loader::LoaderError,
+        memory::GuestMemory,
+        pvh::{
+            is_elf, setup_start_info, Elf64Ehdr, Elf64Nhdr, Elf64Phdr, HvmStartInfo, PvhImage,
+            HVM_START_MAGIC, PT_LOAD, PT_NOTE, XEN_ELFNOTE_PHYS32_ENTRY,
+        },
+        util::ByteValued,
+    };
+    use std::{mem, slice};
+
+    fn push<T: ByteValued>(image: &mut Vec<u8>, val: &T) {
+        image.extend_from_slice(unsafe {
+            slice::from_raw_parts((val as *const T).cast(), mem::size_of::<T>())
+        });
+    }
+
+    /// ELF with one loadable segment of `code` at 1MiB, followed by 0x100
+    /// bytes of .bss, and optionally the PVH note
+    fn fake_vmlinux(code: &[u8], entry: Option<u32>) -> Vec<u8> {
+        let headers = mem::size_of::<Elf64Ehdr>() + 2 * mem::size_of::<Elf64Phdr>();
+        let note = mem::size_of::<Elf64Nhdr>() + 4 + 4;
+        let mut image = Vec::new();
+
+        let mut ident = [0; 16];
+        ident[..4].copy_from_slice(b"\x7fELF");
+        ident[4] = 2;
+        ident[5] = 1;
+
+        push(
+            &mut image,
+            &Elf64Ehdr {
+                ident,
+                type_: 2,
+                machine: 62,
+                phoff: mem::size_of::<Elf64Ehdr>() as u64,
+                phentsize: mem::size_of::<Elf64Phdr>() as u16,
+                phnum: 2,
+                ..Default::default()
+            },
+        );
+        push(
+            &mut image,
+            &Elf64Phdr {
+                type_: PT_NOTE,
+                offset: headers as u64,
+                filesz: note as u64,
+                ..Default::default()
+            },
+        );
+        push(
+            &mut image,
+            &Elf64Phdr {
+                type_: PT_LOAD,
+                offset: (headers + note) as u64,
+                paddr: 0x100000,
+                filesz: code.len() as u64,
+                memsz: code.len() as u64 + 0x100,
+                ..Default::default()
+            },
+        );
+        push(
+            &mut image,
+            &Elf64Nhdr {
+                namesz: 4,
+                descsz: 4,
+                type_: entry.map_or(0, |_| XEN_ELFNOTE_PHYS32_ENTRY),
+            },
+        );
+        image.extend_from_slice(b"Xen\0");
+        image.extend_from_slice(&entry.unwrap_or(0).to_le_bytes());
+        image.extend_from_slice(code);
+
+        image
+    }
+
+    #[test]
+    fn segments_are_loaded() {
+        let image = fake_vmlinux(&[0xf4; 0x10], Some(0x100004));
+        let pvh = PvhImage::new(&image).unwrap();
+
+        assert!(is_elf(&image));
+        assert_eq!(pvh.entry(), 0x100004);
+        assert_eq!(pvh.end(), 0x100110);
+
+        let memory = GuestMemory::with_ram(0x200000).unwrap();
+        memory.write_slice(0x100010, &[0xff; 0x100]).unwrap();
+        pvh.load(&memory).unwrap();
+
+        assert_eq!(
+            memory.read_obj::<[u8; 0x10]>(0x100000).unwrap(),
+            [0xf4; 0x10]
+        );
+        assert_eq!(
+            memory.read_obj::<[u8; 0x100]>(0x100010).unwrap(),
+            [0; 0x100]
+        );
+
+        assert!(matches!(
+            PvhImage::new(&fake_vmlinux(&[0xf4], None)),
+            Err(LoaderError::NoPvhEntry)
+        ));
+        assert!(matches!(
+            PvhImage::new(&image[..image.len() - 1]),
+            Err(LoaderError::ImageTooSmall)
+        ));
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let mut image = fake_vmlinux(&[0xf4], Some(0x100000));
+        let note = mem::size_of::<Elf64Ehdr>() + 2 * mem::size_of::<Elf64Phdr>();
+
+        // A note descriptor that runs past the end of the segment
+        image[note + 4..note + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            PvhImage::new(&image),
+            Err(LoaderError::InvalidImage)
+        ));
+
+        // The segment's physical address range wraps around
+        let mut image = fake_vmlinux(&[0xf4], Some(0x100000));
+        let paddr = mem::size_of::<Elf64Ehdr>() + mem::size_of::<Elf64Phdr>() + 24;
+        image[paddr..paddr + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            PvhImage::new(&image),
+            Err(LoaderError::InvalidImage)
+        ));
+    }
+
+    #[test]
+    fn start_info_points_to_tables() {
+        let memory = GuestMemory::with_ram(0x200000).unwrap();
+        let e820 = [boot_e820_entry {
+            addr: 0,
+            size: 0x200000,
+            type_: E820_RAM,
+        }];
+
+        setup_start_info(
+            &memory,
+            0x10000,
+            0x20000,
+            0xe0000,
+            Some((0x1f0000, 0x800)),
+            &e820,
+        )
+        .unwrap();
+
+        let info = memory.read_obj::<HvmStartInfo>(0x10000).unwrap();
+
+        assert_eq!(info.magic, HVM_START_MAGIC);
+        assert_eq!(info.nr_modules, 1);
+        assert_eq!(info.cmdline_paddr, 0x20000);
+        assert_eq!(info.rsdp_paddr, 0xe0000);
+        assert_eq!(info.memmap_entries, 1);
+        assert_eq!(
+            memory.read_obj::<u64>(info.modlist_paddr).unwrap(),
+            0x1f0000
+        );
+        assert_eq!(
+            memory.read_obj::<u64>(info.memmap_paddr + 8).unwrap(),
+            0x200000
+        );
+    }
+}
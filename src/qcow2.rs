@@ -0,0 +1,910 @@
+//! qcow2 images, as created by `qemu-img create -f qcow2`, ref:
+//! https://gitlab.com/qemu-project/qemu/-/blob/master/docs/interop/qcow2.txt
+
+use crate::disk::{self, DiskImage, RawImage};
+use std::{
+    fs::File,
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+/// "QFI\xfb"
+const MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_LEN: usize = 72;
+const V3_HEADER_LEN: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+/// Backing files of backing files are followed up to this depth, which
+/// also stops loops
+const MAX_BACKING_DEPTH: usize = 16;
+const MAX_BACKING_NAME_LEN: u32 = 1023;
+/// The L1 and refcount tables are read whole, they're limited to what QEMU
+/// creates for the largest images it supports
+const MAX_TABLE_SIZE: u64 = 32 << 20;
+/// Offset of `autoclear_features` in version 3 headers
+const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+/// Bits 9-55 of L1, L2 and refcount table entries, the offset of a cluster
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The cluster's refcount is exactly one, so it may be written in place
+const COPIED: u64 = 1 << 63;
+/// The cluster is compressed, which isn't supported
+const COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeroes, in version 3 images
+const ZERO: u64 = 1 << 0;
+
+/// 16-bit refcounts, the only width of version 2 images and the default
+const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("qcow2: {msg}"))
+}
+
+fn unsupported(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("qcow2: {msg}"))
+}
+
+fn read_be_u64(file: &File, offset: u64) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    file.read_exact_at(&mut buf, offset)?;
+
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn write_be_u64(file: &File, offset: u64, value: u64) -> io::Result<()> {
+    file.write_all_at(&value.to_be_bytes(), offset)
+}
+
+/// Whether `file` starts with the qcow2 magic
+pub fn probe(file: &File) -> io::Result<bool> {
+    let mut magic = [0; 4];
+
+    match file.read_exact_at(&mut magic, 0) {
+        Ok(()) => Ok(u32::from_be_bytes(magic) == MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// The fields of the header that are used, all big endian on disk
+#[derive(Clone, Debug, Default)]
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible_features: u64,
+    compatible_features: u64,
+    autoclear_features: u64,
+    refcount_order: u32,
+    header_length: u32,
+}
+
+impl Header {
+    fn read(file: &File) -> io::Result<Self> {
+        let mut buf = [0; V3_HEADER_LEN];
+        file.read_exact_at(&mut buf[..V2_HEADER_LEN], 0)?;
+
+        let u32_at = |buf: &[u8], offset: usize| {
+            u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+        };
+        let u64_at = |buf: &[u8], offset: usize| {
+            u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+        };
+
+        if u32_at(&buf, 0) != MAGIC {
+            return Err(invalid_data("bad magic"));
+        }
+
+        let mut header = Self {
+            version: u32_at(&buf, 4),
+            backing_file_offset: u64_at(&buf, 8),
+            backing_file_size: u32_at(&buf, 16),
+            cluster_bits: u32_at(&buf, 20),
+            size: u64_at(&buf, 24),
+            crypt_method: u32_at(&buf, 32),
+            l1_size: u32_at(&buf, 36),
+            l1_table_offset: u64_at(&buf, 40),
+            refcount_table_offset: u64_at(&buf, 48),
+            refcount_table_clusters: u32_at(&buf, 56),
+            nb_snapshots: u32_at(&buf, 60),
+            refcount_order: DEFAULT_REFCOUNT_ORDER,
+            header_length: V2_HEADER_LEN as u32,
+            ..Default::default()
+        };
+
+        match header.version {
+            2 => {}
+            3 => {
+                file.read_exact_at(&mut buf[V2_HEADER_LEN..], V2_HEADER_LEN as u64)?;
+
+                header.incompatible_features = u64_at(&buf, 72);
+                header.compatible_features = u64_at(&buf, 80);
+                header.autoclear_features = u64_at(&buf, 88);
+                header.refcount_order = u32_at(&buf, 96);
+                header.header_length = u32_at(&buf, 100);
+
+                if (header.header_length as usize) < V3_HEADER_LEN {
+                    return Err(invalid_data("header is too short"));
+                }
+            }
+            version => return Err(unsupported(&format!("version {version}"))),
+        }
+
+        Ok(header)
+    }
+
+    /// A version 3 header, without any header extensions
+    #[cfg(test)]
+    fn to_bytes(&self) -> [u8; V3_HEADER_LEN] {
+        let mut buf = [0; V3_HEADER_LEN];
+        let mut offset = 0;
+
+        let mut put = |bytes: &[u8]| {
+            buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+            offset += bytes.len();
+        };
+
+        put(&MAGIC.to_be_bytes());
+        put(&self.version.to_be_bytes());
+        put(&self.backing_file_offset.to_be_bytes());
+        put(&self.backing_file_size.to_be_bytes());
+        put(&self.cluster_bits.to_be_bytes());
+        put(&self.size.to_be_bytes());
+        put(&self.crypt_method.to_be_bytes());
+        put(&self.l1_size.to_be_bytes());
+        put(&self.l1_table_offset.to_be_bytes());
+        put(&self.refcount_table_offset.to_be_bytes());
+        put(&self.refcount_table_clusters.to_be_bytes());
+        put(&self.nb_snapshots.to_be_bytes());
+        // snapshots_offset, new images don't have any
+        put(&0u64.to_be_bytes());
+        put(&self.incompatible_features.to_be_bytes());
+        put(&self.compatible_features.to_be_bytes());
+        put(&self.autoclear_features.to_be_bytes());
+        put(&self.refcount_order.to_be_bytes());
+        put(&self.header_length.to_be_bytes());
+
+        buf
+    }
+}
+
+/// Number of L2 entries, or refcounts, that fit in a cluster
+fn entries_per_cluster(cluster_bits: u32, entry_bits: u32) -> u64 {
+    (1 << (cluster_bits + 3)) / u64::from(entry_bits)
+}
+
+/// Create an empty version 3 image of `size` bytes with clusters of
+/// `1 << cluster_bits` bytes, which reads through to `backing` if given. The
+/// backing file's path is stored as is, relative paths are resolved from the
+/// directory of the image
+#[cfg(test)]
+pub fn create(file: &File, size: u64, cluster_bits: u32, backing: Option<&str>) -> io::Result<()> {
+    if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let cluster_size = 1u64 << cluster_bits;
+    let refcount_bits = 1 << DEFAULT_REFCOUNT_ORDER;
+    let clusters = |bytes: u64| bytes.div_ceil(cluster_size);
+
+    let l2_coverage = entries_per_cluster(cluster_bits, 64) * cluster_size;
+    let l1_size = size.div_ceil(l2_coverage);
+    let l1_clusters = clusters(l1_size * 8).max(1);
+
+    // The refcount table can't grow, it's made large enough to cover the
+    // whole disk along with all of its metadata twice over
+    let data_clusters = clusters(size) * 2 + l1_clusters + 16;
+    let table_coverage =
+        entries_per_cluster(cluster_bits, 64) * entries_per_cluster(cluster_bits, refcount_bits);
+    let refcount_table_clusters = data_clusters.div_ceil(table_coverage);
+
+    // The header, the refcount table, a refcount block and the L1 table
+    let refcount_table_offset = cluster_size;
+    let refcount_block_offset = refcount_table_offset + refcount_table_clusters * cluster_size;
+    let l1_table_offset = refcount_block_offset + cluster_size;
+    let used_clusters = 2 + refcount_table_clusters + l1_clusters;
+
+    if used_clusters > entries_per_cluster(cluster_bits, refcount_bits)
+        || l1_size > u64::from(u32::MAX)
+    {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let mut header = Header {
+        version: 3,
+        cluster_bits,
+        size,
+        l1_size: l1_size as u32,
+        l1_table_offset,
+        refcount_table_offset,
+        refcount_table_clusters: refcount_table_clusters as u32,
+        refcount_order: DEFAULT_REFCOUNT_ORDER,
+        header_length: V3_HEADER_LEN as u32,
+        ..Default::default()
+    };
+
+    // The backing file name follows the end of the header extensions
+    if let Some(backing) = backing {
+        if backing.is_empty() || backing.len() > MAX_BACKING_NAME_LEN as usize {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        header.backing_file_offset = V3_HEADER_LEN as u64 + 8;
+        header.backing_file_size = backing.len() as u32;
+    }
+
+    file.set_len(0)?;
+    file.set_len(l1_table_offset + l1_clusters * cluster_size)?;
+    file.write_all_at(&header.to_bytes(), 0)?;
+
+    if let Some(backing) = backing {
+        file.write_all_at(backing.as_bytes(), header.backing_file_offset)?;
+    }
+
+    write_be_u64(file, refcount_table_offset, refcount_block_offset)?;
+
+    let refcounts = [0, 1].repeat(used_clusters as usize);
+    file.write_all_at(&refcounts, refcount_block_offset)?;
+
+    Ok(())
+}
+
+/// A disk stored in a qcow2 image, where clusters are only allocated once
+/// they're written to
+pub struct Qcow2Image {
+    file: File,
+    version: u32,
+    size: u64,
+    cluster_bits: u32,
+    l1_table_offset: u64,
+    /// Offsets of the L2 tables along with their flags
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    /// Offsets of the refcount blocks
+    refcount_table: Vec<u64>,
+    refcount_bits: u32,
+    /// Where reads of clusters that weren't allocated go
+    backing: Option<Box<dyn DiskImage>>,
+    /// New clusters are appended at the end of the file
+    end: u64,
+    /// Clusters that aren't referenced anymore, reused before appending
+    free_clusters: Vec<u64>,
+}
+
+impl Qcow2Image {
+    /// Open the image in `file`, found at `path`, which is needed to find
+    /// backing files relative to it
+    pub fn open(file: File, path: &Path, read_only: bool) -> io::Result<Self> {
+        Self::open_at_depth(file, path, read_only, 0)
+    }
+
+    fn open_at_depth(file: File, path: &Path, read_only: bool, depth: usize) -> io::Result<Self> {
+        let header = Header::read(&file)?;
+
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&header.cluster_bits) {
+            return Err(invalid_data("invalid cluster size"));
+        }
+
+        if header.crypt_method != 0 {
+            return Err(unsupported("encrypted images"));
+        }
+
+        // Dirty images need their refcounts repaired, and corrupt ones
+        // shouldn't be touched at all
+        if header.incompatible_features != 0 {
+            return Err(unsupported(&format!(
+                "incompatible features {:#x}",
+                header.incompatible_features
+            )));
+        }
+
+        // Only whole bytes, from 8 to 64-bit refcounts
+        if !(3..=6).contains(&header.refcount_order) {
+            return Err(unsupported(&format!(
+                "refcount order {}",
+                header.refcount_order
+            )));
+        }
+
+        if !read_only && header.nb_snapshots != 0 {
+            return Err(unsupported("writing to images with internal snapshots"));
+        }
+
+        let cluster_bits = header.cluster_bits;
+        let cluster_size = 1u64 << cluster_bits;
+        let l2_coverage = entries_per_cluster(cluster_bits, 64) * cluster_size;
+
+        if u64::from(header.l1_size) < header.size.div_ceil(l2_coverage)
+            || (header.l1_table_offset | header.refcount_table_offset) & (cluster_size - 1) != 0
+        {
+            return Err(invalid_data("invalid L1 or refcount table"));
+        }
+
+        let file_len = file.metadata()?.len();
+        let l1_table = Self::read_table(
+            &file,
+            file_len,
+            header.l1_table_offset,
+            header.l1_size.into(),
+        )?;
+        let refcount_table = Self::read_table(
+            &file,
+            file_len,
+            header.refcount_table_offset,
+            u64::from(header.refcount_table_clusters) << (cluster_bits - 3),
+        )?;
+
+        let backing = if header.backing_file_offset != 0 {
+            if depth == MAX_BACKING_DEPTH {
+                return Err(invalid_data("too many backing files"));
+            }
+
+            if header.backing_file_size > MAX_BACKING_NAME_LEN {
+                return Err(invalid_data("backing file name is too long"));
+            }
+
+            let mut name = vec![0; header.backing_file_size as usize];
+            file.read_exact_at(&mut name, header.backing_file_offset)?;
+
+            let name = String::from_utf8(name)
+                .map_err(|_| invalid_data("backing file name isn't UTF-8"))?;
+
+            Some(Self::open_backing(path, &name, depth)?)
+        } else {
+            None
+        };
+
+        // The autoclear features describe metadata that would go stale once
+        // the image is written to without updating it
+        if !read_only && header.autoclear_features != 0 {
+            write_be_u64(&file, AUTOCLEAR_FEATURES_OFFSET, 0)?;
+        }
+
+        let end = file_len.next_multiple_of(cluster_size);
+
+        Ok(Self {
+            file,
+            version: header.version,
+            size: header.size,
+            cluster_bits,
+            l1_table_offset: header.l1_table_offset,
+            l1_table,
+            refcount_table_offset: header.refcount_table_offset,
+            refcount_table,
+            refcount_bits: 1 << header.refcount_order,
+            backing,
+            end,
+            free_clusters: Vec::new(),
+        })
+    }
+
+    /// Backing files are never written to, and may be either raw or qcow2
+    fn open_backing(path: &Path, name: &str, depth: usize) -> io::Result<Box<dyn DiskImage>> {
+        let backing_path = match path.parent() {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        };
+
+        let file = File::open(&backing_path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("qcow2: failed to open {}: {err}", backing_path.display()),
+            )
+        })?;
+
+        if probe(&file)? {
+            Ok(Box::new(Self::open_at_depth(
+                file,
+                &backing_path,
+                true,
+                depth + 1,
+            )?))
+        } else {
+            Ok(Box::new(RawImage::new(file)?))
+        }
+    }
+
+    /// Read a table of `entries` big endian entries at `offset`, which must
+    /// be within the `file_len` bytes of the file
+    fn read_table(file: &File, file_len: u64, offset: u64, entries: u64) -> io::Result<Vec<u64>> {
+        let size = entries
+            .checked_mul(8)
+            .filter(|&size| size <= MAX_TABLE_SIZE)
+            .filter(|&size| offset.checked_add(size).is_some_and(|end| end <= file_len))
+            .ok_or_else(|| invalid_data("table is too large or past the end of the file"))?;
+
+        let mut buf = vec![0; size as usize];
+        file.read_exact_at(&mut buf, offset)?;
+
+        Ok(buf
+            .chunks_exact(8)
+            .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+            .collect())
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Index into the L1 table and into the L2 table for `offset`
+    fn indices(&self, offset: u64) -> (usize, u64) {
+        let cluster = offset >> self.cluster_bits;
+        let l2_entries = entries_per_cluster(self.cluster_bits, 64);
+
+        ((cluster / l2_entries) as usize, cluster % l2_entries)
+    }
+
+    /// The L2 entry of the cluster containing `offset`, zero if there's no
+    /// L2 table for it yet
+    fn l2_entry(&self, offset: u64) -> io::Result<u64> {
+        let (l1_index, l2_index) = self.indices(offset);
+        let l2_table = self.l1_table[l1_index] & OFFSET_MASK;
+
+        if l2_table == 0 {
+            return Ok(0);
+        }
+
+        read_be_u64(&self.file, l2_table + l2_index * 8)
+    }
+
+    /// Offset of the cluster an entry points to, if it's allocated
+    fn cluster_offset(&self, entry: u64) -> io::Result<Option<u64>> {
+        if entry & COMPRESSED != 0 {
+            return Err(unsupported("compressed clusters"));
+        }
+
+        let offset = entry & OFFSET_MASK;
+
+        if offset & (self.cluster_size() - 1) != 0 {
+            return Err(invalid_data("unaligned cluster"));
+        }
+
+        Ok((offset != 0).then_some(offset))
+    }
+
+    /// Split `len` bytes at `offset` into the parts within each cluster,
+    /// calling `f` with the offset of each part and its range in the buffer
+    fn for_each_cluster(
+        &mut self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(&mut Self, u64, std::ops::Range<usize>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if offset
+            .checked_add(len as u64)
+            .is_none_or(|end| end > self.size)
+        {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let mut done = 0;
+
+        while done < len {
+            let offset = offset + done as u64;
+            let in_cluster = (offset & (self.cluster_size() - 1)) as usize;
+            let chunk = (len - done).min(self.cluster_size() as usize - in_cluster);
+
+            f(self, offset, done..done + chunk)?;
+            done += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// Read within a single cluster
+    fn read_cluster(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let entry = self.l2_entry(offset)?;
+
+        if self.version >= 3 && entry & ZERO != 0 {
+            buf.fill(0);
+            return Ok(());
+        }
+
+        let in_cluster = offset & (self.cluster_size() - 1);
+
+        match (self.cluster_offset(entry)?, &self.backing) {
+            (Some(cluster), _) => self.file.read_exact_at(buf, cluster + in_cluster),
+            // The backing file may be smaller than the image
+            (None, Some(backing)) if offset < backing.size() => {
+                let len = buf.len().min((backing.size() - offset) as usize);
+
+                buf[len..].fill(0);
+                backing.read_at(&mut buf[..len], offset)
+            }
+            (None, _) => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    fn refcount_location(&self, cluster: u64) -> (usize, u64) {
+        let per_block = entries_per_cluster(self.cluster_bits, self.refcount_bits);
+        let index = cluster >> self.cluster_bits;
+
+        ((index / per_block) as usize, index % per_block)
+    }
+
+    fn refcount(&self, cluster: u64) -> io::Result<u64> {
+        let (table_index, block_index) = self.refcount_location(cluster);
+        let block = self
+            .refcount_table
+            .get(table_index)
+            .map_or(0, |entry| entry & OFFSET_MASK);
+
+        if block == 0 {
+            return Ok(0);
+        }
+
+        let width = (self.refcount_bits / 8) as usize;
+        let mut buf = [0; 8];
+        self.file
+            .read_exact_at(&mut buf[8 - width..], block + block_index * width as u64)?;
+
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn set_refcount(&mut self, cluster: u64, refcount: u64) -> io::Result<()> {
+        let (table_index, block_index) = self.refcount_location(cluster);
+
+        let Some(&entry) = self.refcount_table.get(table_index) else {
+            return Err(unsupported("growing the refcount table"));
+        };
+
+        let mut block = entry & OFFSET_MASK;
+
+        if block == 0 {
+            block = self.end;
+            self.end += self.cluster_size();
+
+            disk::write_zeroes(&self.file, block, self.cluster_size())?;
+            write_be_u64(
+                &self.file,
+                self.refcount_table_offset + table_index as u64 * 8,
+                block,
+            )?;
+            self.refcount_table[table_index] = block;
+
+            // The new block is usually right after the cluster it was made
+            // for, and so accounts for itself
+            self.set_refcount(block, 1)?;
+        }
+
+        let width = (self.refcount_bits / 8) as usize;
+        self.file.write_all_at(
+            &refcount.to_be_bytes()[8 - width..],
+            block + block_index * width as u64,
+        )
+    }
+
+    /// A cluster which isn't used by anything yet, with a refcount of one
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let cluster = match self.free_clusters.pop() {
+            Some(cluster) => cluster,
+            None => {
+                self.end += self.cluster_size();
+                self.end - self.cluster_size()
+            }
+        };
+
+        self.set_refcount(cluster, 1)?;
+
+        Ok(cluster)
+    }
+
+    fn release_cluster(&mut self, cluster: u64) -> io::Result<()> {
+        let refcount = self.refcount(cluster)?;
+
+        if refcount == 0 {
+            return Err(invalid_data("cluster is referenced more than counted"));
+        }
+
+        self.set_refcount(cluster, refcount - 1)?;
+
+        if refcount == 1 {
+            self.free_clusters.push(cluster);
+        }
+
+        Ok(())
+    }
+
+    /// Offset of the L2 table entry for `offset`, allocating the table if
+    /// there's none yet
+    fn l2_entry_for_write(&mut self, offset: u64) -> io::Result<u64> {
+        let (l1_index, l2_index) = self.indices(offset);
+        let entry = self.l1_table[l1_index];
+
+        let table = match self.cluster_offset(entry & !COPIED)? {
+            // Tables are only ever shared by snapshots
+            Some(table) if entry & COPIED != 0 || self.refcount(table)? == 1 => table,
+            Some(_) => return Err(invalid_data("L2 table is shared")),
+            None => {
+                let table = self.allocate_cluster()?;
+                disk::write_zeroes(&self.file, table, self.cluster_size())?;
+
+                table
+            }
+        };
+
+        if entry != table | COPIED {
+            write_be_u64(
+                &self.file,
+                self.l1_table_offset + l1_index as u64 * 8,
+                table | COPIED,
+            )?;
+            self.l1_table[l1_index] = table | COPIED;
+        }
+
+        Ok(table + l2_index * 8)
+    }
+
+    /// Point the L2 entry at `entry_addr` to `entry`, releasing the cluster
+    /// it pointed to before
+    fn replace_l2_entry(&mut self, entry_addr: u64, old: u64, entry: u64) -> io::Result<()> {
+        write_be_u64(&self.file, entry_addr, entry)?;
+
+        match self.cluster_offset(old)? {
+            Some(cluster) => self.release_cluster(cluster),
+            None => Ok(()),
+        }
+    }
+
+    /// Write within a single cluster, copying the rest of it from wherever
+    /// it was read from till now unless it's only referenced by this image
+    fn write_cluster(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let entry_addr = self.l2_entry_for_write(offset)?;
+        let entry = read_be_u64(&self.file, entry_addr)?;
+        let in_cluster = offset & (self.cluster_size() - 1);
+        let zero = self.version >= 3 && entry & ZERO != 0;
+
+        if let Some(cluster) = self.cluster_offset(entry)? {
+            if !zero && (entry & COPIED != 0 || self.refcount(cluster)? == 1) {
+                return self.file.write_all_at(buf, cluster + in_cluster);
+            }
+        }
+
+        let mut data = vec![0; self.cluster_size() as usize];
+
+        if buf.len() < data.len() {
+            self.read_cluster(&mut data, offset - in_cluster)?;
+        }
+
+        data[in_cluster as usize..][..buf.len()].copy_from_slice(buf);
+
+        // The data must be in place before anything points to it
+        let cluster = self.allocate_cluster()?;
+        self.file.write_all_at(&data, cluster)?;
+        self.replace_l2_entry(entry_addr, entry, cluster | COPIED)
+    }
+
+    /// Make a whole cluster read as zeroes, without any data of its own
+    fn zero_cluster(&mut self, offset: u64, unmap: bool) -> io::Result<()> {
+        let entry = self.l2_entry(offset)?;
+
+        if entry == 0 && self.backing.is_none() {
+            return Ok(());
+        }
+
+        // Version 2 images can only drop the cluster, which reveals the
+        // backing file if there's one
+        let zeroed = if self.version >= 3 {
+            ZERO
+        } else if unmap && self.backing.is_none() {
+            0
+        } else {
+            let zeroes = vec![0; self.cluster_size() as usize];
+            return self.write_cluster(&zeroes, offset);
+        };
+
+        if entry != zeroed {
+            let entry_addr = self.l2_entry_for_write(offset)?;
+            self.replace_l2_entry(entry_addr, entry, zeroed)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DiskImage for Qcow2Image {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        if offset
+            .checked_add(buf.len() as u64)
+            .is_none_or(|end| end > self.size)
+        {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let mut done = 0;
+
+        while done < buf.len() {
+            let offset = offset + done as u64;
+            let in_cluster = (offset & (self.cluster_size() - 1)) as usize;
+            let chunk = (buf.len() - done).min(self.cluster_size() as usize - in_cluster);
+
+            self.read_cluster(&mut buf[done..done + chunk], offset)?;
+            done += chunk;
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.for_each_cluster(offset, buf.len(), |image, offset, range| {
+            image.write_cluster(&buf[range], offset)
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn zero_range(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        let zeroes = vec![0; self.cluster_size() as usize];
+
+        self.for_each_cluster(offset, len as usize, |image, offset, range| {
+            if range.len() == zeroes.len() {
+                image.zero_cluster(offset, unmap)
+            } else {
+                image.write_cluster(&zeroes[..range.len()], offset)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        disk::{self, DiskImage},
+        qcow2::{self, Qcow2Image},
+    };
+    use std::{fs::File, io, os::unix::fs::FileExt, path::PathBuf};
+
+    /// A file in a fresh directory, removed along with it once dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("vmm-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            Self(dir)
+        }
+
+        fn create(&self, name: &str) -> File {
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(self.0.join(name))
+                .unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn clusters_are_allocated_on_write() {
+        let dir = TempDir::new("qcow2");
+        let file = dir.create("disk.qcow2");
+        qcow2::create(&file, 16 << 20, 12, None).unwrap();
+
+        let path = dir.0.join("disk.qcow2");
+        let mut image = disk::open(&path, false).unwrap();
+        let len = file.metadata().unwrap().len();
+        assert_eq!(image.size(), 16 << 20);
+
+        // Unallocated clusters read as zeroes, writes may span clusters
+        let mut buf = [0xff; 8];
+        image.read_at(&mut buf, 0x1234).unwrap();
+        assert_eq!(buf, [0; 8]);
+
+        image.write_at(&[1, 2, 3, 4, 5, 6, 7, 8], 0x1ffc).unwrap();
+        image.write_at(&[9; 4], 0xfff000).unwrap();
+        image.flush().unwrap();
+
+        // An L2 table for each end of the disk, and three data clusters
+        assert_eq!(file.metadata().unwrap().len(), len + 5 * 0x1000);
+
+        let image = Qcow2Image::open(file.try_clone().unwrap(), &path, true).unwrap();
+        image.read_at(&mut buf, 0x1ffc).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+        image.read_at(&mut buf, 0xfffff8).unwrap();
+        assert_eq!(buf, [0; 8]);
+        assert!(image.read_at(&mut buf, 16 << 20).is_err());
+
+        // Every cluster of the file is referenced exactly once
+        for cluster in (0..file.metadata().unwrap().len()).step_by(0x1000) {
+            assert_eq!(image.refcount(cluster).unwrap(), 1, "cluster {cluster:#x}");
+        }
+
+        let mut image = Qcow2Image::open(file.try_clone().unwrap(), &path, false).unwrap();
+        image.zero_range(0x1000, 0x2000, true).unwrap();
+        image.read_at(&mut buf, 0x1ffc).unwrap();
+        assert_eq!(buf, [0; 8]);
+
+        // The freed clusters are reused
+        image.write_at(&[1; 0x2000], 0x4000).unwrap();
+        assert_eq!(image.refcount(0x1000 * 6).unwrap(), 1);
+        assert_eq!(file.metadata().unwrap().len(), len + 5 * 0x1000);
+    }
+
+    #[test]
+    fn overlays_read_through_to_the_backing_file() {
+        let dir = TempDir::new("qcow2-backing");
+        let base = dir.create("base.raw");
+        base.write_all_at(&[0xaa; 0x3000], 0).unwrap();
+
+        // A chain of a raw base, an empty qcow2 layer and the overlay, which
+        // is larger than the base
+        let middle = dir.create("middle.qcow2");
+        qcow2::create(&middle, 0x3000, 9, Some("base.raw")).unwrap();
+        let overlay = dir.create("overlay.qcow2");
+        qcow2::create(&overlay, 0x4000, 12, Some("middle.qcow2")).unwrap();
+
+        let mut image = disk::open(&dir.0.join("overlay.qcow2"), false).unwrap();
+        let mut buf = [0; 0x4000];
+        image.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..0x3000], [0xaa; 0x3000]);
+        assert_eq!(buf[0x3000..], [0; 0x1000]);
+
+        // The rest of the cluster is copied from the backing file, which is
+        // left untouched
+        image.write_at(&[0x55; 2], 0x1fff).unwrap();
+        image.zero_range(0, 0x1000, false).unwrap();
+        image.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..0x1000], [0; 0x1000]);
+        assert_eq!(buf[0x1000..0x1fff], [0xaa; 0xfff]);
+        assert_eq!(buf[0x1fff..0x2001], [0x55; 2]);
+        assert_eq!(buf[0x2001..0x3000], [0xaa; 0xfff]);
+
+        let mut base_buf = [0; 0x3000];
+        base.read_exact_at(&mut base_buf, 0).unwrap();
+        assert_eq!(base_buf, [0xaa; 0x3000]);
+    }
+
+    #[test]
+    fn header_is_checked_and_updated() {
+        let dir = TempDir::new("qcow2-header");
+        let file = dir.create("disk.qcow2");
+        let path = dir.0.join("disk.qcow2");
+        qcow2::create(&file, 16 << 20, 12, None).unwrap();
+
+        // Autoclear features are cleared when opening the image for writing
+        file.write_all_at(&1u64.to_be_bytes(), 88).unwrap();
+        Qcow2Image::open(file.try_clone().unwrap(), &path, true).unwrap();
+        assert_eq!(qcow2::read_be_u64(&file, 88).unwrap(), 1);
+        Qcow2Image::open(file.try_clone().unwrap(), &path, false).unwrap();
+        assert_eq!(qcow2::read_be_u64(&file, 88).unwrap(), 0);
+
+        // An L1 table past the end of the file, or larger than the cap
+        for l1_size in [0x1000u32, u32::MAX] {
+            file.write_all_at(&l1_size.to_be_bytes(), 36).unwrap();
+            let err = Qcow2Image::open(file.try_clone().unwrap(), &path, true).err();
+            assert_eq!(err.unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // 128-bit refcounts
+        file.write_all_at(&8u32.to_be_bytes(), 36).unwrap();
+        file.write_all_at(&7u32.to_be_bytes(), 96).unwrap();
+        let err = Qcow2Image::open(file.try_clone().unwrap(), &path, true).err();
+        assert_eq!(err.unwrap().to_string(), "qcow2: refcount order 7");
+    }
+}
@@ -1,12 +1,14 @@
 use crate::{
     constants::{Cr0Flags, Cr4Flags, EferFlags, PageFlags, PageTables},
-    linux_loader::{CODE_SEGMENT, DATA_SEGMENT},
+    linux_loader::{CODE32_SEGMENT, CODE_SEGMENT, DATA_SEGMENT},
+    memory::{GuestMemory, MemoryError},
 };
 use kvm_bindings::{kvm_dtable, kvm_regs, kvm_segment, kvm_sregs};
 use std::{
     mem,
     mem::ManuallyDrop,
     ops::{Deref, DerefMut},
+    slice,
 };
 
 /// Wrap a value, executing the `cleanup` callback when it's dropped
@@ -105,29 +107,56 @@ pub fn pack_segment(segment: &kvm_segment) -> u64 {
 }
 
 /// Sets up the GDT according to the boot protocol
-pub fn setup_gdt(memory: &mut [u64]) {
+pub fn setup_gdt(memory: &GuestMemory) -> Result<(), MemoryError> {
+    let entry_size = mem::size_of::<u64>() as u64;
+
+    // 32-bit CS for PVH (0x8)
+    memory.write_obj(entry_size, &pack_segment(&CODE32_SEGMENT))?;
     // CS (0x10)
-    memory[2] = pack_segment(&CODE_SEGMENT);
+    memory.write_obj(2 * entry_size, &pack_segment(&CODE_SEGMENT))?;
     // DS (0x18)
-    memory[3] = pack_segment(&DATA_SEGMENT);
+    memory.write_obj(3 * entry_size, &pack_segment(&DATA_SEGMENT))?;
+
+    Ok(())
 }
 
-/// Sets up paging with identity mapping
-pub fn setup_paging(memory: &mut [u64]) {
-    let entry_size = mem::size_of::<u64>();
+/// Sets up paging with identity mapping, using 2MiB pages
+/// All of guest memory is mapped, up to the amount that Page Directories
+/// fit in the region reserved for them (see `PageTables::PD_END`), which is
+/// plenty for booting as the kernel sets up its own page tables right away
+pub fn setup_paging(memory: &GuestMemory) -> Result<(), MemoryError> {
+    let entry_size = mem::size_of::<u64>() as u64;
+    let page_size = 0x1000;
+
+    let max_pds = ((PageTables::PD_END - PageTables::PD) / page_size) as u64;
+    let pds = memory.end().div_ceil(1 << 30).clamp(1, max_pds);
 
-    memory[PageTables::PML4 / entry_size] =
-        PageFlags::PRESENT | PageFlags::READ_WRITE | PageTables::PDPT as u64;
-    memory[PageTables::PDPT / entry_size] =
-        PageFlags::PRESENT | PageFlags::READ_WRITE | PageTables::PD as u64;
+    memory.write_obj(
+        PageTables::PML4 as u64,
+        &(PageFlags::PRESENT | PageFlags::READ_WRITE | PageTables::PDPT as u64),
+    )?;
 
-    let pd = &mut memory[(PageTables::PD / entry_size)..][..512];
+    for pd in 0..pds {
+        let pd_addr = PageTables::PD as u64 + pd * page_size as u64;
 
-    // Identity Mapping
-    for (n, entry) in pd.iter_mut().enumerate() {
-        *entry =
-            PageFlags::PRESENT | PageFlags::READ_WRITE | PageFlags::PAGE_SIZE | ((n as u64) << 21);
+        memory.write_obj(
+            PageTables::PDPT as u64 + pd * entry_size,
+            &(PageFlags::PRESENT | PageFlags::READ_WRITE | pd_addr),
+        )?;
+
+        // Identity Mapping
+        for n in 0..512 {
+            memory.write_obj(
+                pd_addr + n * entry_size,
+                &(PageFlags::PRESENT
+                    | PageFlags::READ_WRITE
+                    | PageFlags::PAGE_SIZE
+                    | (((pd << 9) + n) << 21)),
+            )?;
+        }
     }
+
+    Ok(())
 }
 
 /// Setup the KVM segment registers in accordance with our paging & GDT setup
@@ -168,3 +197,97 @@ pub fn setup_regs(code64_start: u64, boot_params_addr: u64) -> kvm_regs {
         ..Default::default()
     }
 }
+
+/// Setup the KVM segment registers for the PVH entry point, which expects
+/// 32-bit protected mode with paging disabled
+pub fn setup_pvh_sregs() -> kvm_sregs {
+    kvm_sregs {
+        cr0: Cr0Flags::PE,
+        gdt: kvm_dtable {
+            base: 0,
+            ..Default::default()
+        },
+        cs: CODE32_SEGMENT,
+        ds: DATA_SEGMENT,
+        es: DATA_SEGMENT,
+        fs: DATA_SEGMENT,
+        gs: DATA_SEGMENT,
+        ss: DATA_SEGMENT,
+        ..Default::default()
+    }
+}
+
+/// Setup the KVM CPU registers in accordance with the PVH boot ABI
+pub fn setup_pvh_regs(entry: u32, start_info_addr: u64) -> kvm_regs {
+    kvm_regs {
+        rflags: 1 << 1,
+        rip: entry.into(),
+        // The `ebx` register must contain the address of the `hvm_start_info` struct
+        rbx: start_info_addr,
+        ..Default::default()
+    }
+}
+
+/// Plain old data, such as firmware tables, that can be viewed as bytes and
+/// created from arbitrary bytes
+///
+/// # Safety
+///
+/// The type must not contain any padding, and every bit pattern must be a
+/// valid value of it
+pub unsafe trait ByteValued: Copy {}
+
+macro_rules! byte_valued {
+    ($($ty:ty),*) => {
+        $(unsafe impl ByteValued for $ty {})*
+    };
+}
+
+byte_valued!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, usize, isize);
+
+unsafe impl<T: ByteValued, const N: usize> ByteValued for [T; N] {}
+
+/// View a plain old data structure, such as a firmware table, as bytes
+pub fn as_bytes<T: ByteValued>(val: &T) -> &[u8] {
+    // SAFETY: `ByteValued` guarantees that all the bytes are initialized
+    unsafe { slice::from_raw_parts((val as *const T).cast(), mem::size_of::<T>()) }
+}
+
+/// Value that makes all the bytes of a firmware table sum up to zero
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+/// Parse a human readable size such as `512M` or `4G`, suffixes are binary
+/// units (K, M, G, T), a plain number is taken as bytes
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (digits, shift) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 10),
+        'M' => (&size[..size.len() - 1], 20),
+        'G' => (&size[..size.len() - 1], 30),
+        'T' => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::parse_size;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("256M"), Some(256 << 20));
+        assert_eq!(parse_size("4g"), Some(4 << 30));
+        assert_eq!(parse_size("64G"), Some(64 << 30));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+}
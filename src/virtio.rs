@@ -0,0 +1,178 @@
+//! Transport independent parts of virtio devices, ref:
+//! https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+
+use crate::{devices::Interrupt, memory::GuestMemory, memory::MemoryError};
+use std::{
+    fmt, io,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+/// Device status bits, set by the driver as it initializes the device
+#[allow(non_snake_case)]
+pub mod DeviceStatus {
+    /// The guest noticed the device
+    pub const ACKNOWLEDGE: u8 = 1 << 0;
+    /// The guest knows how to drive the device
+    pub const DRIVER: u8 = 1 << 1;
+    /// The driver is set up and ready to drive the device
+    pub const DRIVER_OK: u8 = 1 << 2;
+    /// Feature negotiation is complete
+    pub const FEATURES_OK: u8 = 1 << 3;
+    /// Set by the device when it hit an error it can't recover from
+    pub const DEVICE_NEEDS_RESET: u8 = 1 << 6;
+    /// The driver gave up on the device
+    pub const FAILED: u8 = 1 << 7;
+}
+
+/// Feature bits that aren't specific to a device type
+#[allow(non_snake_case)]
+pub mod Features {
+    /// Descriptors may point to a table of descriptors
+    pub const RING_INDIRECT_DESC: u64 = 1 << 28;
+    /// The `used_event` and `avail_event` fields suppress notifications
+    pub const RING_EVENT_IDX: u64 = 1 << 29;
+    /// Compliance with the virtio 1.0 spec, as opposed to legacy devices
+    pub const VERSION_1: u64 = 1 << 32;
+    /// Packed virtqueues instead of split ones
+    pub const RING_PACKED: u64 = 1 << 34;
+    /// Buffers are used in the order they were made available
+    pub const IN_ORDER: u64 = 1 << 35;
+}
+
+/// Bits of the interrupt status
+#[allow(non_snake_case)]
+pub mod InterruptStatus {
+    /// Buffers were added to the used ring of a queue
+    pub const USED_BUFFER: u32 = 1 << 0;
+    /// The configuration space changed
+    pub const CONFIG_CHANGE: u32 = 1 << 1;
+}
+
+#[derive(Debug)]
+pub enum VirtioError {
+    /// The driver set up the queue with an invalid size or address
+    InvalidQueue(u16),
+    /// The driver made a malformed descriptor chain available in a queue
+    InvalidChain(u16),
+    /// The driver pointed the device at memory outside of RAM
+    Memory(MemoryError),
+    Io(io::Error),
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidQueue(index) => write!(f, "queue {index} is set up incorrectly"),
+            Self::InvalidChain(index) => write!(f, "malformed descriptor chain in queue {index}"),
+            Self::Memory(err) => write!(f, "invalid guest memory access: {err}"),
+            Self::Io(err) => write!(f, "I/O error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for VirtioError {}
+
+impl From<MemoryError> for VirtioError {
+    fn from(err: MemoryError) -> Self {
+        Self::Memory(err)
+    }
+}
+
+impl From<io::Error> for VirtioError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// A queue as set up by the driver through the transport
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Largest size the device supports
+    pub max_size: u16,
+    /// Number of descriptors chosen by the driver
+    pub size: u16,
+    pub ready: bool,
+    /// Guest physical address of the descriptor table
+    pub desc_table: u64,
+    /// Guest physical address of the driver area, the available ring
+    pub driver_area: u64,
+    /// Guest physical address of the device area, the used ring
+    pub device_area: u64,
+}
+
+/// Notifies the driver, the interrupt status is shared with the transport
+/// which lets the driver read and acknowledge it
+#[derive(Clone)]
+pub struct VirtioInterrupt {
+    status: Arc<AtomicU32>,
+    interrupt: Arc<Interrupt>,
+}
+
+impl VirtioInterrupt {
+    pub fn new(interrupt: Interrupt) -> Self {
+        Self {
+            status: Arc::default(),
+            interrupt: Arc::new(interrupt),
+        }
+    }
+
+    fn signal(&self, status: u32) -> io::Result<()> {
+        self.status.fetch_or(status, Ordering::SeqCst);
+        self.interrupt.trigger()
+    }
+
+    /// Tell the driver that buffers were used
+    pub fn signal_used_buffer(&self) -> io::Result<()> {
+        self.signal(InterruptStatus::USED_BUFFER)
+    }
+
+    /// Tell the driver that the configuration space changed
+    pub fn signal_config_change(&self) -> io::Result<()> {
+        self.signal(InterruptStatus::CONFIG_CHANGE)
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status.load(Ordering::SeqCst)
+    }
+
+    /// Clear the bits handled by the driver
+    pub fn ack(&self, status: u32) {
+        self.status.fetch_and(!status, Ordering::SeqCst);
+    }
+}
+
+/// The device specific part of a virtio device, driven by a transport
+pub trait VirtioDevice: Send {
+    /// Device ID, such as 2 for block devices
+    fn device_type(&self) -> u32;
+
+    /// Features offered to the driver, `VERSION_1` is always required
+    fn features(&self) -> u64;
+
+    /// Largest size of each queue, one entry per queue
+    fn queue_max_sizes(&self) -> &[u16];
+
+    /// Fill `data` from `offset` into the configuration space
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    /// Handle the driver writing to the configuration space, ignored by default
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// The driver is ready, the queues that it enabled may be used from now on
+    fn activate(
+        &mut self,
+        memory: Arc<GuestMemory>,
+        features: u64,
+        queues: &[QueueConfig],
+        interrupt: VirtioInterrupt,
+    ) -> Result<(), VirtioError>;
+
+    /// The driver made new buffers available in queue `index`
+    fn queue_notify(&mut self, index: u16) -> Result<(), VirtioError>;
+
+    /// Go back to the initial state, forgetting about the queues
+    fn reset(&mut self);
+}
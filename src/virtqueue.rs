@@ -0,0 +1,1167 @@
+//! Virtqueues, through which the driver hands buffers to the device, ref:
+//! https://docs.oasis-open.org/virtio/virtio/v1.2/cs01/virtio-v1.2-cs01.html#x1-270006
+
+use crate::{
+    memory::GuestMemory,
+    util::ByteValued,
+    virtio::{Features, QueueConfig, VirtioError},
+};
+use std::{
+    io, mem,
+    sync::{
+        atomic::{fence, Ordering},
+        Arc,
+    },
+};
+
+/// Flags of a descriptor
+#[allow(non_snake_case)]
+pub mod DescriptorFlags {
+    /// The chain continues with the descriptor in `next`
+    pub const NEXT: u16 = 1 << 0;
+    /// The buffer is written by the device instead of read
+    pub const WRITE: u16 = 1 << 1;
+    /// The buffer holds a table of descriptors
+    pub const INDIRECT: u16 = 1 << 2;
+}
+
+/// Flags only found in the ring of a packed queue
+#[allow(non_snake_case)]
+mod PackedFlags {
+    /// Matches the driver's wrap counter once the entry is made available
+    pub const AVAIL: u16 = 1 << 7;
+    /// Matches the device's wrap counter once the entry is used
+    pub const USED: u16 = 1 << 15;
+}
+
+/// Flags of the event suppression structures of packed queues
+#[allow(non_snake_case)]
+mod EventFlags {
+    pub const ENABLE: u16 = 0;
+    pub const DISABLE: u16 = 1;
+    /// Only notify once the entry in `off_wrap` is reached, which requires
+    /// `RING_EVENT_IDX`
+    pub const DESC: u16 = 2;
+}
+
+/// The driver doesn't want to be interrupted, without `RING_EVENT_IDX`
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// Largest size of a queue, and of an indirect table
+const MAX_QUEUE_SIZE: u16 = 32768;
+/// Size of a descriptor of either layout
+const DESC_SIZE: u32 = 16;
+
+/// A descriptor as laid out in a split queue's descriptor table
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct SplitDescriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A descriptor as laid out in a packed queue's ring and indirect tables
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct PackedDescriptor {
+    addr: u64,
+    len: u32,
+    /// Buffer ID, handed back to the driver once the chain is used
+    id: u16,
+    flags: u16,
+}
+
+/// Tells the other side of a packed queue when to send notifications
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct EventSuppression {
+    /// Entry and wrap counter in bit 15, with `EventFlags::DESC`
+    off_wrap: u16,
+    flags: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+// SAFETY: the descriptors and ring entries are only made of integers, with
+// their fields naturally aligned so there's no padding
+unsafe impl ByteValued for SplitDescriptor {}
+unsafe impl ByteValued for PackedDescriptor {}
+unsafe impl ByteValued for EventSuppression {}
+unsafe impl ByteValued for UsedElem {}
+
+/// A buffer in guest memory, already checked to be backed by RAM
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    /// Whether the device writes to the buffer, or only reads it
+    pub write_only: bool,
+}
+
+/// The buffers making up a request, all device-readable buffers come before
+/// the device-writable ones
+#[derive(Clone, Debug)]
+pub struct DescriptorChain {
+    head: u16,
+    /// Entries of the ring taken up by the chain, for packed queues
+    ring_entries: u16,
+    descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// ID of the chain, the index of its first descriptor in split queues
+    /// and the buffer ID in packed ones
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+
+    /// Read the device-readable part of the chain in order
+    pub fn reader<'a>(&'a self, memory: &'a GuestMemory) -> ChainReader<'a> {
+        ChainReader(ChainCursor::new(
+            memory,
+            self.descriptors.iter().filter(|desc| !desc.write_only),
+        ))
+    }
+
+    /// Write the device-writable part of the chain in order
+    pub fn writer<'a>(&'a self, memory: &'a GuestMemory) -> ChainWriter<'a> {
+        ChainWriter(ChainCursor::new(
+            memory,
+            self.descriptors.iter().filter(|desc| desc.write_only),
+        ))
+    }
+}
+
+/// Position in a list of buffers, used by both the reader and the writer
+struct ChainCursor<'a> {
+    memory: &'a GuestMemory,
+    descriptors: Vec<Descriptor>,
+    /// Offset into the current descriptor, the last one
+    offset: u32,
+    done: usize,
+}
+
+impl<'a> ChainCursor<'a> {
+    fn new(memory: &'a GuestMemory, descriptors: impl Iterator<Item = &'a Descriptor>) -> Self {
+        let mut descriptors = descriptors.copied().collect::<Vec<_>>();
+        descriptors.reverse();
+
+        Self {
+            memory,
+            descriptors,
+            offset: 0,
+            done: 0,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        let total = self
+            .descriptors
+            .iter()
+            .map(|desc| desc.len as usize)
+            .sum::<usize>();
+
+        total - self.offset as usize
+    }
+
+    /// Call `f` with the guest address of as many bytes as possible out of
+    /// `len`, all in the current descriptor
+    fn advance(
+        &mut self,
+        len: usize,
+        f: impl FnOnce(u64, usize) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        let Some(desc) = self.descriptors.last().copied() else {
+            return Ok(0);
+        };
+
+        let chunk = len.min((desc.len - self.offset) as usize);
+        f(desc.addr + u64::from(self.offset), chunk)?;
+
+        self.offset += chunk as u32;
+        self.done += chunk;
+
+        if self.offset == desc.len {
+            self.descriptors.pop();
+            self.offset = 0;
+        }
+
+        Ok(chunk)
+    }
+}
+
+/// Reads the device-readable buffers of a chain as one stream of bytes
+pub struct ChainReader<'a>(ChainCursor<'a>);
+
+impl ChainReader<'_> {
+    /// Bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.0.remaining()
+    }
+
+    /// Read a plain-old-data object
+    pub fn read_obj<T: ByteValued + Default>(&mut self) -> io::Result<T> {
+        let mut obj = T::default();
+
+        // SAFETY: `T` is plain old data and `buf` covers exactly `obj`
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(&mut obj as *mut T as *mut u8, mem::size_of::<T>())
+        };
+        io::Read::read_exact(self, buf)?;
+
+        Ok(obj)
+    }
+}
+
+impl io::Read for ChainReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let memory = self.0.memory;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let chunk = self.0.advance(buf.len() - done, |addr, len| {
+                memory
+                    .read_slice(addr, &mut buf[done..done + len])
+                    .map_err(io::Error::other)
+            })?;
+
+            if chunk == 0 {
+                break;
+            }
+
+            done += chunk;
+        }
+
+        Ok(done)
+    }
+}
+
+/// Writes the device-writable buffers of a chain as one stream of bytes
+pub struct ChainWriter<'a>(ChainCursor<'a>);
+
+impl ChainWriter<'_> {
+    /// Bytes left to write
+    pub fn remaining(&self) -> usize {
+        self.0.remaining()
+    }
+
+    /// Bytes written so far, which is reported to the driver once the chain
+    /// is used
+    pub fn written(&self) -> usize {
+        self.0.done
+    }
+
+    pub fn write_obj<T: ByteValued>(&mut self, obj: &T) -> io::Result<()> {
+        // SAFETY: `buf` covers exactly `obj`, which outlives it
+        let buf = unsafe {
+            std::slice::from_raw_parts(obj as *const T as *const u8, mem::size_of::<T>())
+        };
+
+        io::Write::write_all(self, buf)
+    }
+}
+
+impl io::Write for ChainWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let memory = self.0.memory;
+        let mut done = 0;
+
+        while done < buf.len() {
+            let chunk = self.0.advance(buf.len() - done, |addr, len| {
+                memory
+                    .write_slice(addr, &buf[done..done + len])
+                    .map_err(io::Error::other)
+            })?;
+
+            if chunk == 0 {
+                break;
+            }
+
+            done += chunk;
+        }
+
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Check that `len` bytes at `addr` are aligned to `align` and backed by RAM
+fn check_area(memory: &GuestMemory, addr: u64, len: usize, align: u64) -> bool {
+    addr & (align - 1) == 0 && memory.check_range(addr, len)
+}
+
+/// Number of descriptors in the indirect table of `len` bytes at `addr`,
+/// unless the table is empty or not backed by RAM
+fn indirect_entries(memory: &GuestMemory, addr: u64, len: u32) -> Option<u16> {
+    let entries = len / DESC_SIZE;
+
+    (entries != 0
+        && len.is_multiple_of(DESC_SIZE)
+        && entries <= u32::from(MAX_QUEUE_SIZE)
+        && check_area(memory, addr, len as usize, 1))
+    .then_some(entries as u16)
+}
+
+/// Collects the buffers of a chain, checking each of them as it's added
+struct ChainBuilder<'a> {
+    memory: &'a GuestMemory,
+    descriptors: Vec<Descriptor>,
+    total_len: u64,
+}
+
+impl<'a> ChainBuilder<'a> {
+    fn new(memory: &'a GuestMemory) -> Self {
+        Self {
+            memory,
+            descriptors: Vec::new(),
+            total_len: 0,
+        }
+    }
+
+    /// Add a buffer, unless it isn't backed by RAM, makes the chain longer
+    /// than 4GiB or is device-readable and follows a device-writable buffer.
+    /// Empty buffers are left out, the readers and writers would stop at them
+    fn push(&mut self, addr: u64, len: u32, flags: u16) -> bool {
+        let write_only = flags & DescriptorFlags::WRITE != 0;
+
+        if !write_only && self.descriptors.last().is_some_and(|desc| desc.write_only) {
+            return false;
+        }
+
+        if len == 0 {
+            return true;
+        }
+
+        self.total_len += u64::from(len);
+
+        if self.total_len > u64::from(u32::MAX) || !self.memory.check_range(addr, len as usize) {
+            return false;
+        }
+
+        self.descriptors.push(Descriptor {
+            addr,
+            len,
+            write_only,
+        });
+
+        true
+    }
+
+    fn finish(self, head: u16, ring_entries: u16) -> DescriptorChain {
+        DescriptorChain {
+            head,
+            ring_entries,
+            descriptors: self.descriptors,
+        }
+    }
+}
+
+/// A virtqueue of either layout, depending on the features negotiated with
+/// the driver, which lets each device offer packed queues or not
+pub enum Queue {
+    Split(SplitQueue),
+    Packed(PackedQueue),
+}
+
+impl Queue {
+    /// Take over queue `index` as set up by the driver, using the layout
+    /// and features it negotiated
+    pub fn new(
+        memory: Arc<GuestMemory>,
+        index: u16,
+        config: &QueueConfig,
+        features: u64,
+    ) -> Result<Self, VirtioError> {
+        if features & Features::RING_PACKED != 0 {
+            PackedQueue::new(memory, index, config, features).map(Self::Packed)
+        } else {
+            SplitQueue::new(memory, index, config, features).map(Self::Split)
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        match self {
+            Self::Split(queue) => queue.size(),
+            Self::Packed(queue) => queue.size(),
+        }
+    }
+
+    /// Take the next chain made available by the driver, if any
+    pub fn pop(&mut self) -> Result<Option<DescriptorChain>, VirtioError> {
+        match self {
+            Self::Split(queue) => queue.pop(),
+            Self::Packed(queue) => queue.pop(),
+        }
+    }
+
+    /// Hand `chain` back to the driver, after writing `len` bytes to its
+    /// buffers
+    pub fn add_used(&mut self, chain: &DescriptorChain, len: u32) -> Result<(), VirtioError> {
+        match self {
+            Self::Split(queue) => queue.add_used(chain, len),
+            Self::Packed(queue) => queue.add_used(chain, len),
+        }
+    }
+
+    /// Whether the driver wants an interrupt for the chains used since the
+    /// last call
+    pub fn needs_notification(&mut self) -> Result<bool, VirtioError> {
+        match self {
+            Self::Split(queue) => queue.needs_notification(),
+            Self::Packed(queue) => queue.needs_notification(),
+        }
+    }
+}
+
+/// A split virtqueue, made up of a descriptor table, the available ring
+/// written by the driver and the used ring written by the device
+pub struct SplitQueue {
+    memory: Arc<GuestMemory>,
+    index: u16,
+    size: u16,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+    indirect: bool,
+    event_idx: bool,
+    /// Next entry of the available ring to take a chain from
+    next_avail: u16,
+    /// Next entry of the used ring to put a chain into
+    next_used: u16,
+    /// Value of `next_used` the last time the driver was considered for a
+    /// notification
+    signalled_used: u16,
+}
+
+impl SplitQueue {
+    /// Take over queue `index` as set up by the driver, with the features
+    /// it negotiated
+    pub fn new(
+        memory: Arc<GuestMemory>,
+        index: u16,
+        config: &QueueConfig,
+        features: u64,
+    ) -> Result<Self, VirtioError> {
+        let size = config.size;
+        let invalid = || VirtioError::InvalidQueue(index);
+
+        if !config.ready
+            || !size.is_power_of_two()
+            || size > config.max_size
+            || size > MAX_QUEUE_SIZE
+        {
+            return Err(invalid());
+        }
+
+        let entries = usize::from(size);
+        let desc_size = DESC_SIZE as usize * entries;
+        // flags, idx, ring and used_event
+        let avail_size = 6 + 2 * entries;
+        // flags, idx, ring and avail_event
+        let used_size = 6 + mem::size_of::<UsedElem>() * entries;
+
+        if !check_area(&memory, config.desc_table, desc_size, 16)
+            || !check_area(&memory, config.driver_area, avail_size, 2)
+            || !check_area(&memory, config.device_area, used_size, 4)
+        {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            memory,
+            index,
+            size,
+            desc_table: config.desc_table,
+            avail_ring: config.driver_area,
+            used_ring: config.device_area,
+            indirect: features & Features::RING_INDIRECT_DESC != 0,
+            event_idx: features & Features::RING_EVENT_IDX != 0,
+            next_avail: 0,
+            next_used: 0,
+            signalled_used: 0,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn avail_idx(&self) -> Result<u16, VirtioError> {
+        let idx = self.memory.read_obj(self.avail_ring + 2)?;
+
+        // The ring entries must not be read before the index
+        fence(Ordering::Acquire);
+
+        Ok(idx)
+    }
+
+    /// Set `avail_event`, asking the driver to notify once it made the entry
+    /// after it available
+    fn set_avail_event(&self, idx: u16) -> Result<(), VirtioError> {
+        let addr = self.used_ring + 4 + 8 * u64::from(self.size);
+        self.memory.write_obj(addr, &idx)?;
+
+        Ok(())
+    }
+
+    /// Take the next chain made available by the driver, if any
+    pub fn pop(&mut self) -> Result<Option<DescriptorChain>, VirtioError> {
+        let mut avail_idx = self.avail_idx()?;
+
+        if avail_idx == self.next_avail && self.event_idx {
+            // The driver may have added a chain before seeing the new event
+            self.set_avail_event(self.next_avail)?;
+            fence(Ordering::SeqCst);
+
+            avail_idx = self.avail_idx()?;
+        }
+
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+
+        // The driver never has more chains in flight than the queue's size
+        if avail_idx.wrapping_sub(self.next_avail) > self.size {
+            return Err(VirtioError::InvalidQueue(self.index));
+        }
+
+        let slot = u64::from(self.next_avail % self.size);
+        let head: u16 = self.memory.read_obj(self.avail_ring + 4 + 2 * slot)?;
+
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        if self.event_idx {
+            self.set_avail_event(self.next_avail)?;
+        }
+
+        self.read_chain(head).map(Some)
+    }
+
+    fn read_descriptor(&self, table: u64, index: u16) -> Result<SplitDescriptor, VirtioError> {
+        let addr = table + u64::from(index) * u64::from(DESC_SIZE);
+
+        Ok(self.memory.read_obj(addr)?)
+    }
+
+    /// Follow the chain starting at `head`, flattening indirect tables
+    fn read_chain(&self, head: u16) -> Result<DescriptorChain, VirtioError> {
+        let invalid = || VirtioError::InvalidChain(self.index);
+
+        if head >= self.size {
+            return Err(invalid());
+        }
+
+        let mut chain = ChainBuilder::new(&self.memory);
+        let mut table = self.desc_table;
+        let mut table_size = self.size;
+        let mut indirect = false;
+        let mut index = head;
+        // Every descriptor may only be visited once, or the chain loops
+        let mut budget = self.size;
+
+        loop {
+            if budget == 0 {
+                return Err(invalid());
+            }
+            budget -= 1;
+
+            let desc = self.read_descriptor(table, index)?;
+
+            if desc.flags & DescriptorFlags::INDIRECT != 0 {
+                // Indirect tables can't be nested or followed by anything
+                if !self.indirect || indirect || desc.flags & DescriptorFlags::NEXT != 0 {
+                    return Err(invalid());
+                }
+
+                table_size =
+                    indirect_entries(&self.memory, desc.addr, desc.len).ok_or_else(invalid)?;
+                table = desc.addr;
+                budget = table_size;
+                indirect = true;
+                index = 0;
+                continue;
+            }
+
+            if !chain.push(desc.addr, desc.len, desc.flags) {
+                return Err(invalid());
+            }
+
+            if desc.flags & DescriptorFlags::NEXT == 0 {
+                break;
+            }
+
+            if desc.next >= table_size {
+                return Err(invalid());
+            }
+
+            index = desc.next;
+        }
+
+        Ok(chain.finish(head, 1))
+    }
+
+    /// Hand `chain` back to the driver, after writing `len` bytes to its
+    /// buffers
+    pub fn add_used(&mut self, chain: &DescriptorChain, len: u32) -> Result<(), VirtioError> {
+        let slot = u64::from(self.next_used % self.size);
+        let elem = UsedElem {
+            id: u32::from(chain.head),
+            len,
+        };
+
+        self.memory
+            .write_obj(self.used_ring + 4 + 8 * slot, &elem)?;
+        self.next_used = self.next_used.wrapping_add(1);
+
+        // The entry must be visible before the index
+        fence(Ordering::Release);
+        self.memory.write_obj(self.used_ring + 2, &self.next_used)?;
+
+        Ok(())
+    }
+
+    /// Whether the driver wants an interrupt for the chains used since the
+    /// last call
+    pub fn needs_notification(&mut self) -> Result<bool, VirtioError> {
+        // The used index must be visible before reading what the driver wants
+        fence(Ordering::SeqCst);
+
+        let old = mem::replace(&mut self.signalled_used, self.next_used);
+        let new = self.next_used;
+
+        if !self.event_idx {
+            let flags: u16 = self.memory.read_obj(self.avail_ring)?;
+            return Ok(new != old && flags & AVAIL_F_NO_INTERRUPT == 0);
+        }
+
+        let used_event: u16 = self
+            .memory
+            .read_obj(self.avail_ring + 4 + 2 * u64::from(self.size))?;
+
+        // Whether `used_event` was passed by the chains used since
+        Ok(need_event(used_event, new, old))
+    }
+}
+
+/// Whether moving from `old` to `new` passed `event`, which is where the
+/// other side asked to be notified
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// Position in the ring of a packed queue, which flips the wrap counter
+/// every time it goes past the end
+#[derive(Clone, Copy, Debug)]
+struct RingPosition {
+    index: u16,
+    wrap_counter: bool,
+}
+
+impl RingPosition {
+    fn advance(&mut self, entries: u16, size: u16) {
+        let next = u32::from(self.index) + u32::from(entries);
+
+        if next >= u32::from(size) {
+            self.wrap_counter = !self.wrap_counter;
+        }
+
+        self.index = (next % u32::from(size)) as u16;
+    }
+
+    /// The position as stored in `off_wrap` of event suppression structures
+    fn off_wrap(self) -> u16 {
+        self.index | u16::from(self.wrap_counter) << 15
+    }
+}
+
+/// A packed virtqueue, a single ring of descriptors which are marked as
+/// available by the driver, and then overwritten and marked as used by the
+/// device
+pub struct PackedQueue {
+    memory: Arc<GuestMemory>,
+    index: u16,
+    size: u16,
+    ring: u64,
+    /// Driver event suppression, whether the driver wants interrupts
+    driver_event: u64,
+    /// Device event suppression, whether the device wants notifications
+    device_event: u64,
+    indirect: bool,
+    event_idx: bool,
+    next_avail: RingPosition,
+    next_used: RingPosition,
+    /// Ring entries used since the driver was last considered for a
+    /// notification
+    unsignalled: u16,
+}
+
+impl PackedQueue {
+    /// Take over queue `index` as set up by the driver, with the features
+    /// it negotiated
+    pub fn new(
+        memory: Arc<GuestMemory>,
+        index: u16,
+        config: &QueueConfig,
+        features: u64,
+    ) -> Result<Self, VirtioError> {
+        let size = config.size;
+
+        // Packed queues don't have to be a power of two
+        if !config.ready
+            || size == 0
+            || size > config.max_size
+            || size > MAX_QUEUE_SIZE
+            || !check_area(
+                &memory,
+                config.desc_table,
+                DESC_SIZE as usize * usize::from(size),
+                16,
+            )
+            || !check_area(&memory, config.driver_area, 4, 4)
+            || !check_area(&memory, config.device_area, 4, 4)
+        {
+            return Err(VirtioError::InvalidQueue(index));
+        }
+
+        let start = RingPosition {
+            index: 0,
+            wrap_counter: true,
+        };
+
+        let queue = Self {
+            memory,
+            index,
+            size,
+            ring: config.desc_table,
+            driver_event: config.driver_area,
+            device_event: config.device_area,
+            indirect: features & Features::RING_INDIRECT_DESC != 0,
+            event_idx: features & Features::RING_EVENT_IDX != 0,
+            next_avail: start,
+            next_used: start,
+            unsignalled: 0,
+        };
+
+        queue.set_device_event(EventFlags::ENABLE, start)?;
+
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn set_device_event(&self, flags: u16, position: RingPosition) -> Result<(), VirtioError> {
+        let event = EventSuppression {
+            off_wrap: position.off_wrap(),
+            flags,
+        };
+
+        self.memory.write_obj(self.device_event, &event)?;
+
+        Ok(())
+    }
+
+    fn read_descriptor(&self, addr: u64) -> Result<PackedDescriptor, VirtioError> {
+        let desc = self.memory.read_obj(addr)?;
+
+        // The rest of the chain must not be read before the flags
+        fence(Ordering::Acquire);
+
+        Ok(desc)
+    }
+
+    fn ring_addr(&self, index: u16) -> u64 {
+        self.ring + u64::from(index) * u64::from(DESC_SIZE)
+    }
+
+    fn is_available(flags: u16, wrap_counter: bool) -> bool {
+        let avail = flags & PackedFlags::AVAIL != 0;
+        let used = flags & PackedFlags::USED != 0;
+
+        avail == wrap_counter && used != wrap_counter
+    }
+
+    /// Take the next chain made available by the driver, if any
+    pub fn pop(&mut self) -> Result<Option<DescriptorChain>, VirtioError> {
+        let invalid = || VirtioError::InvalidChain(self.index);
+        let start = self.next_avail;
+        let mut desc = self.read_descriptor(self.ring_addr(start.index))?;
+
+        if !Self::is_available(desc.flags, start.wrap_counter) {
+            if self.event_idx {
+                // Ask to be notified about the very next chain, then check
+                // whether the driver added it before seeing the event
+                self.set_device_event(EventFlags::DESC, start)?;
+                fence(Ordering::SeqCst);
+
+                desc = self.read_descriptor(self.ring_addr(start.index))?;
+            }
+
+            if !Self::is_available(desc.flags, start.wrap_counter) {
+                return Ok(None);
+            }
+        }
+
+        let mut chain = ChainBuilder::new(&self.memory);
+        let mut index = start.index;
+        let mut entries = 0;
+
+        // The rest of the chain follows in the ring, only the first entry is
+        // marked available last
+        loop {
+            if entries == self.size {
+                return Err(invalid());
+            }
+            entries += 1;
+
+            if desc.flags & DescriptorFlags::INDIRECT != 0 {
+                if !self.indirect || desc.flags & DescriptorFlags::NEXT != 0 {
+                    return Err(invalid());
+                }
+
+                let table_size =
+                    indirect_entries(&self.memory, desc.addr, desc.len).ok_or_else(invalid)?;
+
+                // All entries of the table belong to the chain, in order
+                for n in 0..u64::from(table_size) {
+                    let entry: PackedDescriptor =
+                        self.memory.read_obj(desc.addr + n * u64::from(DESC_SIZE))?;
+
+                    if entry.flags & DescriptorFlags::INDIRECT != 0
+                        || !chain.push(entry.addr, entry.len, entry.flags)
+                    {
+                        return Err(invalid());
+                    }
+                }
+
+                break;
+            }
+
+            if !chain.push(desc.addr, desc.len, desc.flags) {
+                return Err(invalid());
+            }
+
+            if desc.flags & DescriptorFlags::NEXT == 0 {
+                break;
+            }
+
+            index = (index + 1) % self.size;
+            desc = self.memory.read_obj(self.ring_addr(index))?;
+        }
+
+        self.next_avail.advance(entries, self.size);
+
+        if self.event_idx {
+            self.set_device_event(EventFlags::DESC, self.next_avail)?;
+        }
+
+        // The buffer ID is taken from the last entry of the chain
+        Ok(Some(chain.finish(desc.id, entries)))
+    }
+
+    /// Hand `chain` back to the driver, after writing `len` bytes to its
+    /// buffers, which takes up a single entry regardless of the chain's size
+    pub fn add_used(&mut self, chain: &DescriptorChain, len: u32) -> Result<(), VirtioError> {
+        let addr = self.ring_addr(self.next_used.index);
+        // Both bits match the wrap counter once used
+        let flags = if self.next_used.wrap_counter {
+            PackedFlags::AVAIL | PackedFlags::USED
+        } else {
+            0
+        };
+
+        self.memory.write_obj(addr + 8, &len)?;
+        self.memory.write_obj(addr + 12, &chain.head)?;
+
+        // The driver may reuse the entry once the flags are visible
+        fence(Ordering::Release);
+        self.memory.write_obj(addr + 14, &flags)?;
+
+        // The used entries are skipped over as if the whole chain was used,
+        // which keeps the device in step with the driver
+        self.next_used.advance(chain.ring_entries, self.size);
+        self.unsignalled = self.unsignalled.saturating_add(chain.ring_entries);
+
+        Ok(())
+    }
+
+    /// Whether the driver wants an interrupt for the chains used since the
+    /// last call
+    pub fn needs_notification(&mut self) -> Result<bool, VirtioError> {
+        // The used entries must be visible before reading what the driver
+        // wants
+        fence(Ordering::SeqCst);
+
+        let used = mem::take(&mut self.unsignalled);
+        let event: EventSuppression = self.memory.read_obj(self.driver_event)?;
+
+        match event.flags {
+            _ if used == 0 => Ok(false),
+            EventFlags::ENABLE => Ok(true),
+            EventFlags::DISABLE => Ok(false),
+            EventFlags::DESC if self.event_idx => {
+                let new = self.next_used.index;
+                let old = new.wrapping_sub(used);
+                let mut event_idx = event.off_wrap & !(1 << 15);
+
+                // The event is expressed in the driver's lap of the ring,
+                // the indices are only compared relative to each other
+                if (event.off_wrap >> 15 != 0) != self.next_used.wrap_counter {
+                    event_idx = event_idx.wrapping_sub(self.size);
+                }
+
+                Ok(need_event(event_idx, new, old))
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        memory::GuestMemory,
+        virtio::{Features, QueueConfig, VirtioError},
+        virtqueue::{Descriptor, DescriptorFlags, PackedFlags, Queue, SplitQueue},
+    };
+    use std::{
+        io::{Read, Write},
+        sync::Arc,
+    };
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const SIZE: u16 = 8;
+
+    fn setup(features: u64) -> (Arc<GuestMemory>, SplitQueue) {
+        let memory = Arc::new(GuestMemory::with_ram(1 << 20).unwrap());
+        let config = QueueConfig {
+            max_size: 256,
+            size: SIZE,
+            ready: true,
+            desc_table: DESC_TABLE,
+            driver_area: AVAIL_RING,
+            device_area: USED_RING,
+        };
+
+        let queue = SplitQueue::new(memory.clone(), 0, &config, features).unwrap();
+        (memory, queue)
+    }
+
+    fn write_desc(memory: &GuestMemory, table: u64, index: u16, desc: (u64, u32, u16, u16)) {
+        let addr = table + u64::from(index) * 16;
+        memory.write_obj(addr, &desc.0).unwrap();
+        memory.write_obj(addr + 8, &desc.1).unwrap();
+        memory.write_obj(addr + 12, &desc.2).unwrap();
+        memory.write_obj(addr + 14, &desc.3).unwrap();
+    }
+
+    /// Make the chain at `head` available as the `n`th entry
+    fn make_available(memory: &GuestMemory, n: u16, head: u16) {
+        memory
+            .write_obj(AVAIL_RING + 4 + 2 * u64::from(n % SIZE), &head)
+            .unwrap();
+        memory.write_obj(AVAIL_RING + 2, &(n + 1)).unwrap();
+    }
+
+    #[test]
+    fn chains_are_read_and_used() {
+        let (memory, mut queue) = setup(Features::RING_INDIRECT_DESC | Features::RING_EVENT_IDX);
+        assert!(queue.pop().unwrap().is_none());
+
+        // A direct descriptor followed by an indirect table of three, with an
+        // empty one in the middle
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            3,
+            (0x10000, 4, DescriptorFlags::NEXT, 5),
+        );
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            5,
+            (0x4000, 48, DescriptorFlags::INDIRECT, 0),
+        );
+        write_desc(&memory, 0x4000, 0, (0x10004, 4, DescriptorFlags::NEXT, 1));
+        write_desc(&memory, 0x4000, 1, (0x10008, 0, DescriptorFlags::NEXT, 2));
+        write_desc(&memory, 0x4000, 2, (0x20000, 6, DescriptorFlags::WRITE, 0));
+        memory.write_slice(0x10000, b"virtqueue").unwrap();
+        make_available(&memory, 0, 3);
+
+        let chain = queue.pop().unwrap().unwrap();
+        assert_eq!(chain.head(), 3);
+        assert_eq!(
+            chain.descriptors()[2],
+            Descriptor {
+                addr: 0x20000,
+                len: 6,
+                write_only: true
+            }
+        );
+
+        let mut buf = Vec::new();
+        chain.reader(&memory).read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"virtqueu");
+
+        let mut writer = chain.writer(&memory);
+        assert_eq!(writer.write(b"used ring").unwrap(), 6);
+        assert_eq!(writer.remaining(), 0);
+        assert!(queue.pop().unwrap().is_none());
+
+        // The driver asked to be notified once the first chain is used
+        memory.write_obj(AVAIL_RING + 4 + 2 * 8, &0u16).unwrap();
+        queue.add_used(&chain, writer.written() as u32).unwrap();
+        assert!(queue.needs_notification().unwrap());
+        assert_eq!(memory.read_obj::<u16>(USED_RING + 2).unwrap(), 1);
+        assert_eq!(memory.read_obj::<[u32; 2]>(USED_RING + 4).unwrap(), [3, 6]);
+        assert_eq!(memory.read_obj::<u16>(USED_RING + 4 + 8 * 8).unwrap(), 1);
+
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            0,
+            (0x20000, 1, DescriptorFlags::WRITE, 0),
+        );
+        make_available(&memory, 1, 0);
+        let chain = queue.pop().unwrap().unwrap();
+        queue.add_used(&chain, 0).unwrap();
+        assert!(!queue.needs_notification().unwrap());
+    }
+
+    #[test]
+    fn malformed_chains_are_rejected() {
+        let (memory, mut queue) = setup(0);
+        let looping = DescriptorFlags::NEXT;
+
+        // Loops, out of range indices and memory, and readable buffers after
+        // writable ones
+        write_desc(&memory, DESC_TABLE, 0, (0x10000, 4, looping, 1));
+        write_desc(&memory, DESC_TABLE, 1, (0x10000, 4, looping, 0));
+        write_desc(&memory, DESC_TABLE, 2, (0x10000, 4, looping, SIZE));
+        write_desc(&memory, DESC_TABLE, 3, (0xff000, 0x2000, 0, 0));
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            4,
+            (0x10000, 4, DescriptorFlags::WRITE | looping, 5),
+        );
+        write_desc(&memory, DESC_TABLE, 5, (0x10000, 4, 0, 0));
+        // Indirect descriptors weren't negotiated
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            6,
+            (0x4000, 16, DescriptorFlags::INDIRECT, 0),
+        );
+
+        for (n, head) in [0, 2, 3, 4, 6, SIZE].into_iter().enumerate() {
+            make_available(&memory, n as u16, head);
+            assert!(matches!(queue.pop(), Err(VirtioError::InvalidChain(0))));
+        }
+
+        // The driver can't make more chains available than fit in the queue
+        memory.write_obj(AVAIL_RING + 2, &(7 + SIZE)).unwrap();
+        assert!(matches!(queue.pop(), Err(VirtioError::InvalidQueue(0))));
+    }
+
+    #[test]
+    fn packed_ring_wraps() {
+        let memory = Arc::new(GuestMemory::with_ram(1 << 20).unwrap());
+        let config = QueueConfig {
+            max_size: 256,
+            size: 4,
+            ready: true,
+            desc_table: DESC_TABLE,
+            driver_area: AVAIL_RING,
+            device_area: USED_RING,
+        };
+        let features =
+            Features::RING_PACKED | Features::RING_INDIRECT_DESC | Features::RING_EVENT_IDX;
+        let mut queue = Queue::new(memory.clone(), 0, &config, features).unwrap();
+
+        // Descriptors are written as addr, len, buffer ID and flags
+        let avail = PackedFlags::AVAIL;
+
+        // Two entries with the ID in the last one, then an indirect table
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            0,
+            (0x10000, 4, 0, avail | DescriptorFlags::NEXT),
+        );
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            1,
+            (0x20000, 4, 7, avail | DescriptorFlags::WRITE),
+        );
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            2,
+            (0x4000, 32, 2, avail | DescriptorFlags::INDIRECT),
+        );
+        write_desc(&memory, 0x4000, 0, (0x10000, 8, 0, 0));
+        write_desc(&memory, 0x4000, 1, (0x20000, 8, 0, DescriptorFlags::WRITE));
+
+        let chain = queue.pop().unwrap().unwrap();
+        assert_eq!((chain.head(), chain.descriptors().len()), (7, 2));
+        queue.add_used(&chain, 4).unwrap();
+        assert!(queue.needs_notification().unwrap());
+        assert_eq!(memory.read_obj::<u32>(DESC_TABLE + 8).unwrap(), 4);
+        assert_eq!(
+            memory.read_obj::<[u16; 2]>(DESC_TABLE + 12).unwrap(),
+            [7, PackedFlags::AVAIL | PackedFlags::USED]
+        );
+
+        let indirect = queue.pop().unwrap().unwrap();
+        assert_eq!((indirect.head(), indirect.descriptors().len()), (2, 2));
+
+        // The first entry is reused for the second lap, with the opposite
+        // flags
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            3,
+            (0x10000, 4, 0, avail | DescriptorFlags::NEXT),
+        );
+        write_desc(
+            &memory,
+            DESC_TABLE,
+            0,
+            (0x20000, 4, 3, PackedFlags::USED | DescriptorFlags::WRITE),
+        );
+
+        let wrapped = queue.pop().unwrap().unwrap();
+        assert_eq!((wrapped.head(), wrapped.descriptors().len()), (3, 2));
+        assert!(queue.pop().unwrap().is_none());
+
+        // Notifications are asked for once the driver makes entry 1 of the
+        // second lap available
+        assert_eq!(memory.read_obj::<[u16; 2]>(USED_RING).unwrap(), [1, 2]);
+
+        queue.add_used(&indirect, 16).unwrap();
+        queue.add_used(&wrapped, 4).unwrap();
+
+        // The driver wants an interrupt once entry 3 of the first lap is used
+        memory.write_obj(AVAIL_RING, &[3 | 1 << 15, 2u16]).unwrap();
+        assert!(queue.needs_notification().unwrap());
+        assert!(!queue.needs_notification().unwrap());
+    }
+}